@@ -0,0 +1,221 @@
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use anyhow::{anyhow, bail, Context};
+use clap::{Args, Parser, Subcommand};
+use smelt_data::client_commands::{
+    client_resp::ClientResponses, configure_smelt::InitExecutor, CfgDocker, CfgLocal,
+    ClientCommand, ClientResp, ConfigureSmelt, OtlpCfg,
+};
+use smelt_events::{
+    event_log::read_event_log, timeline::write_timeline, ClientCommandBundle, EventStreams,
+};
+use smelt_graph::{spawn_graph_server, SmeltServerHandle};
+
+mod status;
+
+use status::StatusPrinter;
+
+/// Runs lowered smelt command files, without needing a python interpreter
+#[derive(Parser, Debug)]
+#[command(name = "smelt-run", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: CliCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    /// Runs the commands of a lowered command file
+    ///
+    /// Without any names or selector, every command of the given type is run
+    Run(RunArgs),
+    /// Prints a run that was recorded with `--record-events`, as if it was running
+    Replay {
+        /// Event log of the run, from smelt-out/events/
+        event_log: PathBuf,
+    },
+    /// Writes a run that was recorded with `--record-events` as a Chrome trace-event timeline,
+    /// which opens in Perfetto
+    Timeline {
+        /// Event log of the run, from smelt-out/events/
+        event_log: PathBuf,
+        /// Where the timeline is written
+        #[arg(short, long, default_value = "timeline.json")]
+        output: PathBuf,
+    },
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    /// YAML file holding the lowered list of commands
+    command_file: PathBuf,
+    /// Names of the commands to run -- can be repeated
+    #[arg(short, long = "name", conflicts_with_all = ["select"])]
+    names: Vec<String>,
+    /// Type of the commands to run, if no names are given
+    #[arg(short = 't', long = "type", default_value = "test")]
+    target_type: String,
+    /// Selector expression picking the commands to run, e.g. `tag:nightly`
+    #[arg(long)]
+    select: Option<String>,
+    /// Plans the commands, without executing any of them
+    #[arg(long)]
+    dry_run: bool,
+    /// Root of the project, smelt-out/ is created under it -- defaults to the current directory
+    #[arg(long)]
+    smelt_root: Option<PathBuf>,
+    /// Number of job slots the executor has -- defaults to the number of cpus
+    #[arg(short, long)]
+    jobs: Option<u64>,
+    /// Ignores every command that is not a test
+    #[arg(long)]
+    test_only: bool,
+    /// Number of extra times a failed test is rerun
+    #[arg(long, default_value_t = 0)]
+    retry_failed: u32,
+    /// Executes every command in a transient container of this image
+    #[arg(long)]
+    docker_image: Option<String>,
+    /// Prints the stdout of every command as it executes
+    #[arg(long)]
+    stream_output: bool,
+    /// Records every event of the run to smelt-out/events/, so it can be replayed
+    #[arg(long)]
+    record_events: bool,
+    /// Exports every run as a trace to the OTLP collector at this endpoint
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+}
+
+impl RunArgs {
+    fn smelt_cfg(&self) -> anyhow::Result<ConfigureSmelt> {
+        let smelt_root = match self.smelt_root {
+            Some(ref root) => root.canonicalize()?,
+            None => std::env::current_dir()?,
+        };
+        let job_slots = match self.jobs {
+            Some(jobs) => jobs,
+            None => std::thread::available_parallelism()?.get() as u64,
+        };
+        let init_executor = match self.docker_image {
+            Some(ref image_name) => InitExecutor::Docker(CfgDocker {
+                image_name: image_name.clone(),
+                ..Default::default()
+            }),
+            None => InitExecutor::Local(CfgLocal {}),
+        };
+        Ok(ConfigureSmelt {
+            smelt_root: smelt_root.to_string_lossy().to_string(),
+            job_slots,
+            test_only: self.test_only,
+            silent: !self.stream_output,
+            retry_failed: self.retry_failed,
+            record_events: self.record_events,
+            otlp: self.otlp_endpoint.as_ref().map(|endpoint| OtlpCfg {
+                endpoint: endpoint.clone(),
+                ..Default::default()
+            }),
+            init_executor: Some(init_executor),
+            ..Default::default()
+        })
+    }
+
+    fn client_command(&self) -> ClientCommand {
+        let command = match (self.names.as_slice(), &self.select) {
+            (_, Some(selector)) => ClientCommand::execute_selected(selector.clone()),
+            ([], None) => ClientCommand::execute_type(self.target_type.clone()),
+            ([name], None) => ClientCommand::execute_command(name.clone()),
+            (names, None) => ClientCommand::execute_many(names.to_vec()),
+        };
+        command.with_dry_run(self.dry_run)
+    }
+}
+
+fn submit(handle: &SmeltServerHandle, message: ClientCommand) -> anyhow::Result<EventStreams> {
+    let (bundle, streams) = ClientCommandBundle::from_message(message);
+    handle
+        .tx_client
+        .send(bundle)
+        .map_err(|_| anyhow!("The command graph is no longer running"))?;
+    Ok(streams)
+}
+
+async fn set_graph(handle: &SmeltServerHandle, command_file: &Path) -> anyhow::Result<()> {
+    let graph = std::fs::read_to_string(command_file)
+        .with_context(|| format!("Could not read {}", command_file.display()))?;
+    let EventStreams { sync_chan, .. } = submit(handle, ClientCommand::send_graph(graph))?;
+    let resp = sync_chan.await?.map_err(|err| anyhow!(err))?;
+    if let Some(ClientResponses::Validation(validation)) = resp.client_responses {
+        for err in validation.errors.iter() {
+            eprintln!("error: {}", err.message);
+        }
+        if !validation.errors.is_empty() {
+            bail!("{} is not a valid command graph", command_file.display());
+        }
+    }
+    Ok(())
+}
+
+async fn run(args: RunArgs) -> anyhow::Result<ExitCode> {
+    let handle = spawn_graph_server(args.smelt_cfg()?);
+    set_graph(&handle, &args.command_file).await?;
+
+    let EventStreams {
+        sync_chan,
+        mut event_stream,
+    } = submit(&handle, args.client_command())?;
+    let mut printer = StatusPrinter::default();
+    while let Some(event) = event_stream.recv().await {
+        printer.process_event(&event);
+    }
+    let _: ClientResp = sync_chan.await?.map_err(|err| anyhow!(err))?;
+
+    println!("{}", printer.summary());
+    if !printer.invocation_dir.is_empty() {
+        println!("Invocation recorded at {}", printer.invocation_dir);
+    }
+    Ok(exit_code(&printer))
+}
+
+fn replay(event_log: &Path) -> anyhow::Result<ExitCode> {
+    let events = read_event_log(event_log)
+        .with_context(|| format!("Could not read the event log at {}", event_log.display()))?;
+    let mut printer = StatusPrinter::default();
+    for event in events.iter() {
+        printer.process_event(event);
+    }
+    println!("{}", printer.summary());
+    Ok(exit_code(&printer))
+}
+
+fn timeline(event_log: &Path, output: &Path) -> anyhow::Result<ExitCode> {
+    write_timeline(event_log, output)
+        .with_context(|| format!("Could not write the timeline of {}", event_log.display()))?;
+    println!("Wrote the timeline to {}", output.display());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn exit_code(printer: &StatusPrinter) -> ExitCode {
+    if printer.failed_run() {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let rv = match cli.command {
+        CliCommand::Run(args) => run(args).await,
+        CliCommand::Replay { event_log } => replay(&event_log),
+        CliCommand::Timeline { event_log, output } => timeline(&event_log, &output),
+    };
+    rv.unwrap_or_else(|err| {
+        eprintln!("error: {err:#}");
+        ExitCode::from(2)
+    })
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use smelt_data::{
+    command_event::CommandVariant,
+    event::Et,
+    executed_tests::{artifact_pointer::Pointer, TestOutputs, TestStatus},
+    invoke_event::InvokeVariant,
+    CommandEvent, Event, InvokeEvent, SmeltError, SmeltErrorType,
+};
+
+/// Prints a line for every command as it changes status, and tallies the results of the run
+#[derive(Default)]
+pub struct StatusPrinter {
+    /// Seconds since the epoch that each command started at -- taken from the events, so that
+    /// replayed runs keep their timings
+    started_at: HashMap<String, f64>,
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub planned: usize,
+    pub errors: usize,
+    pub invocation_dir: String,
+}
+
+fn status_label(outputs: &TestOutputs) -> &'static str {
+    match outputs.status() {
+        TestStatus::Flaky => "FLAKY",
+        TestStatus::PassedOnRetry => "RETRIED",
+        _ if outputs.exit_code == 0 => "PASSED",
+        _ => "FAILED",
+    }
+}
+
+fn smelt_log(outputs: &TestOutputs) -> Option<&str> {
+    outputs
+        .artifacts
+        .iter()
+        .find(|artifact| artifact.artifact_name == "smelt_log")
+        .and_then(|artifact| artifact.pointer.as_ref())
+        .map(|Pointer::Path(path)| path.as_str())
+}
+
+impl StatusPrinter {
+    /// True if any command failed or was skipped, or if the runtime reported an error
+    pub fn failed_run(&self) -> bool {
+        self.failed + self.skipped + self.errors != 0
+    }
+
+    pub fn summary(&self) -> String {
+        if self.planned != 0 {
+            return format!("{} commands planned", self.planned);
+        }
+        format!(
+            "{} passed, {} failed, {} skipped",
+            self.passed, self.failed, self.skipped
+        )
+    }
+
+    pub fn process_event(&mut self, event: &Event) {
+        match event.et.as_ref() {
+            Some(Et::Command(CommandEvent {
+                command_ref,
+                command_variant: Some(variant),
+            })) => self.process_command(command_ref, variant, event.time_secs()),
+            Some(Et::Invoke(InvokeEvent {
+                invoke_variant: Some(InvokeVariant::Done(done)),
+            })) => {
+                self.invocation_dir = done.invocation_dir.clone();
+            }
+            Some(Et::Error(SmeltError {
+                sig, error_payload, ..
+            })) => {
+                if *sig == SmeltErrorType::InternalWarn as i32 {
+                    eprintln!("warning: {error_payload}");
+                } else {
+                    self.errors += 1;
+                    eprintln!("error: {error_payload}");
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn process_command(&mut self, name: &str, variant: &CommandVariant, secs: f64) {
+        match variant {
+            // a failed test that is rerun keeps the time of its first attempt
+            CommandVariant::Started(_) if !self.started_at.contains_key(name) => {
+                self.started_at.insert(name.to_string(), secs);
+                println!("[ STARTED ] {name}");
+            }
+            CommandVariant::Finished(finished) => {
+                let outputs = finished.outputs.clone().unwrap_or_default();
+                let elapsed = self
+                    .started_at
+                    .get(name)
+                    .map(|started_at| secs - started_at)
+                    .unwrap_or_default();
+                let label = status_label(&outputs);
+                if outputs.exit_code == 0 {
+                    self.passed += 1;
+                    println!("[ {label:<7} ] {name} ({elapsed:.1}s)");
+                } else {
+                    self.failed += 1;
+                    println!(
+                        "[ {label:<7} ] {name} ({elapsed:.1}s), exit code {}",
+                        outputs.exit_code
+                    );
+                    if let Some(log) = smelt_log(&outputs) {
+                        println!("              log: {log}");
+                    }
+                }
+            }
+            CommandVariant::Skipped(_) => {
+                self.skipped += 1;
+                println!("[ SKIPPED ] {name}");
+            }
+            CommandVariant::Planned(planned) => {
+                self.planned += 1;
+                println!("[ PLANNED ] {name}");
+                for line in planned.script.lines() {
+                    println!("              {line}");
+                }
+            }
+            CommandVariant::Stdout(stdout) => {
+                if stdout.dropped_lines != 0 {
+                    println!(
+                        "{name}: ({} lines not shown, see the log of the command)",
+                        stdout.dropped_lines
+                    );
+                }
+                for line in stdout.output.lines() {
+                    println!("{name}: {line}");
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smelt_data::executed_tests::TestResult;
+
+    use super::*;
+
+    fn finished(name: &str, exit_code: i32, status: TestStatus) -> Event {
+        Event::command_finished(
+            TestResult {
+                test_name: name.to_string(),
+                outputs: Some(TestOutputs {
+                    exit_code,
+                    status: status.into(),
+                    ..Default::default()
+                }),
+                metadata: None,
+            },
+            "test".to_string(),
+            0.0,
+            "trace".to_string(),
+        )
+    }
+
+    #[test]
+    fn tallies_results() {
+        let mut printer = StatusPrinter::default();
+        printer.process_event(&Event::command_started(
+            "a".to_string(),
+            vec![0],
+            "trace".to_string(),
+        ));
+        printer.process_event(&finished("a", 0, TestStatus::Passed));
+        printer.process_event(&finished("b", 0, TestStatus::PassedOnRetry));
+        assert!(!printer.failed_run());
+
+        printer.process_event(&finished("c", 2, TestStatus::Flaky));
+        printer.process_event(&Event::command_skipped(
+            "d".to_string(),
+            "trace".to_string(),
+        ));
+        assert!(printer.failed_run());
+        assert_eq!(printer.summary(), "2 passed, 1 failed, 1 skipped");
+    }
+}
@@ -2,10 +2,11 @@ use allocative::Allocative;
 use dice::DiceError;
 use pyo3::exceptions::PyRuntimeError;
 use pyo3::prelude::PyErr;
+use smelt_data::{SmeltError, SmeltErrorCode, SmeltErrorType};
 
 use thiserror::Error;
 
-use crate::CommandDefPath;
+use crate::{suggest::did_you_mean, CommandDefPath};
 
 #[derive(Error, Debug)]
 pub enum SmeltErr {
@@ -25,10 +26,18 @@ pub enum SmeltErr {
     BadTargetType(String),
     #[error("Executor failed to execute with error : {0}")]
     ExecutorFailed(String),
-    #[error("Dependency for a command named {missing_dep_name} was found, but no such command was declared")]
-    MissingCommandDependency { missing_dep_name: String },
-    #[error("Dependency for a command named {missing_file_name} was found, but no such command was declared")]
-    MissingFileDependency { missing_file_name: String },
+    #[error("Dependency for a command named {missing_dep_name} was found, but no such command was declared{}", did_you_mean(suggestions))]
+    MissingCommandDependency {
+        missing_dep_name: String,
+        /// The declared commands with the closest names
+        suggestions: Vec<String>,
+    },
+    #[error("Dependency for a command named {missing_file_name} was found, but no such command was declared{}", did_you_mean(suggestions))]
+    MissingFileDependency {
+        missing_file_name: String,
+        /// The declared outputs with the closest paths
+        suggestions: Vec<String>,
+    },
     #[error("Setting commands failed; reason is {reason}")]
     CommandSettingFailed { reason: String },
     #[error("Two commands with the same name {name} where declared")]
@@ -42,7 +51,113 @@ pub enum SmeltErr {
     #[error("Artifact name cannot be parsed out")]
     BadArtifactName,
     #[error("Invalid config: {reason}")]
-    InvalidConfig { reason: String }
+    InvalidConfig { reason: String },
+    #[error("Invalid query: {reason}")]
+    InvalidQuery { reason: String },
+    #[error("Dependency cycle found: {}", cycle.join(" -> "))]
+    DependencyCycle { cycle: Vec<String> },
+    #[error("Invalid selector {selector}: {reason}")]
+    InvalidSelector { selector: String, reason: String },
+    #[error("Selector {selector} did not match any commands")]
+    EmptySelection { selector: String },
+    #[error("Invalid shard {shard_index} -- there are only {shard_count} shards")]
+    InvalidShard { shard_index: u32, shard_count: u32 },
+    #[error("Results history failed with error: {0}")]
+    HistoryFailed(String),
+    #[error("Exporting the trace of the run failed with error: {0}")]
+    TraceExportFailed(String),
+}
+
+impl SmeltErr {
+    /// The closest matches to whatever could not be found, if this error is about a missing
+    /// command or file
+    pub fn suggestions(&self) -> &[String] {
+        match self {
+            SmeltErr::MissingCommandDependency { suggestions, .. }
+            | SmeltErr::MissingFileDependency { suggestions, .. } => suggestions,
+            _ => &[],
+        }
+    }
+
+    /// The code this error is reported with in `SmeltError` events
+    pub fn code(&self) -> SmeltErrorCode {
+        match self {
+            SmeltErr::Unknown => SmeltErrorCode::Unknown,
+            SmeltErr::DiceFail(_) => SmeltErrorCode::DiceFail,
+            SmeltErr::IoError(_) => SmeltErrorCode::IoError,
+            SmeltErr::SerdeYamlError(_) => SmeltErrorCode::SerdeYamlError,
+            SmeltErr::SerdeJsonError(_) => SmeltErrorCode::SerdeJsonError,
+            SmeltErr::CommandCacheMiss => SmeltErrorCode::CommandCacheMiss,
+            SmeltErr::BadTargetType(_) => SmeltErrorCode::BadTargetType,
+            SmeltErr::ExecutorFailed(_) => SmeltErrorCode::ExecutorFailed,
+            SmeltErr::MissingCommandDependency { .. } => SmeltErrorCode::MissingCommandDependency,
+            SmeltErr::MissingFileDependency { .. } => SmeltErrorCode::MissingFileDependency,
+            SmeltErr::CommandSettingFailed { .. } => SmeltErrorCode::CommandSettingFailed,
+            SmeltErr::DuplicateCommandName { .. } => SmeltErrorCode::DuplicateCommandName,
+            SmeltErr::DuplicateOutput { .. } => SmeltErrorCode::DuplicateOutput,
+            SmeltErr::MissingOutputs { .. } => SmeltErrorCode::MissingOutputs,
+            SmeltErr::BadArtifactName => SmeltErrorCode::BadArtifactName,
+            SmeltErr::InvalidConfig { .. } => SmeltErrorCode::InvalidConfig,
+            SmeltErr::InvalidQuery { .. } => SmeltErrorCode::InvalidQuery,
+            SmeltErr::DependencyCycle { .. } => SmeltErrorCode::DependencyCycle,
+            SmeltErr::InvalidSelector { .. } => SmeltErrorCode::InvalidSelector,
+            SmeltErr::EmptySelection { .. } => SmeltErrorCode::EmptySelection,
+            SmeltErr::InvalidShard { .. } => SmeltErrorCode::InvalidShard,
+            SmeltErr::HistoryFailed(_) => SmeltErrorCode::HistoryFailed,
+            SmeltErr::TraceExportFailed(_) => SmeltErrorCode::TraceExportFailed,
+        }
+    }
+
+    /// This error in its structured form, which is what is sent in events and raised in python
+    pub fn to_proto(&self, sig: SmeltErrorType) -> SmeltError {
+        let mut error = SmeltError {
+            sig: sig.into(),
+            error_payload: self.to_string(),
+            code: self.code().into(),
+            suggestions: self.suggestions().to_vec(),
+            ..Default::default()
+        };
+        match self {
+            SmeltErr::DiceFail(err) => error.reason = err.to_string(),
+            SmeltErr::IoError(err) => error.reason = err.to_string(),
+            SmeltErr::SerdeYamlError(err) => error.reason = err.to_string(),
+            SmeltErr::SerdeJsonError(err) => error.reason = err.to_string(),
+            SmeltErr::BadTargetType(reason)
+            | SmeltErr::ExecutorFailed(reason)
+            | SmeltErr::HistoryFailed(reason)
+            | SmeltErr::TraceExportFailed(reason)
+            | SmeltErr::CommandSettingFailed { reason }
+            | SmeltErr::InvalidConfig { reason }
+            | SmeltErr::InvalidQuery { reason } => error.reason = reason.clone(),
+            SmeltErr::MissingCommandDependency {
+                missing_dep_name: name,
+                ..
+            }
+            | SmeltErr::DuplicateCommandName { name } => error.command_name = name.clone(),
+            SmeltErr::MissingFileDependency {
+                missing_file_name, ..
+            } => error.path = missing_file_name.clone(),
+            SmeltErr::DuplicateOutput { output } => error.path = output.to_string(),
+            SmeltErr::MissingOutputs { missing_outputs } => {
+                error.missing_outputs = missing_outputs.iter().map(ToString::to_string).collect()
+            }
+            SmeltErr::DependencyCycle { cycle } => error.cycle = cycle.clone(),
+            SmeltErr::InvalidSelector { selector, reason } => {
+                error.selector = selector.clone();
+                error.reason = reason.clone();
+            }
+            SmeltErr::EmptySelection { selector } => error.selector = selector.clone(),
+            SmeltErr::InvalidShard {
+                shard_index,
+                shard_count,
+            } => {
+                error.shard_index = *shard_index;
+                error.shard_count = *shard_count;
+            }
+            SmeltErr::Unknown | SmeltErr::CommandCacheMiss | SmeltErr::BadArtifactName => {}
+        }
+        error
+    }
 }
 
 impl Allocative for SmeltErr {
@@ -58,3 +173,35 @@ impl From<SmeltErr> for PyErr {
         PyRuntimeError::new_err(smelt_string)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn structured_errors_keep_their_fields() {
+        let error = SmeltErr::MissingCommandDependency {
+            missing_dep_name: "tset".to_string(),
+            suggestions: vec!["test".to_string()],
+        }
+        .to_proto(SmeltErrorType::InternalError);
+        assert_eq!(error.code(), SmeltErrorCode::MissingCommandDependency);
+        assert_eq!(error.command_name, "tset");
+        assert_eq!(error.suggestions, ["test"]);
+        assert!(error.error_payload.contains("did you mean test"));
+
+        let error = SmeltErr::DependencyCycle {
+            cycle: vec!["a".to_string(), "b".to_string(), "a".to_string()],
+        }
+        .to_proto(SmeltErrorType::ClientError);
+        assert_eq!(error.sig(), SmeltErrorType::ClientError);
+        assert_eq!(error.cycle, ["a", "b", "a"]);
+
+        let error = SmeltErr::InvalidShard {
+            shard_index: 3,
+            shard_count: 2,
+        }
+        .to_proto(SmeltErrorType::ClientError);
+        assert_eq!((error.shard_index, error.shard_count), (3, 2));
+    }
+}
@@ -1,4 +1,6 @@
 mod error;
 mod paths;
+mod suggest;
 pub use error::SmeltErr;
 pub use paths::*;
+pub use suggest::*;
@@ -0,0 +1,92 @@
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+
+/// The most suggestions that are attached to a single error
+pub const MAX_SUGGESTIONS: usize = 3;
+
+/// Finds the candidates that `query` was most likely a typo of, best match first
+///
+/// Candidates are matched with the skim fuzzy matcher, which catches dropped characters and
+/// abbreviations, and by edit distance, which catches extra, swapped or mistyped characters
+pub fn closest_matches<S: AsRef<str>>(
+    query: &str,
+    candidates: impl IntoIterator<Item = S>,
+) -> Vec<String> {
+    let matcher = SkimMatcherV2::default();
+    let max_distance = (query.chars().count() / 3).max(1);
+
+    let mut matches: Vec<(usize, i64, String)> = candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let candidate = candidate.as_ref();
+            let distance = edit_distance(query, candidate);
+            match matcher.fuzzy_match(candidate, query) {
+                Some(score) => Some((distance, score, candidate.to_string())),
+                None if distance <= max_distance => Some((distance, 0, candidate.to_string())),
+                None => None,
+            }
+        })
+        .collect();
+
+    matches.sort_by(|(dist_a, score_a, name_a), (dist_b, score_b, name_b)| {
+        dist_a
+            .cmp(dist_b)
+            .then(score_b.cmp(score_a))
+            .then(name_a.cmp(name_b))
+    });
+    matches.dedup_by(|(_, _, a), (_, _, b)| a == b);
+    matches
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, _, name)| name)
+        .collect()
+}
+
+/// Formats suggestions for the end of an error message
+pub(crate) fn did_you_mean(suggestions: &[String]) -> String {
+    if suggestions.is_empty() {
+        String::new()
+    } else {
+        format!(" -- did you mean {}?", suggestions.join(", "))
+    }
+}
+
+/// Edit distance between two strings, where swapping two adjacent characters counts as a single
+/// edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut dist = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in dist.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in dist[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            dist[i][j] = (dist[i - 1][j] + 1)
+                .min(dist[i][j - 1] + 1)
+                .min(dist[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                dist[i][j] = dist[i][j].min(dist[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    dist[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_close_names() {
+        let candidates = ["sim", "sim2", "sim3", "stim", "test"];
+        assert_eq!(closest_matches("sim4", candidates)[0], "sim");
+        assert_eq!(closest_matches("tset", candidates), vec!["test"]);
+        assert_eq!(closest_matches("tst", candidates)[0], "test");
+        assert!(closest_matches("completely_unrelated", candidates).is_empty());
+        assert!(closest_matches("s", candidates).len() <= MAX_SUGGESTIONS);
+    }
+}
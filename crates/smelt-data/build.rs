@@ -54,11 +54,28 @@ fn main() -> io::Result<()> {
             "#[derive(Copy, dupe::Dupe,Eq,Hash)]",
         )
         .field_attribute("time", "#[serde(with = \"crate::serialize_timestamp\")]")
-        .field_attribute("rundate", "#[serde(with = \"crate::serialize_timestamp\")]");
+        .field_attribute("rundate", "#[serde(with = \"crate::serialize_timestamp\")]")
+        .field_attribute("started_at", "#[serde(with = \"crate::serialize_timestamp\")]")
+        .field_attribute("finished_at", "#[serde(with = \"crate::serialize_timestamp\")]");
 
     let proto_files = ["data.proto", "client.data.proto", "executed_tests.proto"];
     for proto_file in proto_files {
         println!("cargo:rerun-if-changed={}", proto_file);
     }
-    tonic.compile(&proto_files, &["."])
+    tonic.compile(&proto_files, &["."])?;
+
+    // The service only refers to messages that are generated above
+    println!("cargo:rerun-if-changed=server.proto");
+    tonic_build::configure()
+        .extern_path(".smelt_client.commands", "crate::client_commands")
+        .extern_path(".smelt_telemetry", "crate::smelt_telemetry")
+        .extern_path(".executed_tests", "crate::executed_tests")
+        .compile(&["server.proto"], &["."])?;
+
+    // Only the OTLP trace exporter uses these, so they don't need the derives of our own messages
+    println!("cargo:rerun-if-changed=opentelemetry");
+    tonic_build::configure().compile(
+        &["opentelemetry/proto/collector/trace/v1/trace_service.proto"],
+        &["."],
+    )
 }
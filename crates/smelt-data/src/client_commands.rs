@@ -13,8 +13,39 @@ impl ClientCommand {
         }
     }
 
+    pub fn add_commands(commands_string: String) -> Self {
+        let cc = ClientCommands::AddCommands(AddCommands {
+            command_content: commands_string,
+        });
+
+        ClientCommand {
+            client_commands: Some(cc),
+        }
+    }
+
+    pub fn remove_commands(command_names: Vec<String>) -> Self {
+        let cc = ClientCommands::RemoveCommands(RemoveCommands { command_names });
+
+        ClientCommand {
+            client_commands: Some(cc),
+        }
+    }
+
+    pub fn replace_command(command_string: String) -> Self {
+        let cc = ClientCommands::ReplaceCommand(ReplaceCommand {
+            command_content: command_string,
+        });
+
+        ClientCommand {
+            client_commands: Some(cc),
+        }
+    }
+
     pub fn execute_command(command_name: String) -> Self {
-        let cc = ClientCommands::Runone(RunOne { command_name });
+        let cc = ClientCommands::Runone(RunOne {
+            command_name,
+            dry_run: false,
+        });
 
         ClientCommand {
             client_commands: Some(cc),
@@ -22,7 +53,11 @@ impl ClientCommand {
     }
 
     pub fn execute_many(command_names: Vec<String>) -> Self {
-        let cc = ClientCommands::Runmany(RunMany { command_names });
+        let cc = ClientCommands::Runmany(RunMany {
+            command_names,
+            shard: None,
+            dry_run: false,
+        });
 
         ClientCommand {
             client_commands: Some(cc),
@@ -30,13 +65,52 @@ impl ClientCommand {
     }
 
     pub fn execute_type(typeinfo: String) -> Self {
-        let cc = ClientCommands::Runtype(RunType { typeinfo });
+        let cc = ClientCommands::Runtype(RunType {
+            typeinfo,
+            shard: None,
+            dry_run: false,
+        });
+
+        ClientCommand {
+            client_commands: Some(cc),
+        }
+    }
+
+    pub fn execute_selected(selector: String) -> Self {
+        let cc = ClientCommands::Runselected(RunSelected {
+            selector,
+            shard: None,
+            dry_run: false,
+        });
 
         ClientCommand {
             client_commands: Some(cc),
         }
     }
 
+    /// Restricts a run request to a single shard -- other commands are left untouched
+    pub fn with_shard(mut self, shard: Shard) -> Self {
+        match self.client_commands.as_mut() {
+            Some(ClientCommands::Runmany(val)) => val.shard = Some(shard),
+            Some(ClientCommands::Runtype(val)) => val.shard = Some(shard),
+            Some(ClientCommands::Runselected(val)) => val.shard = Some(shard),
+            _ => {}
+        }
+        self
+    }
+
+    /// Plans a run request without executing any commands -- other commands are left untouched
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        match self.client_commands.as_mut() {
+            Some(ClientCommands::Runone(val)) => val.dry_run = dry_run,
+            Some(ClientCommands::Runmany(val)) => val.dry_run = dry_run,
+            Some(ClientCommands::Runtype(val)) => val.dry_run = dry_run,
+            Some(ClientCommands::Runselected(val)) => val.dry_run = dry_run,
+            _ => {}
+        }
+        self
+    }
+
     pub fn get_cfg() -> Self {
         let cc = ClientCommands::Getcfg(GetConfig {});
 
@@ -44,4 +118,35 @@ impl ClientCommand {
             client_commands: Some(cc),
         }
     }
+
+    pub fn export_graph(output_dir: String, include_status: bool) -> Self {
+        let cc = ClientCommands::Export(ExportGraph {
+            output_dir,
+            include_status,
+        });
+
+        ClientCommand {
+            client_commands: Some(cc),
+        }
+    }
+
+    pub fn history(command_names: Vec<String>) -> Self {
+        let cc = ClientCommands::History(HistoryQuery { command_names });
+
+        ClientCommand {
+            client_commands: Some(cc),
+        }
+    }
+
+    pub fn query(query_type: QueryType, command_names: Vec<String>, destination: String) -> Self {
+        let cc = ClientCommands::Query(Query {
+            query_type: query_type.into(),
+            command_names,
+            destination,
+        });
+
+        ClientCommand {
+            client_commands: Some(cc),
+        }
+    }
 }
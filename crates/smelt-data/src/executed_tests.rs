@@ -22,6 +22,8 @@ pub enum ExecutedTestResult {
         missing_artifacts: Vec<ArtifactPointer>,
     },
     Skipped,
+    /// The command was planned by a dry run, and was never executed
+    Planned,
 }
 
 impl ExecutedTestResult {
@@ -37,7 +39,7 @@ impl ExecutedTestResult {
         match self {
             Self::Success(val) => val,
             Self::MissingFiles { test_result, .. } => test_result,
-            Self::Skipped => TestResult::default(),
+            Self::Skipped | Self::Planned => TestResult::default(),
         }
     }
     pub fn get_retcode(&self) -> i32 {
@@ -54,8 +56,17 @@ impl ExecutedTestResult {
                 );
                 -1
             }
+            Self::Planned => 0,
         }
     }
+    pub fn outputs_mut(&mut self) -> Option<&mut TestOutputs> {
+        match self {
+            Self::Success(val) => val.outputs.as_mut(),
+            Self::MissingFiles { test_result, .. } => test_result.outputs.as_mut(),
+            Self::Skipped | Self::Planned => None,
+        }
+    }
+
     pub fn failed(&self) -> bool {
         match self {
             Self::Success(val) => val.outputs.as_ref().map(|val| val.exit_code).unwrap() != 0,
@@ -67,7 +78,7 @@ impl ExecutedTestResult {
                     .unwrap()
                     != 0
             }
-            Self::Skipped => false,
+            Self::Skipped | Self::Planned => false,
         }
     }
 }
@@ -77,3 +88,20 @@ impl TestOutputs {
         self.exit_code == 0
     }
 }
+
+impl ResourceUsage {
+    /// Folds in the usage of a later attempt of the same command
+    pub fn merge(&mut self, later: &ResourceUsage) {
+        if self.started_at.is_none() {
+            self.started_at = later.started_at.clone();
+        }
+        if later.finished_at.is_some() {
+            self.finished_at = later.finished_at.clone();
+        }
+        self.user_cpu_ms += later.user_cpu_ms;
+        self.system_cpu_ms += later.system_cpu_ms;
+        self.peak_rss_bytes = self.peak_rss_bytes.max(later.peak_rss_bytes);
+        self.hostname.clone_from(&later.hostname);
+        self.container.clone_from(&later.container);
+    }
+}
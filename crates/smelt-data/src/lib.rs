@@ -60,6 +60,39 @@ pub mod smelt_telemetry {
 
     tonic::include_proto!("smelt_telemetry");
 }
+
+pub mod smelt_server {
+
+    tonic::include_proto!("smelt_server");
+}
+
+/// The parts of the OpenTelemetry protocol that invocations are exported as traces with
+pub mod opentelemetry {
+    pub mod proto {
+        pub mod common {
+            pub mod v1 {
+                tonic::include_proto!("opentelemetry.proto.common.v1");
+            }
+        }
+        pub mod resource {
+            pub mod v1 {
+                tonic::include_proto!("opentelemetry.proto.resource.v1");
+            }
+        }
+        pub mod trace {
+            pub mod v1 {
+                tonic::include_proto!("opentelemetry.proto.trace.v1");
+            }
+        }
+        pub mod collector {
+            pub mod trace {
+                pub mod v1 {
+                    tonic::include_proto!("opentelemetry.proto.collector.trace.v1");
+                }
+            }
+        }
+    }
+}
 use executed_tests::TestResult;
 pub use smelt_telemetry::*;
 
@@ -72,6 +105,14 @@ impl Event {
         }
     }
 
+    /// Seconds since the epoch of the event, or 0 if the event has no time
+    pub fn time_secs(&self) -> f64 {
+        self.time
+            .as_ref()
+            .map(|time| time.seconds as f64 + time.nanos as f64 / 1e9)
+            .unwrap_or_default()
+    }
+
     pub fn from_command_variant(
         command_ref: String,
         trace_id: String,
@@ -83,15 +124,21 @@ impl Event {
         });
         Self::new(et, trace_id)
     }
-    pub fn command_started(command_ref: String, trace_id: String) -> Self {
+    /// `job_slots` are the indices of the job slots that the command holds while it runs
+    pub fn command_started(command_ref: String, job_slots: Vec<u32>, trace_id: String) -> Self {
         let et = event::Et::Command(CommandEvent {
             command_ref,
-            command_variant: Some(CommandVariant::Started(CommandStarted {})),
+            command_variant: Some(CommandVariant::Started(CommandStarted { job_slots })),
         });
         Self::new(et, trace_id)
     }
 
-    pub fn command_finished(test: TestResult, command_type: String, trace_id: String) -> Self {
+    pub fn command_finished(
+        test: TestResult,
+        command_type: String,
+        flaky_score: f64,
+        trace_id: String,
+    ) -> Self {
         let command_ref = test.test_name;
         let to = test.outputs.unwrap();
         let et = event::Et::Command(CommandEvent {
@@ -99,11 +146,26 @@ impl Event {
             command_variant: Some(CommandVariant::Finished(CommandFinished {
                 outputs: Some(to),
                 command_type,
+                metadata: test.metadata,
+                flaky_score,
+                peaks: None,
             })),
         });
         Self::new(et, trace_id)
     }
 
+    /// Attaches the peak resource usage of a command to its `CommandFinished` event
+    pub fn with_peaks(mut self, peaks: Option<ProfilePeaks>) -> Self {
+        if let Some(Et::Command(CommandEvent {
+            command_variant: Some(CommandVariant::Finished(finished)),
+            ..
+        })) = self.et.as_mut()
+        {
+            finished.peaks = peaks;
+        }
+        self
+    }
+
     pub fn command_skipped(command_ref: String, trace_id: String) -> Self {
         let et = event::Et::Command(CommandEvent {
             command_ref,
@@ -112,6 +174,14 @@ impl Event {
         Self::new(et, trace_id)
     }
 
+    pub fn command_planned(command_ref: String, trace_id: String, planned: CommandPlanned) -> Self {
+        let et = event::Et::Command(CommandEvent {
+            command_ref,
+            command_variant: Some(CommandVariant::Planned(planned)),
+        });
+        Self::new(et, trace_id)
+    }
+
     pub fn command_scheduled(command_ref: String, trace_id: String) -> Self {
         let et = event::Et::Command(CommandEvent {
             command_ref,
@@ -119,10 +189,18 @@ impl Event {
         });
         Self::new(et, trace_id)
     }
-    pub fn command_stdout(command_ref: String, trace_id: String, stdout: String) -> Self {
+    pub fn command_stdout(
+        command_ref: String,
+        trace_id: String,
+        stdout: String,
+        dropped_lines: u64,
+    ) -> Self {
         let et = event::Et::Command(CommandEvent {
             command_ref,
-            command_variant: Some(CommandVariant::Stdout(CommandStdout { output: stdout })),
+            command_variant: Some(CommandVariant::Stdout(CommandStdout {
+                output: stdout,
+                dropped_lines,
+            })),
         });
         Self::new(et, trace_id)
     }
@@ -163,6 +241,7 @@ impl Event {
         let et = Et::Error(SmeltError {
             sig: SmeltErrorType::ClientError.into(),
             error_payload: payload,
+            ..Default::default()
         });
         Event::new(et, trace_id)
     }
@@ -170,10 +249,16 @@ impl Event {
         let et = Et::Error(SmeltError {
             sig: SmeltErrorType::InternalError.into(),
             error_payload: payload,
+            ..Default::default()
         });
         Event::new(et, trace_id)
     }
 
+    /// Event for an error that was already turned into its structured form
+    pub fn error(error: SmeltError, trace_id: String) -> Event {
+        Event::new(Et::Error(error), trace_id)
+    }
+
     pub fn graph_validate_error(payload: String) -> Event {
         Self::runtime_error(payload, "VALIDATE_ERROR".to_string())
     }
@@ -202,10 +287,40 @@ impl CommandFinished {
     }
 }
 
+impl ProfilePeaks {
+    /// Raises the peaks to the values of a new sample of the same attempt
+    pub fn add_sample(&mut self, sample: &CommandProfile) {
+        self.memory_used = self.memory_used.max(sample.memory_used);
+        self.cpu_load = self.cpu_load.max(sample.cpu_load);
+        self.cpu_time_ms = self.cpu_time_ms.max(sample.cpu_time_ms);
+        self.io_read_bytes = self.io_read_bytes.max(sample.io_read_bytes);
+        self.io_write_bytes = self.io_write_bytes.max(sample.io_write_bytes);
+        self.num_threads = self.num_threads.max(sample.num_threads);
+    }
+
+    /// Folds in the peaks of another attempt of the same command
+    pub fn merge(&mut self, other: &ProfilePeaks) {
+        self.memory_used = self.memory_used.max(other.memory_used);
+        self.cpu_load = self.cpu_load.max(other.cpu_load);
+        self.cpu_time_ms += other.cpu_time_ms;
+        self.io_read_bytes += other.io_read_bytes;
+        self.io_write_bytes += other.io_write_bytes;
+        self.num_threads = self.num_threads.max(other.num_threads);
+    }
+}
+
+impl std::fmt::Display for SmeltError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.error_payload)
+    }
+}
+
+impl std::error::Error for SmeltError {}
+
 impl Et {
     pub fn done() -> Self {
         crate::event::Et::Invoke(InvokeEvent {
-            invoke_variant: Some(invoke_event::InvokeVariant::Done(AllCommandsDone {})),
+            invoke_variant: Some(invoke_event::InvokeVariant::Done(AllCommandsDone::default())),
         })
     }
 
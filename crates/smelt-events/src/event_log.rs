@@ -0,0 +1,126 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use prost::Message;
+use smelt_data::Event;
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+
+/// Where the events of the invocation `trace_id` are recorded
+pub fn event_log_path(smelt_root: &Path, trace_id: &str) -> PathBuf {
+    smelt_root
+        .join("smelt-out")
+        .join("events")
+        .join(format!("{trace_id}.pb"))
+}
+
+/// Appends the events of an invocation to its event log, as length delimited protobufs
+pub struct EventLogWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl EventLogWriter {
+    pub async fn create(smelt_root: &Path, trace_id: &str) -> io::Result<Self> {
+        let path = event_log_path(smelt_root, trace_id);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let file = BufWriter::new(File::create(&path).await?);
+        Ok(Self { path, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn append(&mut self, event: &Event) -> io::Result<()> {
+        self.file
+            .write_all(&event.encode_length_delimited_to_vec())
+            .await
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().await
+    }
+}
+
+/// Reads back every event of an event log, in the order they were recorded
+///
+/// A log that was cut short -- e.g. because the runtime was killed mid run -- holds a partial
+/// event at its end, which is skipped
+pub fn read_event_log(path: &Path) -> io::Result<Vec<Event>> {
+    let contents = std::fs::read(path)?;
+    let mut remaining = contents.as_slice();
+    let mut events = vec![];
+    while !remaining.is_empty() {
+        let Ok(len) = prost::decode_length_delimiter(&mut remaining) else {
+            break;
+        };
+        if len > remaining.len() {
+            break;
+        }
+        let (message, rest) = remaining.split_at(len);
+        let event = Event::decode(message)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        events.push(event);
+        remaining = rest;
+    }
+    if !remaining.is_empty() {
+        tracing::warn!(
+            "The event log at {} ends with a partial event",
+            path.display()
+        );
+    }
+    Ok(events)
+}
+
+/// Replays an event log as an event stream, which ends after the last recorded event
+///
+/// Subscribers can't tell a replayed stream from a live one
+pub fn replay_event_log(path: &Path) -> io::Result<mpsc::Receiver<Event>> {
+    let events = read_event_log(path)?;
+    let (tx, rx) = mpsc::channel(events.len().max(1));
+    for event in events {
+        tx.try_send(event)
+            .expect("The stream has room for every recorded event");
+    }
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replays_recorded_events() {
+        let smelt_root =
+            std::env::temp_dir().join(format!("smelt-event-log-{}", std::process::id()));
+        let mut writer = EventLogWriter::create(&smelt_root, "trace").await.unwrap();
+        let recorded = vec![
+            Event::command_started("test".to_string(), vec![0], "trace".to_string()),
+            Event::done("trace".to_string()),
+        ];
+        for event in recorded.iter() {
+            writer.append(event).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+
+        // a partial event at the end is skipped
+        let mut contents = std::fs::read(writer.path()).unwrap();
+        contents.extend_from_slice(&[10, 1]);
+        std::fs::write(writer.path(), contents).unwrap();
+
+        let mut replayed = replay_event_log(writer.path()).unwrap();
+        for event in recorded {
+            assert_eq!(replayed.recv().await, Some(event));
+        }
+        assert_eq!(replayed.recv().await, None);
+        std::fs::remove_dir_all(smelt_root).unwrap();
+    }
+}
@@ -1,6 +1,10 @@
+pub mod event_log;
 pub mod runtime_support;
+pub mod slots;
+pub mod stdout;
+pub mod timeline;
 
-use smelt_data::client_commands::{ClientResp};
+use smelt_data::{client_commands::ClientResp, SmeltError};
 pub use smelt_data::{client_commands::ClientCommand, Event};
 
 use tokio::{
@@ -60,7 +64,7 @@ mod helpers {
     }
 }
 
-pub type ClientCommandResp = Result<ClientResp, String>;
+pub type ClientCommandResp = Result<ClientResp, SmeltError>;
 
 pub struct ClientCommandBundle {
     pub message: ClientCommand,
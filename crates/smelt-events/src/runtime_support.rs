@@ -1,11 +1,17 @@
-use std::path::{PathBuf};
-
-use crate::Event;
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use crate::{
+    slots::{FairSlots, SlotPermit},
+    Event,
+};
 use async_trait::async_trait;
 use dice::{DiceData, DiceDataBuilder, UserComputationData};
 
-use smelt_data::client_commands::ConfigureSmelt;
-use tokio::sync::{Semaphore, SemaphorePermit};
+use smelt_data::{client_commands::ConfigureSmelt, ProfilePeaks};
 use uuid::Uuid;
 
 use tokio::sync::mpsc::Sender;
@@ -17,14 +23,57 @@ pub trait GetTxChannel {
     fn get_tx_channel(&self) -> Sender<Event>;
 }
 
+/// A new, globally unique id for an invocation
+pub fn new_trace_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
 pub trait SetTraceId {
-    fn init_trace_id(&mut self);
+    fn set_trace_id(&mut self, trace_id: String);
 }
 
 pub trait GetTraceId {
     fn get_trace_id(&self) -> String;
 }
 
+pub trait SetDryRun {
+    fn set_dry_run(&mut self, dry_run: bool);
+}
+
+pub trait GetDryRun {
+    /// True if the commands in this transaction should be planned, but not executed
+    fn is_dry_run(&self) -> bool;
+}
+
+/// Peak resource usage of every command of an invocation, as seen by the profiler
+#[derive(Default)]
+pub struct CommandPeaks(Mutex<HashMap<String, ProfilePeaks>>);
+
+impl CommandPeaks {
+    /// Records the peaks of an attempt of `command_ref`, on top of any earlier attempts
+    pub fn record(&self, command_ref: &str, peaks: &ProfilePeaks) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(command_ref.to_string())
+            .or_default()
+            .merge(peaks);
+    }
+
+    pub fn take(&self, command_ref: &str) -> Option<ProfilePeaks> {
+        self.0.lock().unwrap().remove(command_ref)
+    }
+}
+
+pub trait SetCommandPeaks {
+    fn set_command_peaks(&mut self);
+}
+
+pub trait GetCommandPeaks {
+    /// None if the transaction doesn't track peaks
+    fn get_command_peaks(&self) -> Option<&CommandPeaks>;
+}
+
 pub trait SetSmeltCfg {
     fn set_smelt_cfg(&mut self, cfg: ConfigureSmelt);
 }
@@ -43,8 +92,10 @@ pub trait SetSemaphore {
 
 #[async_trait]
 pub trait LockSemaphore {
-    /// Gets the semaphore we use to control how many slots we're using in smelt
-    async fn lock_sem(&self, cnt: u32) -> SemaphorePermit<'_>;
+    /// Takes `cnt` of the job slots smelt has for the invocation `trace_id`
+    ///
+    /// Slots are shared fairly between invocations that run at the same time
+    async fn lock_sem(&self, trace_id: &str, cnt: u32) -> SlotPermit;
 }
 pub trait GetJobSlots {
     fn get_job_slots(&self) -> u64;
@@ -74,9 +125,8 @@ impl GetTxChannel for UserComputationData {
 
 struct LocalUuid(String);
 impl SetTraceId for UserComputationData {
-    fn init_trace_id(&mut self) {
-        let luid = LocalUuid(Uuid::new_v4().to_string());
-        self.data.set(luid);
+    fn set_trace_id(&mut self, trace_id: String) {
+        self.data.set(LocalUuid(trace_id));
     }
 }
 
@@ -90,6 +140,31 @@ impl GetTraceId for UserComputationData {
     }
 }
 
+struct DryRun(bool);
+impl SetDryRun for UserComputationData {
+    fn set_dry_run(&mut self, dry_run: bool) {
+        self.data.set(DryRun(dry_run));
+    }
+}
+
+impl GetDryRun for UserComputationData {
+    fn is_dry_run(&self) -> bool {
+        self.data.get::<DryRun>().map(|val| val.0).unwrap_or(false)
+    }
+}
+
+impl SetCommandPeaks for UserComputationData {
+    fn set_command_peaks(&mut self) {
+        self.data.set(CommandPeaks::default());
+    }
+}
+
+impl GetCommandPeaks for UserComputationData {
+    fn get_command_peaks(&self) -> Option<&CommandPeaks> {
+        self.data.get::<CommandPeaks>().ok()
+    }
+}
+
 impl GetSmeltRoot for DiceData {
     fn get_smelt_root(&self) -> PathBuf {
         self.get::<ConfigureSmelt>()
@@ -114,25 +189,22 @@ impl SetSmeltCfg for DiceDataBuilder {
 
 impl SetSemaphore for DiceDataBuilder {
     fn set_sempahore(&mut self, cnt: usize) {
-        let sem = Semaphore::new(cnt);
-        self.set(sem);
+        self.set(FairSlots::new(cnt as u32));
     }
 }
 #[async_trait]
 impl LockSemaphore for DiceData {
-    async fn lock_sem(&self, cnt: u32) -> SemaphorePermit<'_> {
-        let sem = self.get::<Semaphore>().expect("Semaphore should be set");
+    async fn lock_sem(&self, trace_id: &str, cnt: u32) -> SlotPermit {
+        let slots = self.get::<FairSlots>().expect("Job slots should be set");
         let max_slots = self.get_smelt_cfg().job_slots;
-        let slots = cnt.min(max_slots as u32);
+        let count = cnt.min(max_slots as u32);
 
-        let available = sem.available_permits();
-        tracing::debug!("Acquiring semaphore {cnt}, max is {max_slots}, current is {available}");
-        
+        let available = slots.available();
+        tracing::debug!(
+            "Acquiring {cnt} slots for {trace_id}, max is {max_slots}, current is {available}"
+        );
 
-        sem
-            .acquire_many(slots)
-            .await
-            .expect("We should NEVER close this semaphore")
+        slots.acquire(trace_id, count).await
     }
 }
 
@@ -0,0 +1,194 @@
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::oneshot;
+
+/// Hands out the job slots of the executor, sharing them fairly between every invocation that is
+/// running at the same time
+///
+/// Whenever slots free up, they go to the waiting invocation that currently holds the fewest of
+/// them -- within an invocation, commands are served in the order they asked for slots. A command
+/// that needs more slots than are free holds up the commands behind it, so large commands are
+/// never starved by smaller ones
+///
+/// Slots are numbered from 0, and a command is handed the lowest numbered slots that are free
+#[derive(Clone)]
+pub struct FairSlots {
+    state: Arc<Mutex<SlotState>>,
+}
+
+struct SlotState {
+    free: BTreeSet<u32>,
+    held: HashMap<String, u32>,
+    waiting: VecDeque<Waiter>,
+}
+
+struct Waiter {
+    trace_id: String,
+    slots: u32,
+    wake: oneshot::Sender<SlotPermit>,
+}
+
+/// Slots held by a single command -- they are returned when the permit is dropped
+pub struct SlotPermit {
+    slots: FairSlots,
+    trace_id: String,
+    granted: Vec<u32>,
+}
+
+impl SlotPermit {
+    /// Indices of the slots that are held
+    pub fn granted(&self) -> &[u32] {
+        &self.granted
+    }
+}
+
+impl FairSlots {
+    pub fn new(total: u32) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SlotState {
+                free: (0..total).collect(),
+                held: HashMap::new(),
+                waiting: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Waits until `count` slots are handed to the invocation `trace_id`
+    pub async fn acquire(&self, trace_id: &str, count: u32) -> SlotPermit {
+        let (wake, woken) = oneshot::channel();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.waiting.push_back(Waiter {
+                trace_id: trace_id.to_string(),
+                slots: count,
+                wake,
+            });
+            self.dispatch(&mut state);
+        }
+        woken
+            .await
+            .expect("Slots are never dropped while a command waits on them")
+    }
+
+    /// Number of slots that are free right now
+    pub fn available(&self) -> u32 {
+        self.state.lock().unwrap().free.len() as u32
+    }
+
+    fn dispatch(&self, state: &mut SlotState) {
+        loop {
+            let next = state
+                .waiting
+                .iter()
+                .enumerate()
+                .min_by_key(|(position, waiter)| {
+                    let held = state.held.get(&waiter.trace_id).copied().unwrap_or(0);
+                    (held, *position)
+                })
+                .map(|(position, waiter)| (position, waiter.slots));
+            let Some((position, slots)) = next else {
+                return;
+            };
+            if slots as usize > state.free.len() {
+                return;
+            }
+
+            let waiter = state.waiting.remove(position).unwrap();
+            let granted: Vec<u32> = (0..slots).filter_map(|_| state.free.pop_first()).collect();
+            let permit = SlotPermit {
+                slots: self.clone(),
+                trace_id: waiter.trace_id.clone(),
+                granted,
+            };
+            *state.held.entry(waiter.trace_id).or_default() += slots;
+            // the command stopped waiting, so its slots go straight back
+            if let Err(mut permit) = waiter.wake.send(permit) {
+                Self::forget_held(state, &permit.trace_id, slots);
+                state.free.extend(permit.granted.drain(..));
+            }
+        }
+    }
+
+    fn forget_held(state: &mut SlotState, trace_id: &str, count: u32) {
+        if let Some(held) = state.held.get_mut(trace_id) {
+            *held -= count;
+            if *held == 0 {
+                state.held.remove(trace_id);
+            }
+        }
+    }
+
+    fn release(&self, trace_id: &str, granted: Vec<u32>) {
+        let mut state = self.state.lock().unwrap();
+        Self::forget_held(&mut state, trace_id, granted.len() as u32);
+        state.free.extend(granted);
+        self.dispatch(&mut state);
+    }
+}
+
+impl Drop for SlotPermit {
+    fn drop(&mut self) {
+        if !self.granted.is_empty() {
+            self.slots
+                .release(&self.trace_id, std::mem::take(&mut self.granted));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn shares_slots_between_invocations() {
+        let slots = FairSlots::new(2);
+        let first = slots.acquire("a", 1).await;
+        let second = slots.acquire("a", 1).await;
+        assert_eq!(slots.available(), 0);
+        assert_eq!(first.granted(), [0]);
+        assert_eq!(second.granted(), [1]);
+
+        // "a" asked first, but "b" holds no slots, so it is served first
+        let waiting_a = tokio::spawn({
+            let slots = slots.clone();
+            async move { slots.acquire("a", 1).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let waiting_b = tokio::spawn({
+            let slots = slots.clone();
+            async move { slots.acquire("b", 1).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        drop(first);
+        let permit_b = waiting_b.await.unwrap();
+        assert_eq!(permit_b.granted(), [0]);
+        assert!(!waiting_a.is_finished());
+
+        drop(second);
+        let permit_a = waiting_a.await.unwrap();
+        drop(permit_a);
+        drop(permit_b);
+        assert_eq!(slots.available(), 2);
+        assert_eq!(slots.acquire("c", 2).await.granted(), [0, 1]);
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiters_give_back_slots() {
+        let slots = FairSlots::new(1);
+        let held = slots.acquire("a", 1).await;
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(10), slots.acquire("b", 1)).await;
+        assert!(cancelled.is_err());
+
+        drop(held);
+        assert_eq!(slots.available(), 1);
+        let _permit = slots.acquire("c", 1).await;
+        assert_eq!(slots.available(), 0);
+    }
+}
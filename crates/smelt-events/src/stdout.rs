@@ -0,0 +1,172 @@
+use smelt_data::Event;
+use tokio::{
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+/// Most lines that are held in a single `CommandStdout` event
+const MAX_BATCH_LINES: usize = 64;
+/// Most bytes of output that are held in a single `CommandStdout` event
+const MAX_BATCH_BYTES: usize = 16 * 1024;
+/// Longest a line is held back, waiting for more lines to batch it with
+const MAX_BATCH_DELAY: Duration = Duration::from_millis(50);
+
+/// Batches the stdout of a single command into `CommandStdout` events
+///
+/// Batches are handed to the event stream without waiting for room in it -- if the stream is
+/// full, the lines of the batch are counted as dropped instead, and they only end up in the log of
+/// the command. The number of lines that were dropped is carried by the next batch that gets
+/// through, and `finish` always gets through
+pub struct StdoutBatcher {
+    command_ref: String,
+    trace_id: String,
+    tx: Sender<Event>,
+    output: String,
+    lines: usize,
+    held_since: Option<Instant>,
+    /// Lines dropped since the last batch that was delivered
+    dropped: u64,
+    total_dropped: u64,
+}
+
+impl StdoutBatcher {
+    pub fn new(command_ref: String, trace_id: String, tx: Sender<Event>) -> Self {
+        Self {
+            command_ref,
+            trace_id,
+            tx,
+            output: String::new(),
+            lines: 0,
+            held_since: None,
+            dropped: 0,
+            total_dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, line: &str) {
+        if self.lines != 0 {
+            self.output.push('\n');
+        }
+        self.output.push_str(line);
+        self.lines += 1;
+        self.held_since.get_or_insert_with(Instant::now);
+        if self.lines >= MAX_BATCH_LINES || self.output.len() >= MAX_BATCH_BYTES {
+            self.flush();
+        }
+    }
+
+    /// When the lines that are held back have to be sent, if any are
+    pub fn deadline(&self) -> Option<Instant> {
+        self.held_since
+            .map(|held_since| held_since + MAX_BATCH_DELAY)
+    }
+
+    /// Sends the lines that are held back, without waiting for room in the event stream
+    pub fn flush(&mut self) {
+        if self.lines == 0 {
+            return;
+        }
+        let event = self.take_batch();
+        if self.tx.try_send(event).is_err() {
+            self.dropped += self.lines as u64;
+            self.total_dropped += self.lines as u64;
+        } else {
+            self.dropped = 0;
+        }
+        self.clear();
+    }
+
+    /// Sends the lines that are still held back, along with the number of lines that were dropped
+    /// since the last delivered batch -- this waits for room in the event stream
+    ///
+    /// Returns how many lines were dropped in total
+    pub async fn finish(mut self) -> u64 {
+        if self.lines != 0 || self.dropped != 0 {
+            let event = self.take_batch();
+            let _ = self.tx.send(event).await;
+        }
+        if self.total_dropped != 0 {
+            tracing::warn!(
+                "Dropped {} lines of stdout from {}, the event stream was full",
+                self.total_dropped,
+                self.command_ref
+            );
+        }
+        self.total_dropped
+    }
+
+    fn take_batch(&mut self) -> Event {
+        Event::command_stdout(
+            self.command_ref.clone(),
+            self.trace_id.clone(),
+            std::mem::take(&mut self.output),
+            self.dropped,
+        )
+    }
+
+    fn clear(&mut self) {
+        self.output.clear();
+        self.lines = 0;
+        self.held_since = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smelt_data::{command_event::CommandVariant, event::Et, CommandStdout};
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn stdout(event: Event) -> CommandStdout {
+        match event.et {
+            Some(Et::Command(command)) => match command.command_variant {
+                Some(CommandVariant::Stdout(stdout)) => stdout,
+                variant => panic!("expected stdout, got {variant:?}"),
+            },
+            et => panic!("expected a command event, got {et:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn batches_lines() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut batcher = StdoutBatcher::new("test".to_string(), "trace".to_string(), tx);
+        batcher.push("first");
+        batcher.push("second");
+        assert!(batcher.deadline().is_some());
+        assert!(rx.try_recv().is_err());
+
+        batcher.flush();
+        assert!(batcher.deadline().is_none());
+        assert_eq!(stdout(rx.try_recv().unwrap()).output, "first\nsecond");
+
+        for line in 0..MAX_BATCH_LINES {
+            batcher.push(&line.to_string());
+        }
+        assert_eq!(
+            stdout(rx.try_recv().unwrap()).output.lines().count(),
+            MAX_BATCH_LINES
+        );
+        assert_eq!(batcher.finish().await, 0);
+    }
+
+    #[tokio::test]
+    async fn counts_dropped_lines() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let mut batcher = StdoutBatcher::new("test".to_string(), "trace".to_string(), tx);
+        batcher.push("delivered");
+        batcher.flush();
+        // the stream is full, so these never make it
+        batcher.push("dropped");
+        batcher.push("dropped too");
+        batcher.flush();
+        batcher.push("last");
+
+        assert_eq!(stdout(rx.recv().await.unwrap()).output, "delivered");
+        let finish = tokio::spawn(batcher.finish());
+        let last = stdout(rx.recv().await.unwrap());
+        assert_eq!((last.output.as_str(), last.dropped_lines), ("last", 2));
+        assert_eq!(finish.await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,323 @@
+use std::{collections::HashMap, io, path::Path};
+
+use serde_json::{json, Value};
+use smelt_data::{
+    command_event::CommandVariant, event::Et, invoke_event::InvokeVariant, CommandEvent, Event,
+    InvokeEvent,
+};
+
+use crate::event_log::read_event_log;
+
+/// Process that holds one track per job slot
+const SLOTS_PID: u32 = 1;
+/// Process that holds the memory and cpu counters of every command
+const RESOURCES_PID: u32 = 2;
+/// Process that holds the commands that run without job slots, e.g. in docker containers
+const UNSLOTTED_PID: u32 = 3;
+
+/// A command that has started, but not finished yet
+struct Running {
+    pid: u32,
+    tracks: Vec<usize>,
+    scheduled_us: f64,
+    started_us: f64,
+}
+
+/// Builds a Chrome trace-event timeline of a run from its events, which opens in Perfetto or
+/// chrome://tracing
+///
+/// Every command is a slice on the track of each job slot it held, from when it started to when
+/// it finished. Commands that hold no job slots each take the lowest free track of a process of
+/// their own. The profile samples of every command become its memory and cpu counter tracks
+#[derive(Default)]
+pub struct Timeline {
+    trace_events: Vec<Value>,
+    /// Microseconds since the epoch of the first event, every timestamp is relative to it
+    origin_us: Option<f64>,
+    last_us: f64,
+    scheduled: HashMap<String, f64>,
+    running: HashMap<String, Running>,
+    /// One more than the highest job slot that a command held
+    slot_count: usize,
+    /// Command in every track of the commands without job slots, None if the track is free
+    unslotted: Vec<Option<String>>,
+}
+
+fn metadata(name: &str, pid: u32, tid: usize, value: &str) -> Value {
+    json!({"name": name, "ph": "M", "pid": pid, "tid": tid, "args": {"name": value}})
+}
+
+impl Timeline {
+    /// Builds the timeline of a run that was recorded to an event log
+    pub fn from_event_log(path: &Path) -> io::Result<Self> {
+        let mut timeline = Self::default();
+        for event in read_event_log(path)?.iter() {
+            timeline.process_event(event);
+        }
+        Ok(timeline)
+    }
+
+    fn relative_us(&mut self, event: &Event) -> f64 {
+        let now = event.time_secs() * 1e6;
+        let origin = *self.origin_us.get_or_insert(now);
+        self.last_us = self.last_us.max(now - origin);
+        now - origin
+    }
+
+    fn take_unslotted_track(&mut self, command_ref: &str) -> usize {
+        let track = match self.unslotted.iter().position(Option::is_none) {
+            Some(track) => track,
+            None => {
+                self.unslotted.push(None);
+                self.unslotted.len() - 1
+            }
+        };
+        self.unslotted[track] = Some(command_ref.to_string());
+        track
+    }
+
+    fn start_command(&mut self, command_ref: &str, job_slots: &[u32], ts: f64) {
+        let (pid, tracks) = if job_slots.is_empty() {
+            (UNSLOTTED_PID, vec![self.take_unslotted_track(command_ref)])
+        } else {
+            let tracks: Vec<usize> = job_slots.iter().map(|slot| *slot as usize).collect();
+            self.slot_count = self.slot_count.max(tracks.iter().max().unwrap() + 1);
+            (SLOTS_PID, tracks)
+        };
+        let scheduled_us = self.scheduled.remove(command_ref).unwrap_or(ts);
+        self.running.insert(
+            command_ref.to_string(),
+            Running {
+                pid,
+                tracks,
+                scheduled_us,
+                started_us: ts,
+            },
+        );
+    }
+
+    fn finish_command(&mut self, command_ref: &str, end_us: f64, args: Value) {
+        let Some(running) = self.running.remove(command_ref) else {
+            return;
+        };
+        let mut args = args;
+        args["queued_ms"] = json!((running.started_us - running.scheduled_us) / 1e3);
+        for track in running.tracks {
+            if running.pid == UNSLOTTED_PID {
+                self.unslotted[track] = None;
+            }
+            self.trace_events.push(json!({
+                "name": command_ref,
+                "cat": "command",
+                "ph": "X",
+                "ts": running.started_us,
+                "dur": end_us - running.started_us,
+                "pid": running.pid,
+                "tid": track,
+                "args": args,
+            }));
+        }
+    }
+
+    pub fn process_event(&mut self, event: &Event) {
+        let ts = self.relative_us(event);
+        match event.et.as_ref() {
+            Some(Et::Command(CommandEvent {
+                command_ref,
+                command_variant: Some(variant),
+            })) => match variant {
+                CommandVariant::Scheduled(_) => {
+                    self.scheduled.insert(command_ref.clone(), ts);
+                }
+                CommandVariant::Started(started) => {
+                    // a failed test that is rerun starts again, in the slots of the new attempt
+                    self.finish_command(command_ref, ts, json!({"rerun": true}));
+                    self.start_command(command_ref, &started.job_slots, ts);
+                }
+                CommandVariant::Finished(finished) => {
+                    let outputs = finished.outputs.clone().unwrap_or_default();
+                    let args = json!({
+                        "exit_code": outputs.exit_code,
+                        "status": outputs.status().as_str_name(),
+                        "target_type": finished.command_type,
+                    });
+                    self.finish_command(command_ref, ts, args);
+                }
+                CommandVariant::Cancelled(_) => {
+                    self.finish_command(command_ref, ts, json!({"cancelled": true}));
+                }
+                CommandVariant::Profile(profile) => {
+                    self.trace_events.extend([
+                        json!({
+                            "name": format!("{command_ref} memory"),
+                            "ph": "C",
+                            "ts": ts,
+                            "pid": RESOURCES_PID,
+                            "args": {"memory_mb": profile.memory_used as f64 / (1024.0 * 1024.0)},
+                        }),
+                        json!({
+                            "name": format!("{command_ref} cpu"),
+                            "ph": "C",
+                            "ts": ts,
+                            "pid": RESOURCES_PID,
+                            "args": {"cpu_load": profile.cpu_load},
+                        }),
+                    ]);
+                }
+                _ => {}
+            },
+            Some(Et::Invoke(InvokeEvent {
+                invoke_variant: Some(InvokeVariant::Start(start)),
+            })) => {
+                self.trace_events.push(json!({
+                    "name": "invocation started",
+                    "ph": "i",
+                    "s": "g",
+                    "ts": ts,
+                    "pid": SLOTS_PID,
+                    "tid": 0,
+                    "args": {"trace_id": event.trace_id, "hostname": start.hostname},
+                }));
+            }
+            _ => {}
+        }
+    }
+
+    /// The timeline as a Chrome trace-event JSON object
+    ///
+    /// Commands that never finished -- e.g. because the run was cut short -- end with the last
+    /// event of the run
+    pub fn to_json(mut self) -> Value {
+        let unfinished: Vec<String> = self.running.keys().cloned().collect();
+        for command_ref in unfinished {
+            self.finish_command(&command_ref, self.last_us, json!({"unfinished": true}));
+        }
+        let mut trace_events = vec![
+            metadata("process_name", SLOTS_PID, 0, "job slots"),
+            metadata("process_name", RESOURCES_PID, 0, "resources"),
+            metadata("process_name", UNSLOTTED_PID, 0, "without job slots"),
+        ];
+        trace_events.extend(
+            (0..self.slot_count)
+                .map(|slot| metadata("thread_name", SLOTS_PID, slot, &format!("slot {slot}"))),
+        );
+        trace_events.extend((0..self.unslotted.len()).map(|track| {
+            metadata(
+                "thread_name",
+                UNSLOTTED_PID,
+                track,
+                &format!("track {track}"),
+            )
+        }));
+        trace_events.append(&mut self.trace_events);
+        json!({"traceEvents": trace_events, "displayTimeUnit": "ms"})
+    }
+}
+
+/// Writes the timeline of the run recorded at `event_log` to `output`
+pub fn write_timeline(event_log: &Path, output: &Path) -> io::Result<()> {
+    let timeline = Timeline::from_event_log(event_log)?.to_json();
+    std::fs::write(output, serde_json::to_string(&timeline)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use smelt_data::{
+        executed_tests::{TestOutputs, TestResult},
+        CommandProfile,
+    };
+
+    use super::*;
+    use crate::new_command_event;
+
+    fn at(mut event: Event, millis: u64) -> Event {
+        let time = UNIX_EPOCH + Duration::from_millis(100_000 + millis);
+        event.time = Some(time.into());
+        event
+    }
+
+    #[test]
+    fn commands_run_in_their_slots() {
+        let trace_id = "trace".to_string();
+        let started = |name: &str, job_slots: Vec<u32>| {
+            Event::command_started(name.to_string(), job_slots, trace_id.clone())
+        };
+        let finished = |name: &str| {
+            Event::command_finished(
+                TestResult {
+                    test_name: name.to_string(),
+                    outputs: Some(TestOutputs::default()),
+                    metadata: None,
+                },
+                "test".to_string(),
+                0.0,
+                trace_id.clone(),
+            )
+        };
+        let mut timeline = Timeline::default();
+        for event in [
+            at(
+                Event::command_scheduled("a".to_string(), trace_id.clone()),
+                0,
+            ),
+            at(started("a", vec![0, 2]), 1),
+            at(started("b", vec![]), 2),
+            at(
+                new_command_event(
+                    "a".to_string(),
+                    CommandVariant::Profile(CommandProfile {
+                        memory_used: 2 * 1024 * 1024,
+                        cpu_load: 1.5,
+                        time_since_start_ms: 2,
+                        ..Default::default()
+                    }),
+                    trace_id.clone(),
+                ),
+                3,
+            ),
+            at(finished("a"), 4),
+            at(started("c", vec![1]), 5),
+            at(started("c", vec![2]), 6),
+            at(finished("c"), 7),
+        ] {
+            timeline.process_event(&event);
+        }
+
+        let trace = timeline.to_json();
+        let slices: Vec<(&str, u64, u64, f64)> = trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|val| val["ph"] == "X")
+            .map(|val| {
+                (
+                    val["name"].as_str().unwrap(),
+                    val["pid"].as_u64().unwrap(),
+                    val["tid"].as_u64().unwrap(),
+                    val["dur"].as_f64().unwrap(),
+                )
+            })
+            .collect();
+        // a holds two slots, the rerun of c moves to the slot it was granted, and b holds no slots
+        // and never finished, so it ends with the run
+        assert_eq!(
+            slices,
+            [
+                ("a", 1, 0, 3000.0),
+                ("a", 1, 2, 3000.0),
+                ("c", 1, 1, 1000.0),
+                ("c", 1, 2, 1000.0),
+                ("b", 3, 0, 5000.0)
+            ]
+        );
+        let memory = trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|val| val["name"] == "a memory")
+            .unwrap();
+        assert_eq!(memory["args"]["memory_mb"], 2.0);
+    }
+}
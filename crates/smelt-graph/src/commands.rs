@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 
 use std::{
+    collections::{BTreeMap, HashMap},
     fmt,
     path::{Path, PathBuf},
     str::FromStr,
@@ -12,6 +13,7 @@ use std::{
 };
 
 use smelt_core::SmeltErr;
+use smelt_data::executed_tests::TestMetaData;
 
 use crate::digest::{CommandDefDigest, CommandIdDigest};
 use smelt_core::CommandDefPath;
@@ -46,6 +48,12 @@ pub struct Command {
     pub working_dir: PathBuf,
     #[serde(default)]
     pub on_failure: Option<CommandDependency>,
+    /// Free-form labels that can be used to select commands
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Free-form key/value pairs that are carried into the results of the command
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
 }
 
 impl Command {
@@ -89,6 +97,30 @@ impl Command {
     pub fn script_contents(&self) -> impl Iterator<Item = String> + '_ {
         self.script.iter().cloned()
     }
+
+    /// Metadata key that is lifted into `TestMetaData::test_owner`
+    pub const fn owner_key() -> &'static str {
+        "owner"
+    }
+
+    /// Metadata key that is lifted into `TestMetaData::design_under_test`
+    pub const fn dut_key() -> &'static str {
+        "dut"
+    }
+
+    pub fn test_metadata(&self) -> TestMetaData {
+        let mut extras: HashMap<String, String> = self
+            .metadata
+            .iter()
+            .map(|(key, val)| (key.clone(), val.clone()))
+            .collect();
+        TestMetaData {
+            test_owner: extras.remove(Self::owner_key()).unwrap_or_default(),
+            design_under_test: extras.remove(Self::dut_key()).unwrap_or_default(),
+            extras,
+            tags: self.tags.clone(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Dupe, PartialEq, Eq, Hash, Debug, Allocative)]
@@ -165,6 +197,57 @@ pub(crate) struct CommandScriptInner {
     deps: Vec<CommandScript>,
 }
 
+/// A command with an empty script and no dependencies, for tests to build on
+#[cfg(test)]
+pub(crate) fn test_command(name: &str, target_type: TargetType) -> Command {
+    Command {
+        name: name.to_string(),
+        target_type,
+        script: vec![],
+        dependent_files: vec![],
+        dependencies: vec![],
+        outputs: vec![],
+        runtime: Runtime {
+            num_cpus: 1,
+            max_memory_mb: 1,
+            timeout: 1,
+        },
+        working_dir: PathBuf::new(),
+        on_failure: None,
+        tags: vec![],
+        metadata: BTreeMap::new(),
+    }
+}
+
+#[cfg(test)]
+impl Command {
+    pub(crate) fn with_tags(mut self, tags: &[&str]) -> Self {
+        self.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        self
+    }
+
+    pub(crate) fn with_deps(mut self, deps: &[&str]) -> Self {
+        self.dependencies = deps
+            .iter()
+            .map(|dep| CommandDependency(dep.to_string()))
+            .collect();
+        self
+    }
+
+    pub(crate) fn with_outputs(mut self, outputs: &[&str]) -> Self {
+        self.outputs = outputs
+            .iter()
+            .map(|output| CommandDefPath::new(output.to_string()))
+            .collect();
+        self
+    }
+
+    pub(crate) fn with_metadata(mut self, key: &str, val: &str) -> Self {
+        self.metadata.insert(key.to_string(), val.to_string());
+        self
+    }
+}
+
 //pub async fn maybe_cache(command: &Command) -> Result<CommandOutput, SmeltErr> {
 //    if let Ok(command_out) = command.get_status_from_fs().await {
 //        if command_out.passed() {
@@ -188,4 +271,19 @@ mod tests {
 
         let _script = script.unwrap();
     }
+
+    #[test]
+    fn metadata_is_split_into_test_metadata() {
+        let command = test_command("t", TargetType::Test)
+            .with_tags(&["smoke"])
+            .with_metadata("owner", "jdoe")
+            .with_metadata("dut", "uart")
+            .with_metadata("seed", "7");
+        let metadata = command.test_metadata();
+        assert_eq!(metadata.test_owner, "jdoe");
+        assert_eq!(metadata.design_under_test, "uart");
+        assert_eq!(metadata.extras.len(), 1);
+        assert_eq!(metadata.extras["seed"], "7");
+        assert_eq!(metadata.tags, vec!["smoke".to_string()]);
+    }
 }
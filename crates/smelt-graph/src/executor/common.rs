@@ -8,13 +8,14 @@ use dice::DiceData;
 
 use smelt_data::{
     executed_tests::{
-        artifact_pointer::Pointer, ArtifactPointer, ExecutedTestResult, TestOutputs, TestResult,
+        artifact_pointer::Pointer, ArtifactPointer, ExecutedTestResult, ResourceUsage, TestOutputs,
+        TestResult, TestStatus,
     },
     Event,
 };
 
-use smelt_events::runtime_support::GetSmeltRoot;
-use tokio::{fs::File, io::AsyncWriteExt, sync::mpsc::Sender};
+use smelt_events::{runtime_support::GetSmeltRoot, stdout::StdoutBatcher};
+use tokio::{fs::File, io::AsyncWriteExt, sync::mpsc::Sender, time::Instant};
 
 pub(crate) struct Workspace {
     pub(crate) script_file: PathBuf,
@@ -26,23 +27,12 @@ pub(crate) fn get_target_root<T: Display, S: Display>(smelt_root: T, command_nam
     format!("{}/{}/{}", smelt_root, "smelt-out", command_name)
 }
 
-/// Creates all of the directory scaffolding expected by a command
-///
-/// This function is currently used across all executors, and is always executed in the host
-/// filesystem
-pub(crate) async fn prepare_workspace(
+/// The full contents of the `command.sh` that is executed for a command
+pub(crate) fn script_contents(
     command: &Command,
-    smelt_root: PathBuf,
+    smelt_root: &Path,
     command_working_dir: &Path,
-) -> anyhow::Result<Workspace> {
-    let working_dir = command.default_target_root(smelt_root.as_path())?;
-    let script_file = working_dir.join(Command::script_file());
-    let stdout_file = working_dir.join(Command::stdout_file());
-    tokio::fs::create_dir_all(&working_dir).await?;
-    let mut file = File::create(&script_file).await?;
-
-    let stdout = File::create(&stdout_file).await?;
-
+) -> anyhow::Result<Vec<u8>> {
     let mut buf: Vec<u8> = Vec::new();
 
     writeln!(buf, "export SMELT_ROOT={}", smelt_root.to_string_lossy())?;
@@ -58,6 +48,27 @@ pub(crate) async fn prepare_workspace(
     for script_line in &command.script {
         writeln!(buf, "{}", script_line)?;
     }
+    Ok(buf)
+}
+
+/// Creates all of the directory scaffolding expected by a command
+///
+/// This function is currently used across all executors, and is always executed in the host
+/// filesystem
+pub(crate) async fn prepare_workspace(
+    command: &Command,
+    smelt_root: PathBuf,
+    command_working_dir: &Path,
+) -> anyhow::Result<Workspace> {
+    let working_dir = command.default_target_root(smelt_root.as_path())?;
+    let script_file = working_dir.join(Command::script_file());
+    let stdout_file = working_dir.join(Command::stdout_file());
+    tokio::fs::create_dir_all(&working_dir).await?;
+    let mut file = File::create(&script_file).await?;
+
+    let stdout = File::create(&stdout_file).await?;
+
+    let buf = script_contents(command, smelt_root.as_path(), command_working_dir)?;
 
     file.write_all(&buf).await?;
     file.flush().await?;
@@ -67,31 +78,58 @@ pub(crate) async fn prepare_workspace(
     })
 }
 
-pub(crate) async fn handle_line(
+/// Batches the stdout of a command into events -- `None` if the stdout is not streamed at all
+pub(crate) fn stdout_batcher(
     command: &Command,
-    line: String,
     trace_id: String,
     tx_chan: &Sender<Event>,
+    silent: bool,
+) -> Option<StdoutBatcher> {
+    (!silent).then(|| StdoutBatcher::new(command.name.clone(), trace_id, tx_chan.clone()))
+}
+
+/// When the stdout held back by `batcher` has to be sent, if any is held back
+pub(crate) fn stdout_deadline(batcher: &Option<StdoutBatcher>) -> Option<Instant> {
+    batcher.as_ref().and_then(StdoutBatcher::deadline)
+}
+
+/// Writes a line of output to the log of the command, and hands it to the batcher
+///
+/// This never waits on the consumers of the events, so a slow consumer does not slow down the
+/// command
+pub(crate) async fn handle_line(
+    line: String,
+    batcher: &mut Option<StdoutBatcher>,
     stdout: &mut File,
-    avoid_message: bool,
 ) {
-    if !avoid_message {
-        let _handleme = tx_chan
-            .send(Event::command_stdout(
-                command.name.clone(),
-                trace_id.clone(),
-                line.clone(),
-            ))
-            .await;
+    if let Some(batcher) = batcher.as_mut() {
+        batcher.push(&line);
     }
     let bytes = line.as_str();
     let _unhandled = stdout.write(bytes.as_bytes()).await;
     let _unhandled = stdout.write(&[b'\n']).await;
 }
 
+/// Copies the log of the latest attempt of a command aside, so that a rerun does not overwrite it
+pub(crate) async fn keep_attempt_log(
+    command: &Command,
+    global_data: &DiceData,
+    attempt: u32,
+) -> anyhow::Result<ArtifactPointer> {
+    let smelt_root = global_data.get_smelt_root();
+    let target_root = PathBuf::from(get_target_root(smelt_root.to_string_lossy(), &command.name));
+    let attempt_log = target_root.join(format!("command.attempt-{attempt}.out"));
+    tokio::fs::copy(target_root.join(Command::stdout_file()), &attempt_log).await?;
+    Ok(ArtifactPointer::file_artifact(
+        format!("smelt_log_attempt_{attempt}"),
+        attempt_log,
+    ))
+}
+
 pub(crate) fn create_test_result(
     command: &Command,
     exit_code: i32,
+    usage: ResourceUsage,
     global_data: &DiceData,
 ) -> ExecutedTestResult {
     let command_default_dir = command.working_dir.clone();
@@ -131,7 +169,15 @@ pub(crate) fn create_test_result(
         outputs: Some(TestOutputs {
             artifacts,
             exit_code,
+            status: if exit_code == 0 {
+                TestStatus::Passed
+            } else {
+                TestStatus::Failed
+            }
+            .into(),
+            usage: Some(usage),
         }),
+        metadata: Some(command.test_metadata()),
     };
 
     if missing_artifacts.is_empty() {
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::SystemTime};
 
 use anyhow::Error;
 use async_trait::async_trait;
@@ -10,23 +10,30 @@ use bollard::{
 };
 use bollard::container::LogOutput;
 use bollard::models::ResourcesUlimits;
-use chrono::Utc;
 use dice::{DiceData, UserComputationData};
 use futures::StreamExt;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
-use tokio::fs::File;
+use tokio::{fs::File, sync::oneshot, time::Instant};
 
 use smelt_core::SmeltErr;
-use smelt_data::{Event, executed_tests::ExecutedTestResult};
+use smelt_data::{
+    Event,
+    executed_tests::{ExecutedTestResult, ResourceUsage},
+};
 use smelt_data::client_commands::{CfgDocker, RunMode, Ulimit};
-use smelt_events::runtime_support::{GetSmeltCfg, GetSmeltRoot, GetTraceId, GetTxChannel};
+use smelt_events::runtime_support::{
+    GetCommandPeaks, GetProfilingFreq, GetSmeltCfg, GetSmeltRoot, GetTraceId, GetTxChannel,
+};
 
-use crate::Command;
+use crate::{utils::local_hostname, Command};
 use crate::executor::Executor;
-use crate::executor::profiler::profile_cmd_docker;
+use crate::executor::profiler::{container_usage, profile_cmd_docker};
 
-use super::common::{create_test_result, get_target_root, handle_line, prepare_workspace};
+use super::common::{
+    create_test_result, get_target_root, handle_line, prepare_workspace, stdout_batcher,
+    stdout_deadline,
+};
 
 pub struct DockerExecutor {
     docker_client: Docker,
@@ -186,16 +193,19 @@ impl Executor for DockerExecutor {
             )
             .await?;
 
-        // Send a message that the command has started, and then start the container
+        // Send a message that the command has started, and then start the container -- containers
+        // don't take job slots
         let _ = tx
             .send(Event::command_started(
                 command.name.clone(),
+                vec![],
                 trace_id.clone(),
             ))
             .await;
         docker
             .start_container(&container.id, None::<StartContainerOptions<String>>)
             .await?;
+        let started_at = SystemTime::now();
 
         // attach to docker logs -- this will also pick up any output that was emitted between the
         // container being started and the "attaching"
@@ -207,35 +217,41 @@ impl Executor for DockerExecutor {
         };
         let mut output = docker.logs(&container.id, Some(attach_options));
 
-        let profile_start_time_millis: u64 = Utc::now().timestamp_millis().try_into().unwrap();
-        let docker_clone = docker.clone();
-        let tx_clone = tx.clone();
-        let command_name_clone = command.name.clone();
-        let trace_id_clone = trace_id.clone();
-        let sample_task = tokio::spawn(async move {
-            profile_cmd_docker(
-                tx_clone,
-                docker_clone,
-                command_name_clone,
-                trace_id_clone,
-                profile_start_time_millis,
-            ).await;
+        let sample_task = global_data.get_profiling_freq().map(|freq| {
+            let (stop, stopped) = oneshot::channel();
+            let task = tokio::spawn(profile_cmd_docker(
+                tx.clone(),
+                docker.clone(),
+                container_name.clone(),
+                command.name.clone(),
+                trace_id.clone(),
+                freq,
+                stopped,
+            ));
+            (stop, task)
         });
 
-        while let Some(message) = output.next().await {
+        let mut batcher = stdout_batcher(command.as_ref(), trace_id.clone(), &tx, silent);
+        loop {
+            let deadline = stdout_deadline(&batcher);
+            let message = tokio::select!(
+                message = output.next() => message,
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() => {
+                    if let Some(batcher) = batcher.as_mut() {
+                        batcher.flush();
+                    }
+                    continue;
+                }
+            );
+            let Some(message) = message else {
+                break;
+            };
             match message {
                 Ok(output) => match output {
                     LogOutput::StdOut { message } | LogOutput::StdErr { message } => {
                         let line = String::from_utf8_lossy(&*message);
-                        handle_line(
-                            command.as_ref(),
-                            line.to_string(),
-                            trace_id.clone(),
-                            &tx,
-                            &mut stdout,
-                            silent,
-                        )
-                        .await;
+                        handle_line(line.to_string(), &mut batcher, &mut stdout).await;
                     }
 
                     // From looking at the code, console messages are docker telemetry that come
@@ -270,11 +286,38 @@ impl Executor for DockerExecutor {
             },
         };
 
-        sample_task.abort();
+        let finished_at = SystemTime::now();
+        // the cpu times of the container add up, so a single read once it exited covers its run
+        let container_usage = container_usage(docker, &container_name).await;
+        let mut usage = ResourceUsage {
+            started_at: Some(started_at.into()),
+            finished_at: Some(finished_at.into()),
+            user_cpu_ms: container_usage.user_cpu_ms,
+            system_cpu_ms: container_usage.system_cpu_ms,
+            peak_rss_bytes: container_usage.peak_rss_bytes,
+            hostname: local_hostname(),
+            container: container_name.clone(),
+        };
+
+        //stop the sampling task, and keep the peaks it saw
+        if let Some((stop, task)) = sample_task {
+            let _ = stop.send(());
+            if let Ok(peaks) = task.await {
+                // without a max usage from docker, the samples may have seen a higher peak
+                usage.peak_rss_bytes = usage.peak_rss_bytes.max(peaks.memory_used);
+                if let Some(command_peaks) = dd.get_command_peaks() {
+                    command_peaks.record(&command.name, &peaks);
+                }
+            }
+        }
+        if let Some(batcher) = batcher {
+            batcher.finish().await;
+        }
 
         Ok(create_test_result(
             command.as_ref(),
             status_code.try_into().unwrap(),
+            usage,
             global_data,
         ))
     }
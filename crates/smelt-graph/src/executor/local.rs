@@ -1,23 +1,29 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{path::PathBuf, sync::Arc, time::SystemTime};
 use std::process::Stdio;
 
 use async_trait::async_trait;
 use dice::{DiceData, UserComputationData};
 use tokio::{
     io::{AsyncBufReadExt, BufReader},
-    sync::mpsc::Sender,
+    process::{ChildStderr, ChildStdout},
+    sync::{mpsc::Sender, oneshot},
+    time::Instant,
 };
 
 use smelt_data::{
     Event,
-    executed_tests::{ExecutedTestResult, TestOutputs},
+    executed_tests::{ExecutedTestResult, ResourceUsage, TestOutputs},
 };
 use smelt_events::runtime_support::{
-    GetProfilingFreq, GetSmeltCfg, GetSmeltRoot, GetTraceId, GetTxChannel, LockSemaphore,
+    GetCommandPeaks, GetProfilingFreq, GetSmeltCfg, GetSmeltRoot, GetTraceId, GetTxChannel,
+    LockSemaphore,
 };
 
-use crate::Command;
-use crate::executor::{common::handle_line, Executor};
+use crate::{utils::local_hostname, Command};
+use crate::executor::{
+    common::{handle_line, stdout_batcher, stdout_deadline},
+    Executor,
+};
 
 use super::{
     common::{create_test_result, prepare_workspace, Workspace},
@@ -45,28 +51,82 @@ impl Executor for LocalExecutor {
             tx.clone(),
             command_default_dir,
             root,
+            dd,
             global_data,
         )
         .await
-        .map(|output| create_test_result(local_command.as_ref(), output.exit_code, global_data))?;
+        .map(|output| {
+            let usage = output.usage.unwrap_or_default();
+            create_test_result(local_command.as_ref(), output.exit_code, usage, global_data)
+        })?;
         Ok(rv)
     }
 }
 
+/// How a command exited, and the resources it used
+#[derive(Debug)]
+struct ExitUsage {
+    exit_code: i32,
+    user_cpu_ms: u64,
+    system_cpu_ms: u64,
+    peak_rss_bytes: u64,
+}
+
+fn timeval_ms(time: libc::timeval) -> u64 {
+    time.tv_sec as u64 * 1000 + time.tv_usec as u64 / 1000
+}
+
+/// Reaps the process `pid`, returning its exit code along with its resource usage
+///
+/// The usage covers the process and every descendant it waited for, so commands are spawned with
+/// std and reaped here rather than by tokio -- this blocks, so it has to run on a blocking thread
+fn wait_with_usage(pid: u32) -> std::io::Result<ExitUsage> {
+    let mut status = 0;
+    // SAFETY: rusage is plain old data, and wait4 only writes to the pointers it is given
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        let rv = unsafe { libc::wait4(pid as libc::pid_t, &mut status, 0, &mut usage) };
+        if rv != -1 {
+            break;
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    let exit_code = if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else {
+        -555
+    };
+    // linux reports the max rss in kilobytes, macos in bytes
+    let rss_unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    Ok(ExitUsage {
+        exit_code,
+        user_cpu_ms: timeval_ms(usage.ru_utime),
+        system_cpu_ms: timeval_ms(usage.ru_stime),
+        peak_rss_bytes: usage.ru_maxrss.max(0) as u64 * rss_unit,
+    })
+}
+
 async fn execute_local_command(
     command: &Command,
     trace_id: String,
     tx_chan: Sender<Event>,
     command_working_dir: PathBuf,
     root: PathBuf,
+    dd: &UserComputationData,
     global_data: &DiceData,
 ) -> anyhow::Result<TestOutputs> {
     let silent = global_data.get_smelt_cfg().silent;
-    let _sem = global_data.lock_sem(command.runtime.num_cpus).await;
+    let slots = global_data
+        .lock_sem(&trace_id, command.runtime.num_cpus)
+        .await;
     let shell = "bash";
     let _handle_me = tx_chan
         .send(Event::command_started(
             command.name.clone(),
+            slots.granted().to_vec(),
             trace_id.clone(),
         ))
         .await;
@@ -77,66 +137,117 @@ async fn execute_local_command(
         ..
     } = prepare_workspace(command, root.clone(), command_working_dir.as_path()).await?;
 
-    let mut commandlocal = tokio::process::Command::new(shell);
+    let mut commandlocal = std::process::Command::new(shell);
 
     commandlocal
         .arg(script_file)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
+    let started_at = SystemTime::now();
     let mut comm_handle = commandlocal.spawn()?;
-    let stderr = comm_handle.stderr.take().unwrap();
+    let stderr = ChildStderr::from_std(comm_handle.stderr.take().unwrap())?;
     let stderr_reader = BufReader::new(stderr);
     let mut stderr_lines = stderr_reader.lines();
 
-    let reader = BufReader::new(comm_handle.stdout.take().unwrap());
+    let stdout_pipe = ChildStdout::from_std(comm_handle.stdout.take().unwrap())?;
+    let reader = BufReader::new(stdout_pipe);
     let mut lines = reader.lines();
-    let maybe_pid = comm_handle.id();
-
-    let sample_task = maybe_pid.and_then(|pid| {
-        global_data.get_profiling_freq().map(|freq| {
-            tokio::spawn(profile_cmd(
-                pid,
-                tx_chan.clone(),
-                freq,
-                command.name.clone(),
-                trace_id.clone(),
-            ))
-        })
+    let pid = comm_handle.id();
+    let mut exited = tokio::task::spawn_blocking(move || wait_with_usage(pid));
+
+    let sample_task = global_data.get_profiling_freq().map(|freq| {
+        let (stop, stopped) = oneshot::channel();
+        let task = tokio::spawn(profile_cmd(
+            pid,
+            tx_chan.clone(),
+            freq,
+            command.name.clone(),
+            trace_id.clone(),
+            stopped,
+        ));
+        (stop, task)
     });
 
     //let sample_task = ;
 
+    let mut batcher = stdout_batcher(command, trace_id.clone(), &tx_chan, silent);
     let cstatus: TestOutputs = loop {
+        let deadline = stdout_deadline(&batcher);
         tokio::select!(
             Ok(Some(line)) = lines.next_line() => {
-                handle_line(command,line,trace_id.clone(),&tx_chan,&mut stdout, silent).await;
+                handle_line(line, &mut batcher, &mut stdout).await;
             }
             Ok(Some(line)) = stderr_lines.next_line() => {
-                handle_line(command,line,trace_id.clone(),&tx_chan,&mut stdout, silent).await;
+                handle_line(line, &mut batcher, &mut stdout).await;
             }
-            status_code = comm_handle.wait() => {
-                break status_code.map(|val| TestOutputs{ exit_code: val.code().unwrap_or(-555), artifacts: vec![]});
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                if deadline.is_some() => {
+                if let Some(batcher) = batcher.as_mut() {
+                    batcher.flush();
+                }
+            }
+            exit = &mut exited => {
+                break exit?.map(|exit| TestOutputs {
+                    exit_code: exit.exit_code,
+                    usage: Some(ResourceUsage {
+                        started_at: Some(started_at.into()),
+                        finished_at: Some(SystemTime::now().into()),
+                        user_cpu_ms: exit.user_cpu_ms,
+                        system_cpu_ms: exit.system_cpu_ms,
+                        peak_rss_bytes: exit.peak_rss_bytes,
+                        hostname: local_hostname(),
+                        container: String::new(),
+                    }),
+                    ..Default::default()
+                });
             }
 
 
         );
     }?;
-    //kill the sampling task
-    if let Some(task) = sample_task {
-        task.abort()
-    }
 
     while let Ok(Some(line)) = lines.next_line().await {
-        handle_line(
-            command,
-            line,
-            trace_id.clone(),
-            &tx_chan,
-            &mut stdout,
-            silent,
-        )
-        .await;
+        handle_line(line, &mut batcher, &mut stdout).await;
+    }
+    // the command is done with its slots -- they go to the next command before the profiler and
+    // the last of the output are handed to a consumer that may be slow
+    drop(slots);
+    //stop the sampling task, and keep the peaks it saw
+    if let Some((stop, task)) = sample_task {
+        let _ = stop.send(());
+        if let (Ok(peaks), Some(command_peaks)) = (task.await, dd.get_command_peaks()) {
+            command_peaks.record(&command.name, &peaks);
+        }
+    }
+    if let Some(batcher) = batcher {
+        batcher.finish().await;
     }
 
     Ok(cstatus)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reaps_with_usage() {
+        // the busy loop runs in a grandchild, which the usage still covers -- the child is
+        // reaped by wait_with_usage, like every command
+        let pid = std::process::Command::new("sh")
+            .args([
+                "-c",
+                "sh -c 'i=0; while [ $i -lt 300000 ]; do i=$((i+1)); done; exit 3'",
+            ])
+            .spawn()
+            .unwrap()
+            .id();
+        let exit = tokio::task::spawn_blocking(move || wait_with_usage(pid))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(exit.exit_code, 3);
+        assert!(exit.user_cpu_ms + exit.system_cpu_ms > 0, "{exit:?}");
+        assert!(exit.peak_rss_bytes > 0);
+    }
+}
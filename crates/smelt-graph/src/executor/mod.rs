@@ -11,8 +11,11 @@ mod common;
 mod docker;
 mod local;
 mod profiler;
+#[cfg(target_os = "linux")]
+mod proc_tree;
 
 use async_trait::async_trait;
+pub(crate) use common::{keep_attempt_log, script_contents};
 #[cfg(feature = "docker")]
 pub use docker::DockerExecutor;
 pub use local::LocalExecutor;
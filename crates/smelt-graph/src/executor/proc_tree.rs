@@ -0,0 +1,159 @@
+//! Samples the resources used by a whole process tree on linux, from /proc
+//!
+//! Commands are bash scripts that often start their real work through a wrapper -- make, a
+//! simulator launcher, etc -- so every descendant of the command is counted, not just its direct
+//! children
+
+use std::collections::HashMap;
+
+/// Resources used by a process tree at the time it was sampled
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct TreeSample {
+    pub rss_bytes: u64,
+    /// user and system cpu time, including that of descendants that have exited and been waited
+    /// for
+    pub cpu_time_ns: u64,
+    pub io_read_bytes: u64,
+    pub io_write_bytes: u64,
+    pub num_threads: u32,
+    pub num_processes: u32,
+}
+
+/// The fields of /proc/<pid>/stat that are sampled
+#[derive(Debug, PartialEq)]
+struct ProcStat {
+    ppid: u32,
+    /// utime + stime + cutime + cstime, in clock ticks
+    cpu_ticks: u64,
+    num_threads: u32,
+    rss_pages: u64,
+}
+
+fn parse_stat(contents: &str) -> Option<ProcStat> {
+    // the command name is in parens, and can hold spaces and parens itself
+    let (_, rest) = contents.rsplit_once(')')?;
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    // `fields` starts at the state, which is field 3 in proc(5)
+    let field = |idx: usize| -> Option<u64> { fields.get(idx - 3)?.parse().ok() };
+    let cpu_ticks = field(14)? + field(15)? + field(16)? + field(17)?;
+    Some(ProcStat {
+        ppid: field(4)? as u32,
+        cpu_ticks,
+        num_threads: field(20)? as u32,
+        rss_pages: field(24)?,
+    })
+}
+
+/// Returns (read_bytes, write_bytes) from /proc/<pid>/io
+fn parse_io(contents: &str) -> (u64, u64) {
+    let mut read_bytes = 0;
+    let mut write_bytes = 0;
+    for line in contents.lines() {
+        match line.split_once(':') {
+            Some(("read_bytes", val)) => read_bytes = val.trim().parse().unwrap_or_default(),
+            Some(("write_bytes", val)) => write_bytes = val.trim().parse().unwrap_or_default(),
+            _ => {}
+        }
+    }
+    (read_bytes, write_bytes)
+}
+
+fn read_stat(pid: u32) -> Option<ProcStat> {
+    parse_stat(&std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?)
+}
+
+/// Pids of every process in the tree rooted at `root`, including `root`
+fn tree_pids(root: u32) -> Vec<u32> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    if let Ok(entries) = std::fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|val| val.parse().ok()) else {
+                continue;
+            };
+            if let Some(stat) = read_stat(pid) {
+                children.entry(stat.ppid).or_default().push(pid);
+            }
+        }
+    }
+    let mut pids = vec![root];
+    let mut idx = 0;
+    while idx < pids.len() {
+        if let Some(kids) = children.get(&pids[idx]) {
+            pids.extend(kids);
+        }
+        idx += 1;
+    }
+    pids
+}
+
+/// Samples every process in the tree rooted at `root`
+///
+/// Returns None once `root` has exited. Processes that exit while they are sampled are skipped
+pub(crate) fn sample_process_tree(root: u32) -> Option<TreeSample> {
+    read_stat(root)?;
+    // SAFETY: sysconf has no preconditions
+    let (ticks_per_sec, page_size) =
+        unsafe { (libc::sysconf(libc::_SC_CLK_TCK), libc::sysconf(libc::_SC_PAGESIZE)) };
+    let ns_per_tick = 1_000_000_000 / ticks_per_sec.max(1) as u64;
+
+    let mut sample = TreeSample::default();
+    for pid in tree_pids(root) {
+        let Some(stat) = read_stat(pid) else {
+            continue;
+        };
+        sample.rss_bytes += stat.rss_pages * page_size.max(0) as u64;
+        sample.cpu_time_ns += stat.cpu_ticks * ns_per_tick;
+        sample.num_threads += stat.num_threads;
+        sample.num_processes += 1;
+        // io is only readable for processes of the same user, which the command's always are
+        if let Ok(io) = std::fs::read_to_string(format!("/proc/{pid}/io")) {
+            let (read_bytes, write_bytes) = parse_io(&io);
+            sample.io_read_bytes += read_bytes;
+            sample.io_write_bytes += write_bytes;
+        }
+    }
+    Some(sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn parses_stat_with_odd_command_names() {
+        let stat = "1234 (make (sim) x) S 1200 1234 1200 0 -1 4194560 300 0 0 0 \
+                    7 3 20 10 20 0 4 0 5000 12345678 256 18446744073709551615";
+        assert_eq!(
+            parse_stat(stat),
+            Some(ProcStat {
+                ppid: 1200,
+                cpu_ticks: 40,
+                num_threads: 4,
+                rss_pages: 256,
+            })
+        );
+        assert_eq!(
+            parse_io("rchar: 10\nread_bytes: 4096\nwrite_bytes: 8192\n"),
+            (4096, 8192)
+        );
+    }
+
+    #[test]
+    fn samples_grandchildren() {
+        let mut wrapper = std::process::Command::new("sh")
+            .args(["-c", "sh -c 'sleep 5; true' & wait"])
+            .spawn()
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        let sample = sample_process_tree(wrapper.id()).unwrap();
+        wrapper.kill().unwrap();
+        wrapper.wait().unwrap();
+
+        // the wrapper, the inner shell and the sleep it runs
+        assert_eq!(sample.num_processes, 3, "{sample:?}");
+        assert!(sample.rss_bytes > 0);
+        assert!(sample.num_threads >= sample.num_processes);
+    }
+}
@@ -3,37 +3,47 @@ use std::time::Duration;
 use bollard::container::{MemoryStatsStats, Stats, StatsOptions};
 use bollard::Docker;
 use chrono::{DateTime, Utc};
-use futures::TryStreamExt;
-use libproc::{self, pid_rusage::PIDRUsage, processes};
-use tokio::{sync::mpsc::Sender, time::Instant};
+use futures::StreamExt;
+use tokio::{
+    sync::{mpsc::Sender, oneshot},
+    time::Instant,
+};
 
-use smelt_data::{command_event::CommandVariant, CommandProfile, Event};
+use smelt_data::{command_event::CommandVariant, CommandProfile, Event, ProfilePeaks};
 
 const NANOS_TO_MICROS: u64 = 1_000;
+const NANOS_TO_MILLIS: u64 = 1_000_000;
 #[derive(Debug)]
 struct SampleStruct {
     /// Memory used by a command in bytes
     ///
-    /// currently calculated by summing the memory used by the command process and all of its
-    /// children
+    /// calculated by summing the memory used by the command process and all of its descendants
+    /// -- on macos, only its direct children are counted
     memory_used: u64,
 
     /// cpu time used, in nanoseconds
     cpu_time_delta: u64,
+
+    io_read_bytes: u64,
+    io_write_bytes: u64,
+    num_threads: u32,
 }
 
 #[cfg(target_os = "linux")]
-fn get_rusage_and_add(pid: i32, timeused: &mut u64, memused: &mut u64) {
-    use libproc::pid_rusage::RUsageInfoV0;
-    if let Some(val) = libproc::pid_rusage::pidrusage::<RUsageInfoV0>(pid as i32).ok() {
-        *memused += val.memory_used();
-        *timeused += (val.ri_system_time + val.ri_user_time);
-    }
+fn sample_memory_and_load(pid: u32) -> Option<SampleStruct> {
+    let sample = super::proc_tree::sample_process_tree(pid)?;
+    Some(SampleStruct {
+        memory_used: sample.rss_bytes,
+        cpu_time_delta: sample.cpu_time_ns,
+        io_read_bytes: sample.io_read_bytes,
+        io_write_bytes: sample.io_write_bytes,
+        num_threads: sample.num_threads,
+    })
 }
 
 #[cfg(target_os = "macos")]
 fn get_rusage_and_add(pid: i32, timeused: &mut u64, memused: &mut u64) {
-    use libproc::pid_rusage::RUsageInfoV3;
+    use libproc::pid_rusage::{PIDRUsage, RUsageInfoV3};
     use mach2::mach_time::mach_timebase_info;
 
     let mut timebase = mach_timebase_info::default();
@@ -46,8 +56,11 @@ fn get_rusage_and_add(pid: i32, timeused: &mut u64, memused: &mut u64) {
     }
 }
 
+#[cfg(target_os = "macos")]
 fn sample_memory_and_load(ppid: u32) -> Option<SampleStruct> {
-    let filter = libproc::processes::ProcFilter::ByParentProcess { ppid };
+    use libproc::processes;
+
+    let filter = processes::ProcFilter::ByParentProcess { ppid };
     let mut timeused = 0;
     let mut memused = 0;
 
@@ -62,121 +75,185 @@ fn sample_memory_and_load(ppid: u32) -> Option<SampleStruct> {
     Some(SampleStruct {
         memory_used: memused,
         cpu_time_delta: timeused,
+        io_read_bytes: 0,
+        io_write_bytes: 0,
+        num_threads: 0,
     })
 }
 
-
-async fn docker_sample(
-    docker_client: &Docker,
-    command_ref: &String,
-) -> Option<Stats> {
-    // TODO This should return a stream of stats. AFAIK the way Docker stats
-    //  works is that the daemon constantly polls each container once per
-    //  second for statistics. The /stats/ API call, once it starts, waits for
-    //  the daemon to poll twice in order to gather data for two consecutive
-    //  ticks, and returns data from both; this is to allow the client to
-    //  compute rate-related information such as CPU load. This means that if
-    //  we set stream=False, our rate of fetching stats is limited to once
-    //  every _two_ seconds.
-    let stats = docker_client.stats(&command_ref, Some(StatsOptions {
-        stream: false,
-        ..Default::default()
-    })).try_collect::<Vec<_>>().await.unwrap();
-
-    for stat in stats {
-        return Some(stat);
-    }
-    None
-}
-
-
-fn docker_stats_to_event(
-    trace_id: &String,
-    command_ref: &String,
-    stats: &Stats,
-    profile_start_time_ms: u64,
-) -> Option<Event> {
-    let parsed = stats
+/// Converts a docker stats sample into a profile
+///
+/// Returns None for samples that can't be used -- the first sample of a stream has no previous
+/// cpu usage to compute the load from, and sometimes has everything zeroed out, with a date of
+/// 0001-01-01T00:00:00Z
+fn docker_profile(stats: &Stats, profile_start_time_ms: u64) -> Option<CommandProfile> {
+    let sample_timestamp_ms: u64 = stats
         .read
-        .parse::<DateTime<Utc>>();
-    // Sometimes the first Stats object returned by the library has everything zeroed out, with a
-    // date of 0001-01-01T00:00:00Z, which corresponds to a negative timestamp. We ignore these.
-    let sample_timestamp_ms: u64 = parsed
-        .expect("failed to parse datetime")
+        .parse::<DateTime<Utc>>()
+        .ok()?
         .timestamp_millis()
-        .try_into().ok()?;
-
-    docker_profile_event(
-        &trace_id,
-        &command_ref,
-        stats,
-        sample_timestamp_ms.saturating_sub(profile_start_time_ms),
-    )
-}
+        .try_into()
+        .ok()?;
+    let time_since_start_ms = sample_timestamp_ms.saturating_sub(profile_start_time_ms);
 
-
-pub async fn profile_cmd_docker(
-    tx: Sender<Event>,
-    docker_client: Docker,
-    command_ref: String,
-    trace_id: String,
-    profile_start_time_ms: u64,
-) {
-    loop {
-        // TODO This should instead use the streaming version of the stats endpoint
-        let new_sample = docker_sample(&docker_client, &command_ref).await;
-
-        if let Some(ref stats) = new_sample {
-            match docker_stats_to_event(&trace_id, &command_ref, &stats, profile_start_time_ms) {
-                Some(event) => {
-                    let _ = tx.send(event).await;
-                }
-                None => {}
-            }
-
-        }
-    }
-}
-
-fn docker_profile_event(
-    trace_id: &String,
-    command_ref: &String,
-    stats: &Stats,
-    time_since_start_ms: u64,
-) -> Option<Event> {
     // Calculations based on https://docs.docker.com/engine/api/v1.45/#tag/Container/operation/ContainerStats
-    let cpu_delta_us = stats.cpu_stats.cpu_usage.total_usage - stats.precpu_stats.cpu_usage.total_usage;
-    let system_cpu_delta_us = match (stats.cpu_stats.system_cpu_usage, stats.precpu_stats.system_cpu_usage) {
-        (None, _) => return None,
-        (_, None) => return None,
-        (Some(system_cpu_usage), Some(prev_system_cpu_usage)) => system_cpu_usage.saturating_sub(prev_system_cpu_usage)
-    };
+    let cpu_delta_us = stats
+        .cpu_stats
+        .cpu_usage
+        .total_usage
+        .saturating_sub(stats.precpu_stats.cpu_usage.total_usage);
+    let system_cpu_delta_us = stats
+        .cpu_stats
+        .system_cpu_usage?
+        .saturating_sub(stats.precpu_stats.system_cpu_usage?);
+    if system_cpu_delta_us == 0 {
+        return None;
+    }
 
-    let number_cpus = match (stats.cpu_stats.online_cpus, stats.cpu_stats.cpu_usage.percpu_usage.clone()) {
+    let percpu_usage = stats.cpu_stats.cpu_usage.percpu_usage.as_ref();
+    let number_cpus = match (stats.cpu_stats.online_cpus, percpu_usage) {
         (Some(cpus), _) => cpus,
-        (_, Some(percpu_usage)) => percpu_usage.len().try_into().unwrap(),
+        (_, Some(percpu_usage)) => percpu_usage.len() as u64,
         (_, _) => return None,
     };
     let cpu_load = cpu_delta_us as f32 / system_cpu_delta_us as f32 * number_cpus as f32;
 
     let memory_stats = stats.memory_stats;
-    let memory_used = match (memory_stats.usage, memory_stats.stats) {
-        (None, _) => return None,
-        (Some(usage), None) => usage,
+    let memory_used = match (memory_stats.usage?, memory_stats.stats) {
+        (usage, None) => usage,
         // From https://docs.docker.com/reference/cli/docker/container/stats/:
         // See On Docker 19.03 and older, the cache usage was defined as the
         // value of cache field. On cgroup v2 hosts, the cache usage is defined
         // as the value of inactive_file field.
-        (Some(usage), Some(MemoryStatsStats::V1(mem_stats_v1))) => usage - mem_stats_v1.cache,
-        (Some(usage), Some(MemoryStatsStats::V2(mem_stats_v2))) => usage - mem_stats_v2.inactive_file,
+        (usage, Some(MemoryStatsStats::V1(v1))) => usage.saturating_sub(v1.cache),
+        (usage, Some(MemoryStatsStats::V2(v2))) => usage.saturating_sub(v2.inactive_file),
+    };
+
+    // cgroup v1 reports "Read" and "Write", cgroup v2 "read" and "write"
+    let io_bytes = |op: &str| -> u64 {
+        stats
+            .blkio_stats
+            .io_service_bytes_recursive
+            .iter()
+            .flatten()
+            .filter(|entry| entry.op.eq_ignore_ascii_case(op))
+            .map(|entry| entry.value)
+            .sum()
     };
 
-    let variant = CommandVariant::Profile(CommandProfile {
+    Some(CommandProfile {
         memory_used,
         cpu_load,
         time_since_start_ms,
-    });
-    Some(Event::from_command_variant(command_ref.clone(), trace_id.clone(), variant))
+        cpu_time_ms: stats.cpu_stats.cpu_usage.total_usage / NANOS_TO_MILLIS,
+        io_read_bytes: io_bytes("read"),
+        io_write_bytes: io_bytes("write"),
+        // every task in the container counts towards pids, threads included
+        num_threads: stats.pids_stats.current.unwrap_or_default() as u32,
+    })
+}
+
+/// Resource usage of a container, as told by its stats
+#[derive(Debug, Default, PartialEq)]
+pub struct ContainerUsage {
+    pub user_cpu_ms: u64,
+    pub system_cpu_ms: u64,
+    pub peak_rss_bytes: u64,
+}
+
+impl ContainerUsage {
+    /// The cpu times of a container add up over its lifetime, and cgroup v1 reports the peak
+    /// memory as `max_usage` -- cgroup v2 does not, so the current usage is the best there is
+    fn from_stats(stats: &Stats) -> Self {
+        let cpu_usage = &stats.cpu_stats.cpu_usage;
+        let memory_stats = &stats.memory_stats;
+        Self {
+            user_cpu_ms: cpu_usage.usage_in_usermode / NANOS_TO_MILLIS,
+            system_cpu_ms: cpu_usage.usage_in_kernelmode / NANOS_TO_MILLIS,
+            peak_rss_bytes: memory_stats
+                .max_usage
+                .or(memory_stats.usage)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Reads the stats of the container `container_name` once -- all zeroes if they can't be read
+pub async fn container_usage(docker_client: &Docker, container_name: &str) -> ContainerUsage {
+    let mut stats = docker_client.stats(
+        container_name,
+        Some(StatsOptions {
+            stream: false,
+            one_shot: true,
+        }),
+    );
+    match stats.next().await {
+        Some(Ok(stats)) => ContainerUsage::from_stats(&stats),
+        Some(Err(err)) => {
+            tracing::debug!("Could not read the stats of {container_name}: {err}");
+            ContainerUsage::default()
+        }
+        None => ContainerUsage::default(),
+    }
+}
+
+/// Streams the stats of the container `container_name` until `stop` fires, or the container is
+/// gone
+///
+/// The docker daemon collects stats about once a second, so samples are sent at most every
+/// `sample_freq_ms`, and no faster than the daemon collects them. Returns the peaks of every
+/// sample that was sent
+pub async fn profile_cmd_docker(
+    tx: Sender<Event>,
+    docker_client: Docker,
+    container_name: String,
+    command_ref: String,
+    trace_id: String,
+    sample_freq_ms: u64,
+    mut stop: oneshot::Receiver<()>,
+) -> ProfilePeaks {
+    let profile_start_time_ms = Utc::now().timestamp_millis().max(0) as u64;
+    let mut stats = docker_client.stats(
+        &container_name,
+        Some(StatsOptions {
+            stream: true,
+            one_shot: false,
+        }),
+    );
+    let sample_freq = Duration::from_millis(sample_freq_ms);
+    let mut last_sent: Option<Instant> = None;
+    let mut peaks = ProfilePeaks::default();
+
+    loop {
+        let sample = tokio::select! {
+            sample = stats.next() => sample,
+            _ = &mut stop => break,
+        };
+        let stats = match sample {
+            Some(Ok(stats)) => stats,
+            Some(Err(err)) => {
+                tracing::debug!("Stopped profiling {command_ref}: {err}");
+                break;
+            }
+            None => break,
+        };
+        if last_sent.is_some_and(|sent| sent.elapsed() < sample_freq) {
+            continue;
+        }
+        let Some(profile) = docker_profile(&stats, profile_start_time_ms) else {
+            continue;
+        };
+        last_sent = Some(Instant::now());
+        peaks.add_sample(&profile);
+        let _ = tx
+            .send(Event::from_command_variant(
+                command_ref.clone(),
+                trace_id.clone(),
+                CommandVariant::Profile(profile),
+            ))
+            .await;
+    }
+    peaks
 }
 
 pub async fn profile_cmd(
@@ -185,10 +262,12 @@ pub async fn profile_cmd(
     sample_freq_ms: u64,
     command_ref: String,
     trace_id: String,
-) {
+    mut stop: oneshot::Receiver<()>,
+) -> ProfilePeaks {
     let start_sample_time = Instant::now();
     let mut prev_sample = None;
     let mut prev_sample_time = Instant::now();
+    let mut peaks = ProfilePeaks::default();
 
     loop {
         let new_sample = sample_memory_and_load(pid);
@@ -197,14 +276,13 @@ pub async fn profile_cmd(
             if let Some(ref _prev) = prev_sample {
                 let time_since_previous = (new_sample_time - prev_sample_time).as_micros() as u64;
                 let time_since_start = (new_sample_time - start_sample_time).as_millis() as u64;
+                let profile = profile_sample(sample, _prev, time_since_previous, time_since_start);
+                peaks.add_sample(&profile);
                 let _ = tx
-                    .send(profile_event(
-                        &trace_id,
-                        &command_ref,
-                        &sample,
-                        &_prev,
-                        time_since_previous,
-                        time_since_start,
+                    .send(Event::from_command_variant(
+                        command_ref.clone(),
+                        trace_id.clone(),
+                        CommandVariant::Profile(profile),
                     ))
                     .await;
             }
@@ -213,25 +291,111 @@ pub async fn profile_cmd(
         }
         prev_sample_time = new_sample_time;
 
-        tokio::time::sleep(Duration::from_millis(sample_freq_ms)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(sample_freq_ms)) => {}
+            _ = &mut stop => break,
+        }
     }
+    peaks
 }
 
-fn profile_event(
-    trace_id: &String,
-    command_ref: &String,
+fn profile_sample(
     sample: &SampleStruct,
     prev: &SampleStruct,
     time_since_previous_us: u64,
     time_since_start_ms: u64
-) -> Event {
-    let variant = CommandVariant::Profile(CommandProfile {
+) -> CommandProfile {
+    CommandProfile {
         memory_used: sample.memory_used,
         // Microseconds of CPU time / microseconds of wall time
         cpu_load: ((sample.cpu_time_delta.saturating_sub(prev.cpu_time_delta)) as f32
             / NANOS_TO_MICROS as f32) as f32
             / time_since_previous_us as f32,
         time_since_start_ms,
-    });
-    Event::from_command_variant(command_ref.clone(), trace_id.clone(), variant)
+        cpu_time_ms: sample.cpu_time_delta / NANOS_TO_MILLIS,
+        io_read_bytes: sample.io_read_bytes,
+        io_write_bytes: sample.io_write_bytes,
+        num_threads: sample.num_threads,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu_stats(total_usage: u64, system_cpu_usage: u64) -> serde_json::Value {
+        serde_json::json!({
+            "cpu_usage": {
+                "total_usage": total_usage,
+                "usage_in_usermode": 0,
+                "usage_in_kernelmode": 0,
+            },
+            "system_cpu_usage": system_cpu_usage,
+            "online_cpus": 4,
+            "throttling_data": {"periods": 0, "throttled_periods": 0, "throttled_time": 0},
+        })
+    }
+
+    fn stats(read: &str, total_usage: u64, system_cpu_usage: u64) -> Stats {
+        serde_json::from_value(serde_json::json!({
+            "read": read,
+            "preread": "2024-05-01T10:00:00Z",
+            "num_procs": 0,
+            "pids_stats": {"current": 5},
+            "memory_stats": {"usage": 2000},
+            "blkio_stats": {"io_service_bytes_recursive": [
+                {"major": 8, "minor": 0, "op": "Read", "value": 4096},
+                {"major": 8, "minor": 0, "op": "Write", "value": 512},
+                {"major": 8, "minor": 16, "op": "Read", "value": 4096},
+            ]},
+            "cpu_stats": cpu_stats(total_usage, system_cpu_usage),
+            "precpu_stats": cpu_stats(1_000_000_000, 10_000_000_000),
+            "storage_stats": {},
+            "name": "/test",
+            "id": "abc",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn converts_docker_stats() {
+        let start_ms = 1_714_557_600_000;
+        let profile = docker_profile(
+            &stats("2024-05-01T10:00:01.5Z", 3_000_000_000, 14_000_000_000),
+            start_ms,
+        )
+        .unwrap();
+        assert_eq!(profile.time_since_start_ms, 1_500);
+        assert_eq!(profile.memory_used, 2000);
+        assert_eq!(profile.cpu_load, 2.0);
+        assert_eq!(profile.cpu_time_ms, 3_000);
+        assert_eq!(profile.io_read_bytes, 8192);
+        assert_eq!(profile.io_write_bytes, 512);
+        assert_eq!(profile.num_threads, 5);
+
+        // the zeroed out first sample of a stream is skipped, rather than panicking
+        assert_eq!(
+            docker_profile(&stats("0001-01-01T00:00:00Z", 0, 0), start_ms),
+            None
+        );
+    }
+
+    #[test]
+    fn converts_container_usage() {
+        let mut stats = stats("2024-05-01T10:00:01Z", 3_000_000_000, 14_000_000_000);
+        stats.cpu_stats.cpu_usage.usage_in_usermode = 2_000_000_000;
+        stats.cpu_stats.cpu_usage.usage_in_kernelmode = 1_000_000_000;
+        assert_eq!(
+            ContainerUsage::from_stats(&stats),
+            ContainerUsage {
+                user_cpu_ms: 2_000,
+                system_cpu_ms: 1_000,
+                peak_rss_bytes: 2000,
+            }
+        );
+
+        // cgroup v1 knows the peak, rather than the current usage
+        stats.memory_stats.max_usage = Some(5000);
+        assert_eq!(ContainerUsage::from_stats(&stats).peak_rss_bytes, 5000);
+    }
 }
@@ -0,0 +1,251 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use dice::{DiceData, DiceDataBuilder};
+use serde::Serialize;
+
+use smelt_core::SmeltErr;
+
+use crate::commands::{Command, TargetType};
+
+/// Outcome of the latest run of a command
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandStatus {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+impl fmt::Display for CommandStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                CommandStatus::Passed => "passed",
+                CommandStatus::Failed => "failed",
+                CommandStatus::Skipped => "skipped",
+            }
+        )
+    }
+}
+
+/// The outcome of the latest run of every command
+///
+/// Statuses are recorded from the events of each invocation, and are never cleared -- a command
+/// that DICE did not have to execute again keeps the status of the run that it was cached from
+#[derive(Default)]
+pub struct CommandStatuses(Mutex<HashMap<String, CommandStatus>>);
+
+impl CommandStatuses {
+    pub fn record(&self, command_name: &str, status: CommandStatus) {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(command_name.to_string(), status);
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, CommandStatus> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+pub trait SetCommandStatuses {
+    fn set_command_statuses(&mut self);
+}
+
+pub trait GetCommandStatuses {
+    fn get_command_statuses(&self) -> Arc<CommandStatuses>;
+}
+
+impl SetCommandStatuses for DiceDataBuilder {
+    fn set_command_statuses(&mut self) {
+        self.set(Arc::new(CommandStatuses::default()))
+    }
+}
+
+impl GetCommandStatuses for DiceData {
+    fn get_command_statuses(&self) -> Arc<CommandStatuses> {
+        self.get::<Arc<CommandStatuses>>()
+            .expect("Command statuses should be set")
+            .clone()
+    }
+}
+
+/// How an edge between two commands was declared
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeKind {
+    /// Declared in `dependencies`
+    Command,
+    /// The dependency produces one of the `dependent_files`
+    File,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExportNode {
+    pub name: String,
+    pub target_type: TargetType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<CommandStatus>,
+}
+
+/// `from` depends on `to`
+#[derive(Serialize, Debug)]
+pub struct ExportEdge {
+    pub from: String,
+    pub to: String,
+    pub kind: EdgeKind,
+}
+
+/// A resolved command graph, in a shape that can be rendered by other tools
+#[derive(Serialize, Debug, Default)]
+pub struct GraphExport {
+    pub nodes: Vec<ExportNode>,
+    pub edges: Vec<ExportEdge>,
+    #[serde(skip)]
+    seen_edges: HashSet<(String, String)>,
+}
+
+impl GraphExport {
+    pub const fn dot_file() -> &'static str {
+        "graph.dot"
+    }
+
+    pub const fn json_file() -> &'static str {
+        "graph.json"
+    }
+
+    pub fn add_node(&mut self, command: &Command, status: Option<CommandStatus>) {
+        self.nodes.push(ExportNode {
+            name: command.name.clone(),
+            target_type: command.target_type.clone(),
+            status,
+        });
+    }
+
+    /// Records that `from` depends on `to` -- a pair of commands only gets one edge, even if the
+    /// dependency is declared more than once
+    pub fn add_edge(&mut self, from: &str, to: &str, kind: EdgeKind) {
+        if self.seen_edges.insert((from.to_string(), to.to_string())) {
+            self.edges.push(ExportEdge {
+                from: from.to_string(),
+                to: to.to_string(),
+                kind,
+            });
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, SmeltErr> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Renders the graph as Graphviz DOT
+    ///
+    /// Nodes are filled based on their target type, and outlined based on the status of their
+    /// latest run, if it is known
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        let _ = writeln!(dot, "digraph smelt {{");
+        let _ = writeln!(dot, "  rankdir=LR;");
+        let _ = writeln!(dot, "  node [shape=box, style=filled];");
+        for node in self.nodes.iter() {
+            let (border, penwidth) = match node.status {
+                Some(CommandStatus::Passed) => ("forestgreen", 3),
+                Some(CommandStatus::Failed) => ("red", 3),
+                Some(CommandStatus::Skipped) => ("gray50", 3),
+                None => ("black", 1),
+            };
+            let label = match node.status {
+                Some(status) => format!("{}\\n{}", escape_dot(&node.name), status),
+                None => escape_dot(&node.name),
+            };
+            let _ = writeln!(
+                dot,
+                "  \"{}\" [label=\"{}\", fillcolor=\"{}\", color=\"{}\", penwidth={}];",
+                escape_dot(&node.name),
+                label,
+                target_type_color(&node.target_type),
+                border,
+                penwidth,
+            );
+        }
+        for edge in self.edges.iter() {
+            let style = match edge.kind {
+                EdgeKind::Command => "solid",
+                EdgeKind::File => "dashed",
+            };
+            let _ = writeln!(
+                dot,
+                "  \"{}\" -> \"{}\" [style={}];",
+                escape_dot(&edge.from),
+                escape_dot(&edge.to),
+                style
+            );
+        }
+        let _ = writeln!(dot, "}}");
+        dot
+    }
+
+    /// Writes the DOT and JSON renderings of the graph to `output_dir`
+    ///
+    /// Returns the paths of the DOT and JSON files, in that order
+    pub async fn write_to(&self, output_dir: &Path) -> Result<(PathBuf, PathBuf), SmeltErr> {
+        tokio::fs::create_dir_all(output_dir).await?;
+        let dot_path = output_dir.join(Self::dot_file());
+        let json_path = output_dir.join(Self::json_file());
+        tokio::fs::write(&dot_path, self.to_dot()).await?;
+        tokio::fs::write(&json_path, self.to_json()?).await?;
+        Ok((dot_path, json_path))
+    }
+}
+
+fn target_type_color(target_type: &TargetType) -> &'static str {
+    match target_type {
+        TargetType::Test => "lightblue",
+        TargetType::Stimulus => "khaki",
+        TargetType::Build => "lightgray",
+        TargetType::Rerun => "orange",
+        TargetType::Rebuild => "plum",
+    }
+}
+
+fn escape_dot(val: &str) -> String {
+    val.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::test_command as command;
+
+    #[test]
+    fn export_dot_and_json() {
+        let mut export = GraphExport::default();
+        export.add_node(&command("build", TargetType::Build), None);
+        export.add_node(
+            &command("test", TargetType::Test),
+            Some(CommandStatus::Failed),
+        );
+        export.add_edge("test", "build", EdgeKind::File);
+        export.add_edge("test", "build", EdgeKind::Command);
+
+        assert_eq!(export.edges.len(), 1);
+
+        let dot = export.to_dot();
+        assert!(dot.contains("\"test\" -> \"build\" [style=dashed];"));
+        assert!(dot
+            .contains("\"test\" [label=\"test\\nfailed\", fillcolor=\"lightblue\", color=\"red\""));
+
+        let json: serde_json::Value = serde_json::from_str(&export.to_json().unwrap()).unwrap();
+        assert_eq!(json["nodes"][1]["status"], "failed");
+        assert_eq!(json["nodes"][0]["target_type"], "build");
+        assert!(json["nodes"][0].get("status").is_none());
+        assert_eq!(json["edges"][0]["kind"], "file");
+    }
+}
@@ -1,4 +1,9 @@
-use std::{collections::HashSet, str::FromStr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
 use allocative::Allocative;
 use async_trait::async_trait;
@@ -14,26 +19,39 @@ use futures::{
     StreamExt,
 };
 use futures::FutureExt;
-use tokio::sync::mpsc::{Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{channel, Sender, UnboundedReceiver, UnboundedSender};
 
 use smelt_core::CommandDefPath;
 use smelt_core::SmeltErr;
+use smelt_core::closest_matches;
 use smelt_data::{
     client_commands::{*, client_command::ClientCommands, client_resp::ClientResponses},
     executed_tests::ExecutedTestResult,
+    CommandPlanned, SmeltErrorType,
 };
 use smelt_events::{
     self,
     ClientCommandBundle,
-    Event, runtime_support::{
-        GetSmeltCfg, GetTraceId, GetTxChannel, SetSmeltCfg, SetTraceId, SetTxChannel,
+    Event, event_log::EventLogWriter, runtime_support::{
+        new_trace_id, GetCommandPeaks, GetSmeltCfg, GetSmeltRoot, GetTraceId,
+        GetTxChannel, SetCommandPeaks, SetDryRun, SetSmeltCfg, SetTraceId, SetTxChannel,
     },
 };
 
 use crate::{
     CommandDependency,
     commands::{Command, TargetType},
-    executor::{DockerExecutor, Executor, GetExecutor, LocalExecutor, SetExecutor},
+    executor::{
+        script_contents, DockerExecutor, Executor, GetExecutor, LocalExecutor, SetExecutor,
+    },
+    export::{EdgeKind, GetCommandStatuses, GraphExport, SetCommandStatuses},
+    history::{GetResultsHistory, ResultsHistory, SetResultsHistory},
+    invocation::{record_invocation, InvocationRecorder},
+    otlp::InvocationTracer,
+    query::DependencyGraph,
+    retry::rerun_failed,
+    selector::Selector,
+    shard::{select_shard, validate_shard},
     utils::invoke_start_message,
 };
 
@@ -61,10 +79,13 @@ impl LookupFileMaker {
     }
 }
 
+/// Suggestions are filled in by `CommandGraph::with_suggestions`, which knows about every
+/// command in the graph
 impl From<LookupCommand> for SmeltErr {
     fn from(lup: LookupCommand) -> SmeltErr {
         SmeltErr::MissingCommandDependency {
             missing_dep_name: lup.0.to_string(),
+            suggestions: vec![],
         }
     }
 }
@@ -73,6 +94,7 @@ impl From<LookupFileMaker> for SmeltErr {
     fn from(lup: LookupFileMaker) -> SmeltErr {
         SmeltErr::MissingFileDependency {
             missing_file_name: lup.0.to_string(),
+            suggestions: vec![],
         }
     }
 }
@@ -94,6 +116,31 @@ impl Key for LookupCommand {
     }
 }
 
+/// Whether commands are planned (a dry run) rather than executed
+///
+/// Every command depends on this key, so switching between dry runs and real runs computes every
+/// command again -- a dry run never reuses executed results, and a real run never reuses a plan
+#[derive(Clone, Dupe, PartialEq, Eq, Hash, Display, Debug, Allocative)]
+#[display(fmt = "RunMode")]
+struct RunMode;
+
+#[async_trait]
+impl Key for RunMode {
+    type Value = bool;
+
+    async fn compute(
+        &self,
+        _ctx: &mut DiceComputations,
+        _cancellations: &CancellationContext,
+    ) -> Self::Value {
+        false
+    }
+
+    fn equality(x: &Self::Value, y: &Self::Value) -> bool {
+        x == y
+    }
+}
+
 #[async_trait]
 impl Key for LookupFileMaker {
     type Value = Result<CommandRef, LookupFileMaker>;
@@ -128,6 +175,11 @@ impl Key for CommandRef {
             .chain(file_command_deps.into_iter())
             .collect::<Result<Vec<CommandRef>, SmeltErr>>()?;
 
+        let dry_run = ctx
+            .compute(&RunMode)
+            .await
+            .map_err(|err| Arc::new(SmeltErr::DiceFail(err)))?;
+
         let tx = ctx.per_transaction_data().get_tx_channel();
         let _ = tx
             .send(Event::command_scheduled(
@@ -187,6 +239,26 @@ impl Key for CommandRef {
             return Ok(need_to_skip);
         }
 
+        if dry_run {
+            let root = ctx.global_data().get_smelt_root();
+            let script = script_contents(&self.0, root.as_path(), self.0.working_dir.as_path())
+                .map_err(|err| Arc::new(SmeltErr::ExecutorFailed(err.to_string())))?;
+            let planned = CommandPlanned {
+                script: String::from_utf8_lossy(&script).into_owned(),
+                num_cpus: self.0.runtime.num_cpus,
+                max_memory_mb: self.0.runtime.max_memory_mb,
+                timeout: self.0.runtime.timeout,
+            };
+            let _ = tx
+                .send(Event::command_planned(
+                    self.0.name.clone(),
+                    ctx.per_transaction_data().get_trace_id(),
+                    planned,
+                ))
+                .await;
+            return Ok(Arc::new(ExecutedTestResult::Planned));
+        }
+
         let executor = ctx.global_data().get_executor();
 
         let output = executor
@@ -197,14 +269,44 @@ impl Key for CommandRef {
             )
             .await;
 
-        let output = output.map_err(|err| Arc::new(SmeltErr::ExecutorFailed(err.to_string())))?;
+        let mut output =
+            output.map_err(|err| Arc::new(SmeltErr::ExecutorFailed(err.to_string())))?;
+
+        let retries = ctx.global_data().get_smelt_cfg().retry_failed;
+        if output.failed() && retries > 0 && self.0.target_type == TargetType::Test {
+            output = rerun_failed(
+                executor,
+                self.0.clone(),
+                output,
+                retries,
+                ctx.per_transaction_data(),
+                ctx.global_data(),
+            )
+            .await
+            .map_err(|err| Arc::new(SmeltErr::ExecutorFailed(err.to_string())))?;
+        }
 
         let tr = output.clone().to_test_result();
 
+        let flaky_score = ctx
+            .global_data()
+            .get_results_history()
+            .flaky_score_with(&self.0.name, !output.failed())
+            .await
+            .unwrap_or_else(|err| {
+                tracing::warn!("Could not compute a flaky score: {err}");
+                0.0
+            });
         let command_finished = Event::command_finished(
             tr,
             self.0.target_type.to_string(),
+            flaky_score,
             ctx.per_transaction_data().get_trace_id(),
+        )
+        .with_peaks(
+            ctx.per_transaction_data()
+                .get_command_peaks()
+                .and_then(|peaks| peaks.take(&self.0.name)),
         );
         let mut _handleme = tx.send(command_finished).await;
         if output.failed() {
@@ -284,6 +386,7 @@ async fn get_command_deps(
 
 pub trait CommandSetter {
     fn add_command(&mut self, command: CommandRef) -> Result<(), SmeltErr>;
+    fn remove_command(&mut self, command: &CommandRef) -> Result<(), SmeltErr>;
     fn add_commands(
         &mut self,
         equations: impl IntoIterator<Item = CommandRef>,
@@ -351,6 +454,16 @@ impl CommandSetter for DiceTransactionUpdater {
         Ok(())
     }
 
+    fn remove_command(&mut self, command: &CommandRef) -> Result<(), SmeltErr> {
+        let lookup = LookupCommand::from_str_ref(&command.0.name);
+        for file in command.0.outputs.iter() {
+            let file_maker = LookupFileMaker::from_ref(file);
+            self.changed_to(vec![(file_maker.dupe(), Err(file_maker))])?;
+        }
+        self.changed_to(vec![(lookup.dupe(), Err(lookup))])?;
+        Ok(())
+    }
+
     fn add_commands(
         &mut self,
         commands: impl IntoIterator<Item = CommandRef>,
@@ -371,6 +484,9 @@ pub struct CommandGraph {
     pub(crate) all_commands: Vec<CommandRef>,
     /// The receiver for all ClientCommands -- these kick off executions of the dice graph
     rx_chan: UnboundedReceiver<ClientCommandBundle>,
+    /// Whether the most recent invocation was a dry run -- `RunMode` is only changed when an
+    /// invocation switches it
+    last_dry_run: Mutex<Option<bool>>,
 }
 
 impl CommandGraph {
@@ -390,8 +506,11 @@ impl CommandGraph {
         };
 
         let mut dice_builder = Dice::builder();
+        let history = ResultsHistory::open(Path::new(&cfg.smelt_root));
+        dice_builder.set_results_history(Arc::new(history));
         dice_builder.set_smelt_cfg(cfg);
         dice_builder.set_executor(executor);
+        dice_builder.set_command_statuses();
 
         let dice = dice_builder.build(DetectCycles::Enabled);
 
@@ -399,6 +518,7 @@ impl CommandGraph {
             dice,
             rx_chan,
             all_commands: vec![],
+            last_dry_run: Mutex::new(None),
         };
 
         tracing::trace!("Successfully made graph!");
@@ -417,19 +537,19 @@ impl CommandGraph {
                 event_streamer,
             }) = self.rx_chan.recv().await
             {
+                // every client command gets its own trace, so that its errors reach the right
+                // invocation even when several of them run at the same time
+                let trace_id = new_trace_id();
                 let rv = self
-                    .eat_command(command, event_streamer.clone())
+                    .eat_command(command, trace_id.clone(), event_streamer.clone())
                     .await
-                    .map_err(|err| err.to_string())
+                    .map_err(|err| err.to_proto(SmeltErrorType::InternalError))
                     .map(|val| ClientResp {
                         client_responses: val,
                     });
                 if let Err(ref err) = rv {
                     let _ = event_streamer
-                        .send(Event::runtime_error(
-                            err.clone(),
-                            "ADD_TRACE_ID_HERE".to_string(),
-                        ))
+                        .send(Event::error(err.clone(), trace_id))
                         .await;
                 }
                 let _ = oneshot_confirmer.send(rv);
@@ -440,21 +560,77 @@ impl CommandGraph {
     async fn eat_command(
         &mut self,
         command: ClientCommands,
+        trace_id: String,
         event_streamer: Sender<Event>,
     ) -> Result<Option<ClientResponses>, SmeltErr> {
         match command {
             ClientCommands::Setter(SetCommands { command_content }) => {
                 let script = serde_yaml::from_str(&command_content)?;
-                self.set_commands(script).await?;
+                let resp = self.set_commands(script).await?;
+                return Ok(Some(ClientResponses::Validation(resp)));
             }
-            ClientCommands::Runone(RunOne { command_name }) => {
-                self.run_one_test(command_name, event_streamer).await?;
+            ClientCommands::AddCommands(AddCommands { command_content }) => {
+                let commands = serde_yaml::from_str(&command_content)?;
+                let resp = self.add_commands(commands).await?;
+                return Ok(Some(ClientResponses::Validation(resp)));
             }
-            ClientCommands::Runtype(RunType { typeinfo }) => {
-                self.run_all_typed(typeinfo, event_streamer).await?;
+            ClientCommands::RemoveCommands(RemoveCommands { command_names }) => {
+                let resp = self.remove_commands(command_names).await?;
+                return Ok(Some(ClientResponses::Validation(resp)));
             }
-            ClientCommands::Runmany(RunMany { command_names }) => {
-                self.run_many_tests(command_names, event_streamer).await?;
+            ClientCommands::ReplaceCommand(ReplaceCommand { command_content }) => {
+                let command = serde_yaml::from_str(&command_content)?;
+                let resp = self.replace_command(command).await?;
+                return Ok(Some(ClientResponses::Validation(resp)));
+            }
+            ClientCommands::Runone(RunOne {
+                command_name,
+                dry_run,
+            }) => {
+                self.run_one_test(command_name, dry_run, trace_id, event_streamer)
+                    .await?;
+            }
+            ClientCommands::Runtype(RunType {
+                typeinfo,
+                shard,
+                dry_run,
+            }) => {
+                self.run_all_typed(
+                    typeinfo,
+                    shard.unwrap_or_default(),
+                    dry_run,
+                    trace_id,
+                    event_streamer,
+                )
+                .await?;
+            }
+            ClientCommands::Runmany(RunMany {
+                command_names,
+                shard,
+                dry_run,
+            }) => {
+                self.run_many_tests(
+                    command_names,
+                    shard.unwrap_or_default(),
+                    dry_run,
+                    trace_id,
+                    event_streamer,
+                )
+                .await?;
+            }
+            ClientCommands::Runselected(RunSelected {
+                selector,
+                shard,
+                dry_run,
+            }) => {
+                self.run_selected(
+                    selector,
+                    shard.unwrap_or_default(),
+                    dry_run,
+                    trace_id,
+                    event_streamer,
+                )
+                .await?;
             }
             ClientCommands::Getcfg(GetConfig {}) => {
                 let rv = self.dice.updater();
@@ -467,64 +643,279 @@ impl CommandGraph {
 
                 return Ok(Some(ClientResponses::CurrentCfg(val)));
             }
+            ClientCommands::Query(query) => {
+                let resp = self.query(query).await?;
+                return Ok(Some(ClientResponses::QueryResp(resp)));
+            }
+            ClientCommands::Export(export) => {
+                let resp = self.export_graph(export).await?;
+                return Ok(Some(ClientResponses::Exported(resp)));
+            }
+            ClientCommands::History(query) => {
+                let resp = self.command_history(query).await?;
+                return Ok(Some(ClientResponses::History(resp)));
+            }
         }
         Ok(None)
     }
 
-    pub async fn set_commands(&mut self, commands: Vec<Command>) -> Result<(), SmeltErr> {
+    /// Sets the graph, and validates it
+    ///
+    /// Every problem that is found is reported in the response, rather than stopping at the first
+    /// one. If there are duplicate names or outputs, the graph is not set at all
+    pub async fn set_commands(
+        &mut self,
+        commands: Vec<Command>,
+    ) -> Result<SetCommandsResp, SmeltErr> {
         let mut ctx = self.dice.updater();
-        #[tracing::instrument(name = "checking_names", level = "debug")]
-        fn check_unique_outputs_and_names(commands: &Vec<Command>) -> Result<(), SmeltErr> {
-            let mut outputfiles = HashSet::new();
-            let mut cmdnames = HashSet::new();
-            for command in commands.iter() {
-                if cmdnames.contains(&command.name) {
-                    return Err(SmeltErr::DuplicateCommandName {
-                        name: command.name.clone(),
-                    });
-                }
-                cmdnames.insert(&command.name);
-                for output in command.outputs.iter() {
-                    if outputfiles.contains(&output) {
-                        return Err(SmeltErr::DuplicateOutput {
-                            output: output.clone(),
-                        });
-                    }
-                    outputfiles.insert(output);
-                }
-            }
-            Ok(())
+        let errors = check_unique_outputs_and_names(&[], &commands);
+        if !errors.is_empty() {
+            return Ok(SetCommandsResp { errors });
         }
 
-        check_unique_outputs_and_names(&commands)?;
-
         let commands: Vec<CommandRef> = commands
             .into_iter()
             .map(|val| CommandRef(Arc::new(val)))
             .collect();
         ctx.add_commands(commands.iter().cloned())?;
         self.all_commands = commands;
-        let mut ctx = ctx.commit().await;
-        self.validate_graph(&mut ctx)
+        ctx.commit().await;
+        let errors = self.validate_graph().await;
+        if errors.is_empty() {
+            tracing::trace!("Successfully validated graph!");
+        }
+        Ok(SetCommandsResp { errors })
+    }
+
+    /// Adds commands to the graph that is already set
+    ///
+    /// Only the new commands, and the commands they depend on, are validated. If a new command
+    /// reuses a name or an output of the graph, nothing is added
+    pub async fn add_commands(
+        &mut self,
+        commands: Vec<Command>,
+    ) -> Result<SetCommandsResp, SmeltErr> {
+        let errors = check_unique_outputs_and_names(&self.all_commands, &commands);
+        if !errors.is_empty() {
+            return Ok(SetCommandsResp { errors });
+        }
+
+        let commands: Vec<CommandRef> = commands
+            .into_iter()
+            .map(|val| CommandRef(Arc::new(val)))
+            .collect();
+        let mut ctx = self.dice.updater();
+        ctx.add_commands(commands.iter().cloned())?;
+        self.all_commands.extend(commands.iter().cloned());
+        ctx.commit().await;
+        let errors = self.validate_commands(commands).await;
+        Ok(SetCommandsResp { errors })
+    }
+
+    /// Removes commands from the graph that is already set
+    ///
+    /// If a command that is kept depends on a removed command, or on one of its outputs, the
+    /// removal is refused and the graph is left as it was
+    pub async fn remove_commands(
+        &mut self,
+        command_names: Vec<String>,
+    ) -> Result<SetCommandsResp, SmeltErr> {
+        let command_names: HashSet<String> = command_names.into_iter().collect();
+        for command_name in command_names.iter() {
+            if !self
+                .all_commands
+                .iter()
+                .any(|val| &val.0.name == command_name)
+            {
+                return Err(self.with_suggestions(SmeltErr::MissingCommandDependency {
+                    missing_dep_name: command_name.clone(),
+                    suggestions: vec![],
+                }));
+            }
+        }
+
+        let (removed, kept): (Vec<CommandRef>, Vec<CommandRef>) = self
+            .all_commands
+            .iter()
+            .cloned()
+            .partition(|val| command_names.contains(&val.0.name));
+        let errors = check_no_dependents(&kept, &removed);
+        if !errors.is_empty() {
+            return Ok(SetCommandsResp { errors });
+        }
+
+        let mut ctx = self.dice.updater();
+        for command in removed.iter() {
+            ctx.remove_command(command)?;
+        }
+        self.all_commands = kept;
+        ctx.commit().await;
+        Ok(SetCommandsResp { errors: vec![] })
+    }
+
+    /// Replaces the command with the same name as `command`
+    ///
+    /// The new definition is validated, along with the commands that depended on an output the
+    /// new definition no longer has. Replacing a command with an identical one changes nothing
+    pub async fn replace_command(&mut self, command: Command) -> Result<SetCommandsResp, SmeltErr> {
+        let Some(position) = self
+            .all_commands
+            .iter()
+            .position(|val| val.0.name == command.name)
+        else {
+            return Err(self.with_suggestions(SmeltErr::MissingCommandDependency {
+                missing_dep_name: command.name,
+                suggestions: vec![],
+            }));
+        };
+        let previous = self.all_commands[position].dupe();
+        if previous.0.as_ref() == &command {
+            return Ok(SetCommandsResp { errors: vec![] });
+        }
+
+        let others: Vec<CommandRef> = self
+            .all_commands
+            .iter()
+            .filter(|val| val.0.name != command.name)
+            .cloned()
+            .collect();
+        let errors = check_unique_outputs_and_names(&others, std::slice::from_ref(&command));
+        if !errors.is_empty() {
+            return Ok(SetCommandsResp { errors });
+        }
+
+        let dropped_outputs: HashSet<&CommandDefPath> = previous
+            .0
+            .outputs
+            .iter()
+            .filter(|output| !command.outputs.contains(output))
+            .collect();
+        let command = CommandRef(Arc::new(command));
+        let mut ctx = self.dice.updater();
+        for output in dropped_outputs.iter() {
+            let file_maker = LookupFileMaker::from_ref(output);
+            ctx.changed_to(vec![(file_maker.dupe(), Err(file_maker))])?;
+        }
+        ctx.add_command(command.dupe())?;
+        self.all_commands[position] = command.dupe();
+        ctx.commit().await;
+
+        let mut affected = vec![command];
+        affected.extend(self.dependents_of(&HashSet::new(), &dropped_outputs));
+        let errors = self.validate_commands(affected).await;
+        Ok(SetCommandsResp { errors })
+    }
+
+    /// Commands of the graph that depend on one of `command_names`, or on one of `outputs`
+    fn dependents_of(
+        &self,
+        command_names: &HashSet<String>,
+        outputs: &HashSet<&CommandDefPath>,
+    ) -> Vec<CommandRef> {
+        self.all_commands
+            .iter()
+            .filter(|val| {
+                val.0
+                    .dependencies
+                    .iter()
+                    .any(|dep| command_names.contains(dep.get_command_name()))
+                    || val.0.dependent_files.iter().any(|file| outputs.contains(file))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Sets up the recorder for the `Invocation` record, reports and results history of a run
+    async fn invocation_recorder(&self) -> (InvocationRecorder, PathBuf) {
+        let state = self.dice.updater().existing_state().await;
+        let global_data = state.global_data();
+        let def_digests = self
+            .all_commands
+            .iter()
+            .map(|val| {
+                (
+                    val.0.name.clone(),
+                    hex::encode(val.0.def_digest().get_payload()),
+                )
+            })
+            .collect();
+        let recorder = InvocationRecorder::new(global_data.get_smelt_cfg().reports().collect())
+            .with_history(global_data.get_results_history(), def_digests)
+            .with_statuses(global_data.get_command_statuses());
+        (recorder, global_data.get_smelt_root())
+    }
+
+    /// Opens the event log of the invocation `trace_id`, if events are recorded
+    ///
+    /// A log that can't be created doesn't stop the run, it is just not recorded
+    async fn event_log(&self, smelt_root: &Path, trace_id: &str) -> Option<EventLogWriter> {
+        let state = self.dice.updater().existing_state().await;
+        if !state.global_data().get_smelt_cfg().record_events {
+            return None;
+        }
+        EventLogWriter::create(smelt_root, trace_id)
             .await
-            .map_err(|vals| SmeltErr::CommandSettingFailed {
-                reason: format!("{} invalid dependencies found", vals.len()),
-            })?;
-        tracing::trace!("Successfully validated graph!");
-        Ok(())
+            .map_err(|err| tracing::error!("Could not create the event log of {trace_id}: {err}"))
+            .ok()
+    }
+
+    /// Builds the tracer of the invocation `trace_id`, if runs are exported to a collector
+    async fn tracer(&self, trace_id: &str) -> Option<InvocationTracer> {
+        let state = self.dice.updater().existing_state().await;
+        let cfg = state.global_data().get_smelt_cfg();
+        let otlp = cfg.otlp.as_ref().filter(|otlp| !otlp.endpoint.is_empty())?;
+        let runtimes = self
+            .all_commands
+            .iter()
+            .map(|val| (val.0.name.clone(), val.0.runtime.clone()))
+            .collect();
+        Some(InvocationTracer::new(otlp, trace_id, runtimes))
     }
 
-    async fn start_tx(&self, tx: Sender<Event>) -> Result<DiceTransaction, SmeltErr> {
-        let ctx = self.dice.updater();
+    /// Starts the invocation `trace_id`
+    ///
+    /// Nothing that can fail should happen after an invocation is started, as the invocation is
+    /// only finished by `run_tests`
+    async fn start_tx(
+        &self,
+        tx: Sender<Event>,
+        trace_id: String,
+        shard: &Shard,
+        dry_run: bool,
+    ) -> Result<DiceTransaction, SmeltErr> {
+        let mut ctx = self.dice.updater();
+        {
+            let mut last_dry_run = self.last_dry_run.lock().unwrap();
+            if *last_dry_run != Some(dry_run) {
+                ctx.changed_to(vec![(RunMode, dry_run)])?;
+            }
+            *last_dry_run = Some(dry_run);
+        }
         let mut data = UserComputationData::new();
 
-        data.init_trace_id();
-        data.set_tx_channel(tx);
+        // every event of the run passes through the recorder on the way to the client
+        let (recorder, smelt_root) = self.invocation_recorder().await;
+        let event_log = self.event_log(&smelt_root, &trace_id).await;
+        let tracer = self.tracer(&trace_id).await;
+        let (recorder_tx, recorder_rx) = channel(100);
+        tokio::spawn(record_invocation(
+            recorder_rx,
+            tx,
+            smelt_root,
+            recorder,
+            event_log,
+            tracer,
+        ));
+
+        data.set_trace_id(trace_id.clone());
+        data.set_tx_channel(recorder_tx);
+        data.set_dry_run(dry_run);
+        data.set_command_peaks();
         let tx = ctx.commit_with_data(data).await;
         let val = tx.per_transaction_data().get_tx_channel();
         // todo -- handle err
         let _ = val
-            .send(invoke_start_message(tx.per_transaction_data(), tx.global_data()).await)
+            .send(invoke_start_message(tx.per_transaction_data(), tx.global_data(), shard).await)
             .await;
 
         Ok(tx)
@@ -533,17 +924,23 @@ impl CommandGraph {
     pub async fn run_all_typed(
         &self,
         maybe_type: String,
+        shard: Shard,
+        dry_run: bool,
+        trace_id: String,
         event_streamer: Sender<Event>,
     ) -> Result<(), SmeltErr> {
         let tt = TargetType::from_str(maybe_type.as_str())?;
-        let tx = self.start_tx(event_streamer).await?;
         let refs = self
             .all_commands
             .iter()
             .filter(|&val| val.0.target_type == tt)
             .cloned()
             .collect();
+        let refs = select_shard(refs, |val| val.0.name.as_str(), &shard)?;
 
+        let tx = self
+            .start_tx(event_streamer, trace_id, &shard, dry_run)
+            .await?;
         self.run_tests(refs, tx).await
     }
 
@@ -561,35 +958,104 @@ impl CommandGraph {
         });
         Ok(())
     }
+
+    /// Looks up commands by name, without starting an invocation
+    async fn lookup_commands(&self, names: Vec<String>) -> Result<Vec<CommandRef>, SmeltErr> {
+        let mut state = self.dice.updater().existing_state().await;
+        let mut refs = Vec::new();
+        for name in names {
+            let val = state
+                .compute(&LookupCommand(Arc::new(name)))
+                .await?
+                .map_err(|lup| self.with_suggestions(lup.into()))?;
+            refs.push(val);
+        }
+        Ok(refs)
+    }
+
     pub async fn run_many_tests(
         &self,
         test_names: Vec<String>,
+        shard: Shard,
+        dry_run: bool,
+        trace_id: String,
         event_streamer: Sender<Event>,
     ) -> Result<(), SmeltErr> {
-        let mut tx = self.start_tx(event_streamer).await?;
-        let mut refs = Vec::new();
+        validate_shard(&shard)?;
+        let refs = self.lookup_commands(test_names).await?;
+        let refs = select_shard(refs, |val| val.0.name.as_str(), &shard)?;
 
-        for test_name in test_names {
-            let val = tx.compute(&LookupCommand(Arc::new(test_name))).await??;
-            refs.push(val);
+        let tx = self
+            .start_tx(event_streamer, trace_id, &shard, dry_run)
+            .await?;
+        self.run_tests(refs, tx).await
+    }
+
+    /// Runs every command matched by `selector`, or the part of them that belongs to `shard`
+    ///
+    /// The selector is checked before an invocation is started, so a malformed or empty selection
+    /// is reported without executing anything
+    pub async fn run_selected(
+        &self,
+        selector: String,
+        shard: Shard,
+        dry_run: bool,
+        trace_id: String,
+        event_streamer: Sender<Event>,
+    ) -> Result<(), SmeltErr> {
+        let parsed = Selector::from_str(&selector)?;
+        let refs: Vec<CommandRef> = self
+            .all_commands
+            .iter()
+            .filter(|val| parsed.matches(&val.0))
+            .cloned()
+            .collect();
+        if refs.is_empty() {
+            return Err(SmeltErr::EmptySelection { selector });
         }
+        let refs = select_shard(refs, |val| val.0.name.as_str(), &shard)?;
+
+        let tx = self
+            .start_tx(event_streamer, trace_id, &shard, dry_run)
+            .await?;
         self.run_tests(refs, tx).await
     }
 
     pub async fn run_one_test(
         &self,
         test_name: impl Into<String>,
+        dry_run: bool,
+        trace_id: String,
         event_streamer: Sender<Event>,
     ) -> Result<(), SmeltErr> {
-        let mut tx = self.start_tx(event_streamer).await?;
-        let command = tx
-            .compute(&LookupCommand(Arc::new(test_name.into())))
-            .await??;
-        self.run_tests(vec![command], tx).await
+        let refs = self.lookup_commands(vec![test_name.into()]).await?;
+        let tx = self
+            .start_tx(event_streamer, trace_id, &Shard::default(), dry_run)
+            .await?;
+        self.run_tests(refs, tx).await
+    }
+
+    /// Looks up the command and file dependencies of every command in the graph, in the same
+    /// order as `all_commands`
+    async fn resolve_all_deps(
+        &self,
+    ) -> Vec<(
+        Vec<Result<CommandRef, SmeltErr>>,
+        Vec<Result<CommandRef, SmeltErr>>,
+    )> {
+        self.resolve_deps(&self.all_commands).await
     }
 
-    async fn validate_graph(&self, tx: &mut DiceTransaction) -> Result<(), Vec<SmeltErr>> {
-        let futs = tx.compute_many(self.all_commands.iter().map(|val| {
+    /// Looks up the command and file dependencies of `commands`, in the same order
+    async fn resolve_deps(
+        &self,
+        commands: &[CommandRef],
+    ) -> Vec<(
+        Vec<Result<CommandRef, SmeltErr>>,
+        Vec<Result<CommandRef, SmeltErr>>,
+    )> {
+        let mut tx = self.dice.updater().existing_state().await;
+        let futs = tx.compute_many(commands.iter().map(|val| {
             DiceComputations::declare_closure(move |ctx: &mut DiceComputations| {
                 get_command_deps(
                     ctx,
@@ -599,27 +1065,345 @@ impl CommandGraph {
                 .boxed()
             })
         }));
-        let mut err_vec = vec![];
-        let all_deps: Vec<_> = future::join_all(futs).await.into_iter().collect();
-        for (command_deps, file_deps) in all_deps {
+        future::join_all(futs).await
+    }
+
+    /// Resolves the dependencies of every command in the graph
+    ///
+    /// File dependencies are resolved through `LookupFileMaker`, so the producer of a file is
+    /// treated like any other dependency
+    pub async fn dependency_graph(&self) -> Result<DependencyGraph, SmeltErr> {
+        let all_deps = self.resolve_all_deps().await;
+
+        let mut graph =
+            DependencyGraph::new(self.all_commands.iter().map(|val| val.0.name.clone()));
+        for (command, (command_deps, file_deps)) in self.all_commands.iter().zip(all_deps) {
             for dep in command_deps.into_iter().chain(file_deps) {
-                if let Err(err) = dep {
-                    err_vec.push(err)
+                graph.add_edge(&command.0.name, &dep?.0.name);
+            }
+        }
+        Ok(graph)
+    }
+
+    pub async fn query(&self, query: Query) -> Result<QueryResp, SmeltErr> {
+        let graph = self.dependency_graph().await?;
+        let command_names = match query.query_type() {
+            QueryType::Deps => graph.deps(&query.command_names)?,
+            QueryType::Rdeps => graph.rdeps(&query.command_names)?,
+            QueryType::TopoOrder => graph.topo_order(&query.command_names)?,
+            QueryType::Path => match query.command_names.as_slice() {
+                [from] => graph.path(from, &query.destination)?,
+                _ => {
+                    return Err(SmeltErr::InvalidQuery {
+                        reason: format!(
+                            "path queries take exactly one command, {} were provided",
+                            query.command_names.len()
+                        ),
+                    })
                 }
+            },
+        };
+        Ok(QueryResp { command_names })
+    }
+
+    /// Writes the resolved graph to disk as Graphviz DOT and as a JSON node/edge list
+    pub async fn export_graph(&self, export: ExportGraph) -> Result<ExportGraphResp, SmeltErr> {
+        let all_deps = self.resolve_all_deps().await;
+        let state = self.dice.updater().existing_state().await;
+        let statuses = if export.include_status {
+            state.global_data().get_command_statuses().snapshot()
+        } else {
+            HashMap::new()
+        };
+
+        let mut graph = GraphExport::default();
+        for (command, (command_deps, file_deps)) in self.all_commands.iter().zip(all_deps) {
+            graph.add_node(&command.0, statuses.get(&command.0.name).copied());
+            for dep in command_deps {
+                graph.add_edge(&command.0.name, &dep?.0.name, EdgeKind::Command);
+            }
+            for dep in file_deps {
+                graph.add_edge(&command.0.name, &dep?.0.name, EdgeKind::File);
             }
         }
 
-        if !err_vec.is_empty() {
-            for err in err_vec.iter() {
-                let sterr = err.to_string();
-                tracing::info!("found err while validating graph: {sterr}");
+        let output_dir = if export.output_dir.is_empty() {
+            state.global_data().get_smelt_root().join("smelt-out")
+        } else {
+            PathBuf::from(export.output_dir)
+        };
+        let (dot_path, json_path) = graph.write_to(&output_dir).await?;
+        Ok(ExportGraphResp {
+            dot_path: dot_path.to_string_lossy().to_string(),
+            json_path: json_path.to_string_lossy().to_string(),
+        })
+    }
+
+    /// Finds every missing dependency in the graph, and every dependency cycle
+    ///
+    /// Cycles are looked for up front, so they are reported here instead of when the commands
+    /// on them are executed
+    async fn validate_graph(&self) -> Vec<ValidationError> {
+        self.validate_commands(self.all_commands.clone()).await
+    }
+
+    /// Finds every missing dependency of `roots`, and every dependency cycle they can reach
+    ///
+    /// Only `roots` and the commands they transitively depend on are resolved, so an edit to a
+    /// few commands of a large graph does not look at the rest of it
+    async fn validate_commands(&self, roots: Vec<CommandRef>) -> Vec<ValidationError> {
+        let mut errors = vec![];
+        let mut seen: HashSet<String> = roots.iter().map(|val| val.0.name.clone()).collect();
+        let mut names: Vec<String> = roots.iter().map(|val| val.0.name.clone()).collect();
+        let mut edges = vec![];
+        let mut frontier = roots;
+        // missing dependencies are only reported for the roots -- the rest of the graph was
+        // validated when it was set
+        let mut report_missing = true;
+        while !frontier.is_empty() {
+            let all_deps = self.resolve_deps(&frontier).await;
+            let mut next = vec![];
+            for (command, (command_deps, file_deps)) in frontier.iter().zip(all_deps) {
+                let command = &command.0;
+                let mut visit = |dep: CommandRef| {
+                    edges.push((command.name.clone(), dep.0.name.clone()));
+                    if seen.insert(dep.0.name.clone()) {
+                        names.push(dep.0.name.clone());
+                        next.push(dep);
+                    }
+                };
+                for (dep, resolved) in command.dependencies.iter().zip(command_deps) {
+                    match resolved {
+                        Ok(dep) => visit(dep),
+                        Err(err) if report_missing => errors.push(validation_error(
+                            ValidationErrorKind::MissingCommandDependency,
+                            &command.name,
+                            "dependencies",
+                            dep.get_command_name().to_string(),
+                            self.with_suggestions(err),
+                        )),
+                        Err(_) => {}
+                    }
+                }
+                for (file, resolved) in command.dependent_files.iter().zip(file_deps) {
+                    match resolved {
+                        Ok(dep) => visit(dep),
+                        Err(err) if report_missing => errors.push(validation_error(
+                            ValidationErrorKind::MissingFileDependency,
+                            &command.name,
+                            "dependent_files",
+                            file.to_string(),
+                            self.with_suggestions(err),
+                        )),
+                        Err(_) => {}
+                    }
+                }
             }
+            frontier = next;
+            report_missing = false;
+        }
+
+        let mut graph = DependencyGraph::new(names);
+        for (command, dep) in edges.iter() {
+            graph.add_edge(command, dep);
+        }
+        for cycle in graph.cycles() {
+            let mut error = validation_error(
+                ValidationErrorKind::DependencyCycle,
+                &cycle[0],
+                "dependencies",
+                cycle[1].clone(),
+                SmeltErr::DependencyCycle {
+                    cycle: cycle.clone(),
+                },
+            );
+            error.cycle = cycle;
+            errors.push(error);
+        }
 
-            Err(err_vec)
+        for error in errors.iter() {
+            tracing::info!("found err while validating graph: {}", error.message);
+        }
+        errors
+    }
+
+    /// Pass rate, last passing commit, flip count and flaky score of each queried command
+    pub async fn command_history(&self, query: HistoryQuery) -> Result<HistoryResp, SmeltErr> {
+        let known: HashSet<&str> = self
+            .all_commands
+            .iter()
+            .map(|val| val.0.name.as_str())
+            .collect();
+        let command_names = if query.command_names.is_empty() {
+            self.all_commands
+                .iter()
+                .map(|val| val.0.name.clone())
+                .collect()
         } else {
-            Ok(())
+            query.command_names
+        };
+
+        let history = self
+            .dice
+            .updater()
+            .existing_state()
+            .await
+            .global_data()
+            .get_results_history();
+        let mut rv = Vec::with_capacity(command_names.len());
+        for command_name in command_names {
+            if !known.contains(command_name.as_str()) {
+                return Err(self.with_suggestions(SmeltErr::MissingCommandDependency {
+                    missing_dep_name: command_name,
+                    suggestions: vec![],
+                }));
+            }
+            let stats = history.stats(&command_name).await?;
+            rv.push(CommandHistory {
+                command_name,
+                runs: stats.runs,
+                passes: stats.passes,
+                pass_rate: stats.pass_rate,
+                last_passing_commit: stats.last_passing_commit.unwrap_or_default(),
+                flip_count: stats.flip_count,
+                flaky_score: stats.flaky_score,
+            });
+        }
+        Ok(HistoryResp { history: rv })
+    }
+
+    /// Attaches the closest command names, or output paths, to an error about a missing command
+    /// or file
+    fn with_suggestions(&self, err: SmeltErr) -> SmeltErr {
+        match err {
+            SmeltErr::MissingCommandDependency {
+                missing_dep_name, ..
+            } => {
+                let suggestions = closest_matches(
+                    &missing_dep_name,
+                    self.all_commands.iter().map(|val| val.0.name.as_str()),
+                );
+                SmeltErr::MissingCommandDependency {
+                    missing_dep_name,
+                    suggestions,
+                }
+            }
+            SmeltErr::MissingFileDependency {
+                missing_file_name, ..
+            } => {
+                let outputs = self
+                    .all_commands
+                    .iter()
+                    .flat_map(|val| val.0.outputs.iter().map(|output| output.to_string()));
+                let suggestions = closest_matches(&missing_file_name, outputs);
+                SmeltErr::MissingFileDependency {
+                    missing_file_name,
+                    suggestions,
+                }
+            }
+            err => err,
+        }
+    }
+}
+
+/// Reports every command of `commands` whose name or outputs are already taken, either by
+/// `existing` or by an earlier command of `commands`
+#[tracing::instrument(name = "checking_names", level = "debug", skip_all)]
+fn check_unique_outputs_and_names(
+    existing: &[CommandRef],
+    commands: &[Command],
+) -> Vec<ValidationError> {
+    let mut outputfiles: HashSet<&CommandDefPath> =
+        existing.iter().flat_map(|val| val.0.outputs.iter()).collect();
+    let mut cmdnames: HashSet<&String> = existing.iter().map(|val| &val.0.name).collect();
+    let mut errors = vec![];
+    for command in commands.iter() {
+        if !cmdnames.insert(&command.name) {
+            errors.push(validation_error(
+                ValidationErrorKind::DuplicateCommandName,
+                &command.name,
+                "name",
+                command.name.clone(),
+                SmeltErr::DuplicateCommandName {
+                    name: command.name.clone(),
+                },
+            ));
+        }
+        for output in command.outputs.iter() {
+            if !outputfiles.insert(output) {
+                errors.push(validation_error(
+                    ValidationErrorKind::DuplicateOutput,
+                    &command.name,
+                    "outputs",
+                    output.to_string(),
+                    SmeltErr::DuplicateOutput {
+                        output: output.clone(),
+                    },
+                ));
+            }
         }
     }
+    errors
+}
+
+/// Reports every dependency that a command of `kept` has on a command of `removed`, or on one of
+/// its outputs
+fn check_no_dependents(kept: &[CommandRef], removed: &[CommandRef]) -> Vec<ValidationError> {
+    let removed_names: HashSet<&str> = removed.iter().map(|val| val.0.name.as_str()).collect();
+    let removed_outputs: HashSet<&CommandDefPath> = removed
+        .iter()
+        .flat_map(|val| val.0.outputs.iter())
+        .collect();
+    let mut errors = vec![];
+    for command in kept.iter() {
+        for dep in command.0.dependencies.iter() {
+            if removed_names.contains(dep.get_command_name()) {
+                errors.push(validation_error(
+                    ValidationErrorKind::MissingCommandDependency,
+                    &command.0.name,
+                    "dependencies",
+                    dep.get_command_name().to_string(),
+                    SmeltErr::MissingCommandDependency {
+                        missing_dep_name: dep.get_command_name().to_string(),
+                        suggestions: vec![],
+                    },
+                ));
+            }
+        }
+        for file in command.0.dependent_files.iter() {
+            if removed_outputs.contains(file) {
+                errors.push(validation_error(
+                    ValidationErrorKind::MissingFileDependency,
+                    &command.0.name,
+                    "dependent_files",
+                    file.to_string(),
+                    SmeltErr::MissingFileDependency {
+                        missing_file_name: file.to_string(),
+                        suggestions: vec![],
+                    },
+                ));
+            }
+        }
+    }
+    errors
+}
+
+fn validation_error(
+    kind: ValidationErrorKind,
+    command_name: &str,
+    field: &str,
+    reference: String,
+    err: SmeltErr,
+) -> ValidationError {
+    ValidationError {
+        kind: kind.into(),
+        command_name: command_name.to_string(),
+        field: field.to_string(),
+        reference,
+        cycle: vec![],
+        suggestions: err.suggestions().to_vec(),
+        message: format!("{command_name}: {err}"),
+    }
 }
 
 /// Handling logic for each command that is executed
@@ -635,7 +1419,10 @@ async fn handle_result(
     for res in compute_result {
         if let Err(ref rt_err) = res {
             let _ = tx
-                .send(Event::runtime_error(rt_err.to_string(), trace.clone()))
+                .send(Event::error(
+                    rt_err.to_proto(SmeltErrorType::InternalError),
+                    trace.clone(),
+                ))
                 .await;
         }
     }
@@ -686,6 +1473,7 @@ mod tests {
     };
 
     use super::*;
+    use crate::commands::test_command;
 
     struct TestGraphHandle {
         rx_chan: Receiver<Event>,
@@ -721,6 +1509,10 @@ mod tests {
             test_only: false,
             silent: true,
             job_slots: 1,
+            reports: vec![],
+            retry_failed: 0,
+            record_events: false,
+            otlp: None,
             init_executor: Some(configure_smelt::InitExecutor::Local(CfgLocal {})),
         }
     }
@@ -744,7 +1536,13 @@ mod tests {
         let graph = CommandGraph::new(rx, testing_cfg(yaml_path)).await.unwrap();
         let mut gh = TestGraphHandle { rx_chan: rx_handle };
         graph
-            .run_all_typed("test".to_string(), tx.clone())
+            .run_all_typed(
+                "test".to_string(),
+                Shard::default(),
+                false,
+                new_trace_id(),
+                tx.clone(),
+            )
             .await
             .unwrap();
         let events = gh.async_blocking_events().await;
@@ -775,4 +1573,51 @@ mod tests {
         let yaml_path = "test_data/command_lists/cl3.yaml";
         execute_all_tests_in_file(yaml_path).await
     }
+
+    #[test]
+    fn edits_checked_against_existing_commands() {
+        let command = |name: &str, output: &str| -> Command {
+            test_command(name, TargetType::Test).with_outputs(&[output])
+        };
+        let existing = vec![CommandRef(Arc::new(command("build", "build.out")))];
+
+        let errors = check_unique_outputs_and_names(
+            &existing,
+            &[command("test", "test.out"), command("test2", "build.out")],
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind(), ValidationErrorKind::DuplicateOutput);
+        assert_eq!(errors[0].command_name, "test2");
+
+        let errors = check_unique_outputs_and_names(&existing, &[command("build", "other.out")]);
+        assert_eq!(errors[0].kind(), ValidationErrorKind::DuplicateCommandName);
+    }
+
+    #[test]
+    fn removals_checked_against_dependents() {
+        let mut uses_output = test_command("uses_output", TargetType::Test);
+        uses_output.dependent_files = vec![CommandDefPath::new("build.out".to_string())];
+        let build = CommandRef(Arc::new(
+            test_command("build", TargetType::Build).with_outputs(&["build.out"]),
+        ));
+        let kept = vec![
+            CommandRef(Arc::new(
+                test_command("test", TargetType::Test).with_deps(&["build"]),
+            )),
+            CommandRef(Arc::new(uses_output)),
+            CommandRef(Arc::new(test_command("other", TargetType::Test))),
+        ];
+
+        let errors = check_no_dependents(&kept, &[build]);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].command_name, "test");
+        assert_eq!(
+            errors[0].kind(),
+            ValidationErrorKind::MissingCommandDependency
+        );
+        assert_eq!(errors[1].command_name, "uses_output");
+        assert_eq!(errors[1].kind(), ValidationErrorKind::MissingFileDependency);
+
+        assert!(check_no_dependents(&kept[2..], &kept[..2]).is_empty());
+    }
 }
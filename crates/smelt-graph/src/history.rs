@@ -0,0 +1,332 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use dice::{DiceData, DiceDataBuilder};
+use rusqlite::{params, Connection};
+
+use smelt_core::SmeltErr;
+
+/// Number of most recent runs of a command that its flaky score is computed over
+const FLAKY_WINDOW: usize = 20;
+
+/// Outcome of a single command in a single invocation
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub invoke_id: String,
+    pub command_name: String,
+    /// One of "passed", "failed" or "skipped"
+    pub outcome: String,
+    pub exit_code: i32,
+    pub duration_secs: f64,
+    pub git_hash: String,
+    /// Hex digest of the command definition, so that changes to a command can be told apart
+    /// from flakiness
+    pub def_digest: String,
+}
+
+/// What the history knows about a single command
+///
+/// Skipped runs are recorded, but do not count towards any of these statistics
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CommandStats {
+    pub runs: u64,
+    pub passes: u64,
+    pub pass_rate: f64,
+    pub last_passing_commit: Option<String>,
+    /// Number of times the command went from passing to failing, or back
+    pub flip_count: u64,
+    pub flaky_score: f64,
+}
+
+/// Fraction of consecutive runs that had a different outcome, from 0 (stable) to 1 (flips every
+/// run)
+fn flaky_score(outcomes: &[bool]) -> f64 {
+    if outcomes.len() < 2 {
+        return 0.0;
+    }
+    flips(outcomes) as f64 / (outcomes.len() - 1) as f64
+}
+
+fn flips(outcomes: &[bool]) -> u64 {
+    outcomes
+        .windows(2)
+        .filter(|pair| pair[0] != pair[1])
+        .count() as u64
+}
+
+fn history_err(err: rusqlite::Error) -> SmeltErr {
+    SmeltErr::HistoryFailed(err.to_string())
+}
+
+/// Embedded SQLite store of the outcome of every command across invocations
+///
+/// The database is only opened the first time it is used, and every query runs on tokio's
+/// blocking pool, so that SQLite never stalls the workers that drive the graph
+pub struct ResultsHistory {
+    db: Arc<Mutex<HistoryDb>>,
+}
+
+enum HistoryDb {
+    Unopened(PathBuf),
+    Open(Connection),
+}
+
+impl HistoryDb {
+    fn connection(&mut self) -> Result<&mut Connection, SmeltErr> {
+        if let HistoryDb::Unopened(path) = self {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            *self = HistoryDb::Open(open_connection(path)?);
+        }
+        match self {
+            HistoryDb::Open(conn) => Ok(conn),
+            HistoryDb::Unopened(_) => unreachable!("the history was just opened"),
+        }
+    }
+}
+
+fn open_connection(path: &Path) -> Result<Connection, SmeltErr> {
+    init_connection(Connection::open(path).map_err(history_err)?)
+}
+
+fn init_connection(conn: Connection) -> Result<Connection, SmeltErr> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS command_runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            invoke_id TEXT NOT NULL,
+            command_name TEXT NOT NULL,
+            outcome TEXT NOT NULL,
+            exit_code INTEGER NOT NULL,
+            duration_secs REAL NOT NULL,
+            git_hash TEXT NOT NULL,
+            def_digest TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS command_runs_by_name ON command_runs (command_name, id);",
+    )
+    .map_err(history_err)?;
+    Ok(conn)
+}
+
+fn record(conn: &mut Connection, entries: &[HistoryEntry]) -> Result<(), SmeltErr> {
+    let tx = conn.transaction().map_err(history_err)?;
+    {
+        let mut stmt = tx
+            .prepare(
+                "INSERT INTO command_runs
+                (invoke_id, command_name, outcome, exit_code, duration_secs, git_hash, def_digest)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )
+            .map_err(history_err)?;
+        for entry in entries {
+            stmt.execute(params![
+                entry.invoke_id,
+                entry.command_name,
+                entry.outcome,
+                entry.exit_code,
+                entry.duration_secs,
+                entry.git_hash,
+                entry.def_digest,
+            ])
+            .map_err(history_err)?;
+        }
+    }
+    tx.commit().map_err(history_err)
+}
+
+/// Whether each non-skipped run of `command_name` passed, oldest first
+fn outcomes(conn: &Connection, command_name: &str) -> Result<Vec<(bool, String)>, SmeltErr> {
+    let mut stmt = conn
+        .prepare(
+            "SELECT outcome, git_hash FROM command_runs
+            WHERE command_name = ?1 AND outcome != 'skipped'
+            ORDER BY id",
+        )
+        .map_err(history_err)?;
+    let rows = stmt
+        .query_map(params![command_name], |row| {
+            Ok((row.get::<_, String>(0)? == "passed", row.get(1)?))
+        })
+        .map_err(history_err)?;
+    rows.collect::<Result<_, _>>().map_err(history_err)
+}
+
+fn stats(conn: &Connection, command_name: &str) -> Result<CommandStats, SmeltErr> {
+    let outcomes = outcomes(conn, command_name)?;
+    let passed: Vec<bool> = outcomes.iter().map(|(passed, _)| *passed).collect();
+    let runs = passed.len() as u64;
+    let passes = passed.iter().filter(|passed| **passed).count() as u64;
+    Ok(CommandStats {
+        runs,
+        passes,
+        pass_rate: if runs == 0 {
+            0.0
+        } else {
+            passes as f64 / runs as f64
+        },
+        last_passing_commit: outcomes
+            .iter()
+            .rev()
+            .find(|(passed, _)| *passed)
+            .map(|(_, git_hash)| git_hash.clone()),
+        flip_count: flips(&passed),
+        flaky_score: flaky_score(&passed[passed.len().saturating_sub(FLAKY_WINDOW)..]),
+    })
+}
+
+impl ResultsHistory {
+    pub const fn history_file() -> &'static str {
+        "history.sqlite"
+    }
+
+    /// The history in `smelt-out/`, which is created the first time it is used
+    pub fn open(smelt_root: &Path) -> Self {
+        Self::with_db(HistoryDb::Unopened(
+            smelt_root.join("smelt-out").join(Self::history_file()),
+        ))
+    }
+
+    pub fn open_in_memory() -> Result<Self, SmeltErr> {
+        Ok(Self::with_db(HistoryDb::Open(init_connection(
+            Connection::open_in_memory().map_err(history_err)?,
+        )?)))
+    }
+
+    fn with_db(db: HistoryDb) -> Self {
+        Self {
+            db: Arc::new(Mutex::new(db)),
+        }
+    }
+
+    /// Runs `query` against the database on the blocking pool
+    async fn query<T, F>(&self, query: F) -> Result<T, SmeltErr>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, SmeltErr> + Send + 'static,
+    {
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || query(db.lock().unwrap().connection()?))
+            .await
+            .map_err(|err| SmeltErr::HistoryFailed(err.to_string()))?
+    }
+
+    /// Records every entry of an invocation in a single transaction
+    pub async fn record(&self, entries: Vec<HistoryEntry>) -> Result<(), SmeltErr> {
+        self.query(move |conn| record(conn, &entries)).await
+    }
+
+    pub async fn stats(&self, command_name: &str) -> Result<CommandStats, SmeltErr> {
+        let command_name = command_name.to_string();
+        self.query(move |conn| stats(conn, &command_name)).await
+    }
+
+    /// The flaky score of `command_name` once a run with the outcome `passed` is added to its
+    /// history
+    pub async fn flaky_score_with(
+        &self,
+        command_name: &str,
+        passed: bool,
+    ) -> Result<f64, SmeltErr> {
+        let command_name = command_name.to_string();
+        self.query(move |conn| {
+            let mut outcomes: Vec<bool> = outcomes(conn, &command_name)?
+                .into_iter()
+                .map(|(passed, _)| passed)
+                .collect();
+            outcomes.push(passed);
+            Ok(flaky_score(
+                &outcomes[outcomes.len().saturating_sub(FLAKY_WINDOW)..],
+            ))
+        })
+        .await
+    }
+}
+
+pub trait SetResultsHistory {
+    fn set_results_history(&mut self, history: Arc<ResultsHistory>);
+}
+
+pub trait GetResultsHistory {
+    fn get_results_history(&self) -> Arc<ResultsHistory>;
+}
+
+impl SetResultsHistory for DiceDataBuilder {
+    fn set_results_history(&mut self, history: Arc<ResultsHistory>) {
+        self.set(history)
+    }
+}
+
+impl GetResultsHistory for DiceData {
+    fn get_results_history(&self) -> Arc<ResultsHistory> {
+        self.get::<Arc<ResultsHistory>>()
+            .expect("Results history should be set")
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(invoke_id: &str, outcome: &str, git_hash: &str) -> HistoryEntry {
+        HistoryEntry {
+            invoke_id: invoke_id.to_string(),
+            command_name: "test".to_string(),
+            outcome: outcome.to_string(),
+            exit_code: if outcome == "failed" { 1 } else { 0 },
+            duration_secs: 1.0,
+            git_hash: git_hash.to_string(),
+            def_digest: "digest".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_and_flaky_score() {
+        let history = ResultsHistory::open_in_memory().unwrap();
+        assert_eq!(
+            history.stats("test").await.unwrap(),
+            CommandStats::default()
+        );
+
+        history
+            .record(vec![
+                entry("1", "passed", "aaa"),
+                entry("2", "failed", "bbb"),
+                entry("3", "skipped", "ccc"),
+                entry("4", "passed", "ddd"),
+                entry("5", "failed", "eee"),
+            ])
+            .await
+            .unwrap();
+
+        let stats = history.stats("test").await.unwrap();
+        assert_eq!(stats.runs, 4);
+        assert_eq!(stats.passes, 2);
+        assert_eq!(stats.pass_rate, 0.5);
+        assert_eq!(stats.last_passing_commit.as_deref(), Some("ddd"));
+        assert_eq!(stats.flip_count, 3);
+        assert_eq!(stats.flaky_score, 1.0);
+
+        // pass, fail, pass, fail, fail -- 3 flips out of 4 transitions
+        assert_eq!(history.flaky_score_with("test", false).await.unwrap(), 0.75);
+        assert_eq!(history.flaky_score_with("other", false).await.unwrap(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn opened_on_first_use() {
+        let root = std::env::temp_dir().join(format!("smelt-history-{}", std::process::id()));
+        let file = root.join("smelt-out").join(ResultsHistory::history_file());
+        let history = ResultsHistory::open(&root);
+        assert!(!file.exists());
+
+        history
+            .record(vec![entry("1", "passed", "aaa")])
+            .await
+            .unwrap();
+        assert!(file.exists());
+        assert_eq!(history.stats("test").await.unwrap().runs, 1);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use prost::Message;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use smelt_core::SmeltErr;
+use smelt_data::{
+    client_commands::ReportFormat,
+    command_event::CommandVariant,
+    event::Et,
+    executed_tests::{artifact_pointer::Pointer, Invocation, TestResult},
+    invoke_event::InvokeVariant,
+    CommandEvent, Event, InvokeEvent,
+};
+use smelt_events::event_log::EventLogWriter;
+
+use crate::{
+    export::{CommandStatus, CommandStatuses},
+    history::{HistoryEntry, ResultsHistory},
+    otlp::InvocationTracer,
+    report::{output_tail, CaseOutcome, InvocationReport, ReportCase},
+};
+
+/// Builds the `Invocation` record of a single run from the events that the run emits
+#[derive(Default)]
+pub struct InvocationRecorder {
+    invocation: Invocation,
+    dry_run: bool,
+    git_hash: String,
+    /// Where the outcome of every command is recorded once the run is done
+    history: Option<Arc<ResultsHistory>>,
+    /// Where the status of every command that a real run executes or skips is kept
+    statuses: Option<Arc<CommandStatuses>>,
+    /// Hex digest of the definition of every command in the graph, by name
+    def_digests: HashMap<String, String>,
+    /// Reports that are written next to the record
+    reports: Vec<ReportFormat>,
+    /// Seconds since the epoch that the run started at
+    started_at: f64,
+    /// Seconds since the epoch that each command started executing at
+    command_started_at: HashMap<String, f64>,
+    cases: Vec<ReportCase>,
+    /// Index into `cases` and log path of every failed command
+    failed_logs: Vec<(usize, String)>,
+}
+
+impl InvocationRecorder {
+    pub fn new(reports: Vec<ReportFormat>) -> Self {
+        Self {
+            reports,
+            ..Default::default()
+        }
+    }
+
+    /// Records the outcome of every command in `history` once the run is done
+    pub fn with_history(
+        mut self,
+        history: Arc<ResultsHistory>,
+        def_digests: HashMap<String, String>,
+    ) -> Self {
+        self.history = Some(history);
+        self.def_digests = def_digests;
+        self
+    }
+
+    /// Records the status of every command that the run executes or skips in `statuses`
+    pub fn with_statuses(mut self, statuses: Arc<CommandStatuses>) -> Self {
+        self.statuses = Some(statuses);
+        self
+    }
+
+    pub const fn invocations_dir() -> &'static str {
+        "invocations"
+    }
+
+    pub const fn proto_file() -> &'static str {
+        "invocation.pb"
+    }
+
+    pub const fn json_file() -> &'static str {
+        "invocation.json"
+    }
+
+    pub fn invocation(&self) -> &Invocation {
+        &self.invocation
+    }
+
+    /// A dry run doesn't execute anything, so it leaves the statuses of the last real run
+    fn record_status(&self, command_name: &str, status: CommandStatus) {
+        if let Some(statuses) = self.statuses.as_ref().filter(|_| !self.dry_run) {
+            statuses.record(command_name, status);
+        }
+    }
+
+    pub fn process_event(&mut self, event: &Event) {
+        match event.et.as_ref() {
+            Some(Et::Invoke(InvokeEvent {
+                invoke_variant: Some(InvokeVariant::Start(start)),
+            })) => {
+                self.invocation.invoke_id = event.trace_id.clone();
+                self.invocation.user = start.username.clone();
+                self.invocation.repo = start.git_repo.clone();
+                self.invocation.branch = start.git_branch.clone();
+                self.invocation.hostname = start.hostname.clone();
+                self.invocation.smelt_root = start.smelt_root.clone();
+                self.invocation.shard_index = start.shard_index;
+                self.invocation.shard_count = start.shard_count;
+                self.dry_run = start.dry_run;
+                self.git_hash = start.git_hash.clone();
+                self.started_at = event.time_secs();
+            }
+            Some(Et::Invoke(InvokeEvent {
+                invoke_variant: Some(InvokeVariant::Done(_)),
+            })) => {
+                self.invocation.rundate = event.time.clone();
+            }
+            Some(Et::Command(CommandEvent {
+                command_ref,
+                command_variant: Some(CommandVariant::Started(_)),
+            })) => {
+                // reruns of a failed test count towards the duration of its first attempt
+                self.command_started_at
+                    .entry(command_ref.clone())
+                    .or_insert_with(|| event.time_secs());
+            }
+            Some(Et::Command(CommandEvent {
+                command_ref,
+                command_variant: Some(CommandVariant::Skipped(_)),
+            })) => {
+                self.record_status(command_ref, CommandStatus::Skipped);
+                self.cases.push(ReportCase {
+                    name: command_ref.clone(),
+                    command_type: String::new(),
+                    duration_secs: 0.0,
+                    outcome: CaseOutcome::Skipped {
+                        reason: "a dependency failed or was skipped".to_string(),
+                    },
+                });
+            }
+            Some(Et::Command(CommandEvent {
+                command_ref,
+                command_variant: Some(CommandVariant::Finished(finished)),
+            })) => {
+                let outputs = finished.outputs.clone().unwrap_or_default();
+                let outcome = if outputs.exit_code == 0 {
+                    self.record_status(command_ref, CommandStatus::Passed);
+                    CaseOutcome::Passed
+                } else {
+                    let log = outputs
+                        .artifacts
+                        .iter()
+                        .find(|artifact| artifact.artifact_name == "smelt_log")
+                        .and_then(|artifact| artifact.pointer.as_ref())
+                        .map(|Pointer::Path(path)| path.clone());
+                    if let Some(log) = log {
+                        self.failed_logs.push((self.cases.len(), log));
+                    }
+                    self.record_status(command_ref, CommandStatus::Failed);
+                    CaseOutcome::Failed {
+                        exit_code: outputs.exit_code,
+                        output_tail: String::new(),
+                    }
+                };
+                let duration_secs = self
+                    .command_started_at
+                    .get(command_ref)
+                    .map(|started_at| event.time_secs() - started_at)
+                    .unwrap_or_default();
+                self.cases.push(ReportCase {
+                    name: command_ref.clone(),
+                    command_type: finished.command_type.clone(),
+                    duration_secs,
+                    outcome,
+                });
+
+                self.invocation.executed_tests.push(TestResult {
+                    test_name: command_ref.clone(),
+                    outputs: finished.outputs.clone(),
+                    metadata: finished.metadata.clone(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// The report of every command that has finished or been skipped so far, with the tail of
+    /// the log of every failed command
+    pub async fn report(&self) -> InvocationReport {
+        let mut suite_name = self.invocation.invoke_id.clone();
+        if self.invocation.shard_count != 0 {
+            suite_name = format!(
+                "{suite_name}-shard-{}-of-{}",
+                self.invocation.shard_index, self.invocation.shard_count
+            );
+        }
+        let duration_secs = self
+            .invocation
+            .rundate
+            .as_ref()
+            .map(|end| end.seconds as f64 + end.nanos as f64 / 1e9 - self.started_at)
+            .unwrap_or_default();
+
+        let mut cases = self.cases.clone();
+        for (idx, log) in self.failed_logs.iter() {
+            if let CaseOutcome::Failed {
+                output_tail: tail, ..
+            } = &mut cases[*idx].outcome
+            {
+                *tail = output_tail(log).await;
+            }
+        }
+        InvocationReport {
+            suite_name,
+            hostname: self.invocation.hostname.clone(),
+            duration_secs,
+            cases,
+        }
+    }
+
+    /// Adds the outcome of every command that finished or was skipped to the results history
+    ///
+    /// Dry runs are never recorded
+    pub async fn record_history(&self) -> Result<(), SmeltErr> {
+        let Some(history) = self.history.as_ref() else {
+            return Ok(());
+        };
+        if self.invocation.invoke_id.is_empty() || self.dry_run {
+            return Ok(());
+        }
+        let entries: Vec<HistoryEntry> = self
+            .cases
+            .iter()
+            .map(|case| {
+                let (outcome, exit_code) = match case.outcome {
+                    CaseOutcome::Passed => ("passed", 0),
+                    CaseOutcome::Failed { exit_code, .. } => ("failed", exit_code),
+                    CaseOutcome::Skipped { .. } => ("skipped", -1),
+                };
+                HistoryEntry {
+                    invoke_id: self.invocation.invoke_id.clone(),
+                    command_name: case.name.clone(),
+                    outcome: outcome.to_string(),
+                    exit_code,
+                    duration_secs: case.duration_secs,
+                    git_hash: self.git_hash.clone(),
+                    def_digest: self
+                        .def_digests
+                        .get(&case.name)
+                        .cloned()
+                        .unwrap_or_default(),
+                }
+            })
+            .collect();
+        history.record(entries).await
+    }
+
+    /// Writes the record as protobuf and as JSON to `smelt-out/invocations/<trace_id>`, along
+    /// with every report that was requested in `ConfigureSmelt`
+    ///
+    /// Returns the directory the record was written to, or None if there is nothing to record --
+    /// either the run never started, or it was a dry run
+    pub async fn write_to(&self, smelt_root: &Path) -> Result<Option<PathBuf>, SmeltErr> {
+        if self.invocation.invoke_id.is_empty() || self.dry_run {
+            return Ok(None);
+        }
+        let dir = smelt_root
+            .join("smelt-out")
+            .join(Self::invocations_dir())
+            .join(&self.invocation.invoke_id);
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::write(
+            dir.join(Self::proto_file()),
+            self.invocation.encode_to_vec(),
+        )
+        .await?;
+        tokio::fs::write(
+            dir.join(Self::json_file()),
+            serde_json::to_string_pretty(&self.invocation)?,
+        )
+        .await?;
+        if !self.reports.is_empty() {
+            self.report()
+                .await
+                .write_to(&dir, self.reports.iter().copied())
+                .await?;
+        }
+        Ok(Some(dir))
+    }
+}
+
+/// Forwards every event of a run from `rx` to `client`, recording the `Invocation` along the way
+///
+/// The record is written when `AllCommandsDone` comes through, and the directory it was written
+/// to is attached to that event before it is forwarded. If there is an event log, every event is
+/// appended to it as it is forwarded, and if there is a tracer the trace of the run is exported
+/// before the client hears that the run is done
+pub(crate) async fn record_invocation(
+    mut rx: Receiver<Event>,
+    client: Sender<Event>,
+    smelt_root: PathBuf,
+    mut recorder: InvocationRecorder,
+    mut event_log: Option<EventLogWriter>,
+    mut tracer: Option<InvocationTracer>,
+) {
+    while let Some(mut event) = rx.recv().await {
+        recorder.process_event(&event);
+        if let Some(ref mut active) = tracer {
+            active.process_event(&event);
+        }
+        if let Some(Et::Invoke(InvokeEvent {
+            invoke_variant: Some(InvokeVariant::Done(done)),
+        })) = event.et.as_mut()
+        {
+            match recorder.write_to(&smelt_root).await {
+                Ok(Some(dir)) => done.invocation_dir = dir.to_string_lossy().to_string(),
+                Ok(None) => {}
+                Err(err) => tracing::error!("Could not write the invocation record: {err}"),
+            }
+            if let Err(err) = recorder.record_history().await {
+                tracing::error!("Could not record the run in the results history: {err}");
+            }
+            if let Some(finished) = tracer.take() {
+                if let Err(err) = finished.export().await {
+                    tracing::error!("{err}");
+                }
+            }
+        }
+        if let Some(ref mut writer) = event_log {
+            if let Err(err) = append_event(writer, &event).await {
+                tracing::error!("Could not append to {}: {err}", writer.path().display());
+                event_log = None;
+            }
+        }
+        let _ = client.send(event).await;
+    }
+}
+
+/// The log is flushed once the run is done, so it is complete by the time the client hears of it
+async fn append_event(writer: &mut EventLogWriter, event: &Event) -> std::io::Result<()> {
+    writer.append(event).await?;
+    if event.finished_event() {
+        writer.flush().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use smelt_data::{executed_tests::TestOutputs, ExecutionStart};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn records_finished_commands() {
+        let trace_id = "trace".to_string();
+        let statuses = Arc::new(CommandStatuses::default());
+        statuses.record("cached", CommandStatus::Passed);
+        let mut recorder = InvocationRecorder::new(vec![ReportFormat::JunitXml, ReportFormat::Tap])
+            .with_statuses(statuses.clone());
+        recorder.process_event(&Event::new(
+            Et::Invoke(InvokeEvent {
+                invoke_variant: Some(InvokeVariant::Start(ExecutionStart {
+                    username: "me".to_string(),
+                    ..Default::default()
+                })),
+            }),
+            trace_id.clone(),
+        ));
+        recorder.process_event(&Event::command_skipped(
+            "skipped".to_string(),
+            trace_id.clone(),
+        ));
+        recorder.process_event(&Event::command_started(
+            "test".to_string(),
+            vec![0],
+            trace_id.clone(),
+        ));
+        recorder.process_event(&Event::command_finished(
+            TestResult {
+                test_name: "test".to_string(),
+                outputs: Some(TestOutputs {
+                    artifacts: vec![],
+                    exit_code: 1,
+                    ..Default::default()
+                }),
+                metadata: None,
+            },
+            "test".to_string(),
+            0.0,
+            trace_id.clone(),
+        ));
+        recorder.process_event(&Event::done(trace_id.clone()));
+
+        let invocation = recorder.invocation();
+        assert_eq!(invocation.invoke_id, trace_id);
+        assert_eq!(invocation.user, "me");
+        assert!(invocation.rundate.is_some());
+        assert_eq!(invocation.executed_tests.len(), 1);
+        assert_eq!(invocation.executed_tests[0].test_name, "test");
+
+        // a command that wasn't executed again keeps the status of its last run
+        let statuses = statuses.snapshot();
+        assert_eq!(statuses["cached"], CommandStatus::Passed);
+        assert_eq!(statuses["skipped"], CommandStatus::Skipped);
+        assert_eq!(statuses["test"], CommandStatus::Failed);
+
+        let report = recorder.report().await;
+        assert_eq!(report.cases.len(), 2);
+        assert!(matches!(
+            report.cases[0].outcome,
+            CaseOutcome::Skipped { .. }
+        ));
+        assert!(matches!(
+            report.cases[1].outcome,
+            CaseOutcome::Failed { exit_code: 1, .. }
+        ));
+
+        let root = std::env::temp_dir().join(format!("smelt-invocation-{}", std::process::id()));
+        let dir = recorder.write_to(&root).await.unwrap().unwrap();
+        assert_eq!(dir, root.join("smelt-out/invocations/trace"));
+        let bytes = tokio::fs::read(dir.join(InvocationRecorder::proto_file()))
+            .await
+            .unwrap();
+        assert_eq!(&Invocation::decode(bytes.as_slice()).unwrap(), invocation);
+        let json: serde_json::Value = serde_json::from_str(
+            &tokio::fs::read_to_string(dir.join(InvocationRecorder::json_file()))
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(json["executed_tests"][0]["outputs"]["exit_code"], 1);
+        assert!(dir.join(InvocationReport::junit_file()).exists());
+        assert!(dir.join(InvocationReport::tap_file()).exists());
+        let _ = tokio::fs::remove_dir_all(&root).await;
+    }
+}
@@ -2,8 +2,24 @@ mod commands;
 mod digest;
 mod dispatcher;
 mod executor;
+mod export;
 mod graph;
+mod history;
+mod invocation;
+mod otlp;
+mod query;
+mod report;
+mod retry;
+mod selector;
+mod shard;
 mod utils;
 
 pub use commands::*;
+pub use export::*;
 pub use graph::*;
+pub use history::*;
+pub use invocation::InvocationRecorder;
+pub use query::*;
+pub use report::*;
+pub use selector::*;
+pub use shard::*;
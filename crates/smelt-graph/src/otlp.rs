@@ -0,0 +1,409 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    time::Duration,
+};
+
+use smelt_core::SmeltErr;
+use smelt_data::{
+    client_commands::OtlpCfg,
+    command_event::CommandVariant,
+    event::Et,
+    invoke_event::InvokeVariant,
+    opentelemetry::proto::{
+        collector::trace::v1::{
+            trace_service_client::TraceServiceClient, ExportTraceServiceRequest,
+        },
+        common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue},
+        resource::v1::Resource,
+        trace::v1::{span, status::StatusCode, ResourceSpans, ScopeSpans, Span, Status},
+    },
+    CommandEvent, Event, InvokeEvent, SmeltErrorType,
+};
+use tonic::transport::Endpoint;
+
+use crate::commands::Runtime;
+
+/// Longest an export may take -- a collector that is down must not hold up the end of a run
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Builds the spans of a single run from its events, so that the run can be exported as an OTLP
+/// trace
+///
+/// The invocation is the root span, and every command gets a child span that covers it from
+/// when it was scheduled to when it finished
+pub struct InvocationTracer {
+    endpoint: String,
+    service_name: String,
+    /// Resources of every command in the graph, attached to the span of the command
+    runtimes: HashMap<String, Runtime>,
+    root: Span,
+    commands: HashMap<String, Span>,
+    /// Order the command spans were opened in, so the export is stable
+    order: Vec<String>,
+}
+
+fn attribute(key: &str, value: any_value::Value) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue { value: Some(value) }),
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> KeyValue {
+    attribute(key, any_value::Value::StringValue(value.to_string()))
+}
+
+fn int_attribute(key: &str, value: i64) -> KeyValue {
+    attribute(key, any_value::Value::IntValue(value))
+}
+
+/// OTLP trace ids are 16 bytes -- smelt trace ids are uuids, so they map onto them directly
+fn otlp_trace_id(trace_id: &str) -> Vec<u8> {
+    let digits: String = trace_id.chars().filter(|c| *c != '-').collect();
+    match hex::decode(&digits) {
+        Ok(bytes) if bytes.len() == 16 => bytes,
+        _ => {
+            let mut bytes = span_id(&[trace_id, "high"]);
+            bytes.extend(span_id(&[trace_id, "low"]));
+            bytes
+        }
+    }
+}
+
+/// Span ids are derived from the names of what they cover, so they are stable within a trace
+fn span_id(parts: &[&str]) -> Vec<u8> {
+    let mut hasher = DefaultHasher::new();
+    parts.hash(&mut hasher);
+    // an all zero span id is invalid
+    hasher.finish().max(1).to_be_bytes().to_vec()
+}
+
+fn span_event(name: &str, event: &Event) -> span::Event {
+    span::Event {
+        time_unix_nano: (event.time_secs() * 1e9) as u64,
+        name: name.to_string(),
+        ..Default::default()
+    }
+}
+
+impl InvocationTracer {
+    pub fn new(cfg: &OtlpCfg, trace_id: &str, runtimes: HashMap<String, Runtime>) -> Self {
+        let service_name = if cfg.service_name.is_empty() {
+            "smelt".to_string()
+        } else {
+            cfg.service_name.clone()
+        };
+        let root = Span {
+            trace_id: otlp_trace_id(trace_id),
+            span_id: span_id(&[trace_id]),
+            name: "invocation".to_string(),
+            kind: span::SpanKind::Internal.into(),
+            attributes: vec![string_attribute("smelt.trace_id", trace_id)],
+            ..Default::default()
+        };
+        Self {
+            endpoint: cfg.endpoint.clone(),
+            service_name,
+            runtimes,
+            root,
+            commands: HashMap::new(),
+            order: vec![],
+        }
+    }
+
+    fn command_span(&mut self, command_ref: &str, event: &Event) -> &mut Span {
+        if !self.commands.contains_key(command_ref) {
+            let mut attributes = vec![string_attribute("smelt.command.name", command_ref)];
+            if let Some(runtime) = self.runtimes.get(command_ref) {
+                attributes.extend([
+                    int_attribute("smelt.command.num_cpus", runtime.num_cpus.into()),
+                    int_attribute("smelt.command.max_memory_mb", runtime.max_memory_mb.into()),
+                    int_attribute("smelt.command.timeout", runtime.timeout.into()),
+                ]);
+            }
+            let trace_id = self.root.trace_id.clone();
+            let smelt_trace_id = event.trace_id.as_str();
+            self.commands.insert(
+                command_ref.to_string(),
+                Span {
+                    trace_id,
+                    span_id: span_id(&[smelt_trace_id, command_ref]),
+                    parent_span_id: self.root.span_id.clone(),
+                    name: command_ref.to_string(),
+                    kind: span::SpanKind::Internal.into(),
+                    start_time_unix_nano: (event.time_secs() * 1e9) as u64,
+                    attributes,
+                    ..Default::default()
+                },
+            );
+            self.order.push(command_ref.to_string());
+        }
+        self.commands.get_mut(command_ref).unwrap()
+    }
+
+    pub fn process_event(&mut self, event: &Event) {
+        match event.et.as_ref() {
+            Some(Et::Invoke(InvokeEvent {
+                invoke_variant: Some(InvokeVariant::Start(start)),
+            })) => {
+                self.root.start_time_unix_nano = (event.time_secs() * 1e9) as u64;
+                self.root.attributes.extend([
+                    string_attribute("smelt.username", &start.username),
+                    string_attribute("smelt.hostname", &start.hostname),
+                    string_attribute("smelt.git_hash", &start.git_hash),
+                    string_attribute("smelt.git_branch", &start.git_branch),
+                    string_attribute("smelt.smelt_root", &start.smelt_root),
+                    attribute("smelt.dry_run", any_value::Value::BoolValue(start.dry_run)),
+                ]);
+            }
+            Some(Et::Invoke(InvokeEvent {
+                invoke_variant: Some(InvokeVariant::Done(_)),
+            })) => {
+                self.root.end_time_unix_nano = (event.time_secs() * 1e9) as u64;
+            }
+            Some(Et::Command(CommandEvent {
+                command_ref,
+                command_variant: Some(variant),
+            })) => self.process_command(command_ref, variant, event),
+            Some(Et::Error(err)) if err.sig() != SmeltErrorType::InternalWarn => {
+                let mut error = span_event("error", event);
+                error.attributes = vec![
+                    string_attribute("message", &err.error_payload),
+                    string_attribute("code", err.code().as_str_name()),
+                ];
+                self.root.events.push(error);
+                self.root.status = Some(Status {
+                    message: err.error_payload.clone(),
+                    code: StatusCode::Error.into(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn process_command(&mut self, command_ref: &str, variant: &CommandVariant, event: &Event) {
+        let name = match variant {
+            CommandVariant::Scheduled(_) => "scheduled",
+            // a failed test that is rerun starts again, within the same span
+            CommandVariant::Started(_) => "started",
+            CommandVariant::Finished(finished) => {
+                let outputs = finished.outputs.clone().unwrap_or_default();
+                let span = self.command_span(command_ref, event);
+                span.end_time_unix_nano = (event.time_secs() * 1e9) as u64;
+                span.attributes.extend([
+                    int_attribute("smelt.command.exit_code", outputs.exit_code.into()),
+                    string_attribute("smelt.command.target_type", &finished.command_type),
+                    string_attribute("smelt.command.status", outputs.status().as_str_name()),
+                ]);
+                span.status = Some(Status {
+                    message: String::new(),
+                    code: if outputs.exit_code == 0 {
+                        StatusCode::Ok
+                    } else {
+                        StatusCode::Error
+                    }
+                    .into(),
+                });
+                "finished"
+            }
+            CommandVariant::Skipped(_) => {
+                let span = self.command_span(command_ref, event);
+                span.end_time_unix_nano = (event.time_secs() * 1e9) as u64;
+                span.attributes.push(attribute(
+                    "smelt.command.skipped",
+                    any_value::Value::BoolValue(true),
+                ));
+                "skipped"
+            }
+            _ => return,
+        };
+        let span_event = span_event(name, event);
+        self.command_span(command_ref, event)
+            .events
+            .push(span_event);
+    }
+
+    /// The spans of the run, as they are exported -- commands that never finished end with the
+    /// invocation
+    pub fn export_request(mut self) -> ExportTraceServiceRequest {
+        let end = self.root.end_time_unix_nano;
+        let mut spans = vec![];
+        for name in self.order.iter() {
+            let mut span = self.commands.remove(name).unwrap();
+            if span.end_time_unix_nano == 0 {
+                span.end_time_unix_nano = end.max(span.start_time_unix_nano);
+            }
+            spans.push(span);
+        }
+        spans.insert(0, self.root);
+
+        ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: Some(Resource {
+                    attributes: vec![string_attribute("service.name", &self.service_name)],
+                    dropped_attributes_count: 0,
+                }),
+                scope_spans: vec![ScopeSpans {
+                    scope: Some(InstrumentationScope {
+                        name: "smelt".to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                        ..Default::default()
+                    }),
+                    spans,
+                    schema_url: String::new(),
+                }],
+                schema_url: String::new(),
+            }],
+        }
+    }
+
+    /// Sends the spans of the run to the collector
+    pub async fn export(self) -> Result<(), SmeltErr> {
+        let export_err = |err: &dyn std::fmt::Display| SmeltErr::TraceExportFailed(err.to_string());
+        let channel = Endpoint::from_shared(self.endpoint.clone())
+            .map_err(|err| export_err(&err))?
+            .connect_timeout(EXPORT_TIMEOUT)
+            .timeout(EXPORT_TIMEOUT)
+            .connect()
+            .await
+            .map_err(|err| export_err(&err))?;
+        let request = self.export_request();
+        TraceServiceClient::new(channel)
+            .export(request)
+            .await
+            .map_err(|err| export_err(&err))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use smelt_data::{
+        executed_tests::{TestOutputs, TestResult},
+        opentelemetry::proto::collector::trace::v1::{
+            trace_service_server::{TraceService, TraceServiceServer},
+            ExportTraceServiceResponse,
+        },
+        ExecutionStart,
+    };
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{Request, Response};
+
+    use super::*;
+
+    /// Stand-in for an OTLP collector, which keeps every export it receives
+    #[derive(Clone, Default)]
+    struct Collector {
+        received: Arc<Mutex<Vec<ExportTraceServiceRequest>>>,
+    }
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(
+            &self,
+            request: Request<ExportTraceServiceRequest>,
+        ) -> Result<Response<ExportTraceServiceResponse>, tonic::Status> {
+            self.received.lock().unwrap().push(request.into_inner());
+            Ok(Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    fn attribute_value<'a>(span: &'a Span, key: &str) -> Option<&'a any_value::Value> {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key == key)
+            .and_then(|kv| kv.value.as_ref())
+            .and_then(|value| value.value.as_ref())
+    }
+
+    #[tokio::test]
+    async fn exports_invocation_and_command_spans() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let collector = Collector::default();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(collector.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let trace_id = "0b5bd5bd-6b69-4dc3-9d2c-23d3bdfb4b4a".to_string();
+        let cfg = OtlpCfg {
+            endpoint,
+            service_name: String::new(),
+        };
+        let runtimes = HashMap::from([(
+            "test".to_string(),
+            Runtime {
+                num_cpus: 2,
+                max_memory_mb: 512,
+                timeout: 60,
+            },
+        )]);
+        let mut tracer = InvocationTracer::new(&cfg, &trace_id, runtimes);
+        tracer.process_event(&Event::new(
+            Et::Invoke(InvokeEvent {
+                invoke_variant: Some(InvokeVariant::Start(ExecutionStart::default())),
+            }),
+            trace_id.clone(),
+        ));
+        tracer.process_event(&Event::command_scheduled(
+            "test".to_string(),
+            trace_id.clone(),
+        ));
+        tracer.process_event(&Event::command_started(
+            "test".to_string(),
+            vec![0],
+            trace_id.clone(),
+        ));
+        tracer.process_event(&Event::command_finished(
+            TestResult {
+                test_name: "test".to_string(),
+                outputs: Some(TestOutputs {
+                    exit_code: 3,
+                    ..Default::default()
+                }),
+                metadata: None,
+            },
+            "test".to_string(),
+            0.0,
+            trace_id.clone(),
+        ));
+        tracer.process_event(&Event::command_skipped(
+            "skipped".to_string(),
+            trace_id.clone(),
+        ));
+        tracer.process_event(&Event::done(trace_id.clone()));
+        tracer.export().await.unwrap();
+
+        let received = collector.received.lock().unwrap();
+        let spans = &received[0].resource_spans[0].scope_spans[0].spans;
+        let [root, test, skipped] = spans.as_slice() else {
+            panic!("expected 3 spans, got {spans:?}");
+        };
+        assert_eq!(hex::encode(&root.trace_id), trace_id.replace('-', ""));
+        assert!(root.parent_span_id.is_empty());
+        assert_eq!(test.parent_span_id, root.span_id);
+        assert_eq!(skipped.parent_span_id, root.span_id);
+
+        let event_names: Vec<&str> = test.events.iter().map(|val| val.name.as_str()).collect();
+        assert_eq!(event_names, ["scheduled", "started", "finished"]);
+        assert!(test.start_time_unix_nano <= test.end_time_unix_nano);
+        assert_eq!(
+            attribute_value(test, "smelt.command.exit_code"),
+            Some(&any_value::Value::IntValue(3))
+        );
+        assert_eq!(
+            attribute_value(test, "smelt.command.num_cpus"),
+            Some(&any_value::Value::IntValue(2))
+        );
+        assert_eq!(
+            test.status.as_ref().map(|status| status.code()),
+            Some(StatusCode::Error)
+        );
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::{hash_map::Entry, BTreeSet, HashMap, HashSet, VecDeque};
+
+use smelt_core::{closest_matches, SmeltErr};
+
+/// Resolved dependency edges between all of the commands in a graph
+///
+/// Edges come from both the `dependencies` of a command and the producers of its
+/// `dependent_files`. Commands are kept in declaration order, and every query returns commands
+/// in a deterministic order
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    names: Vec<String>,
+    index: HashMap<String, usize>,
+    /// For each command, the commands it directly depends on
+    deps: Vec<Vec<usize>>,
+    /// For each command, the commands that directly depend on it
+    rdeps: Vec<Vec<usize>>,
+}
+
+impl DependencyGraph {
+    pub(crate) fn new(names: impl IntoIterator<Item = String>) -> Self {
+        let names: Vec<String> = names.into_iter().collect();
+        let index = names
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| (name.clone(), idx))
+            .collect();
+        let deps = vec![vec![]; names.len()];
+        let rdeps = vec![vec![]; names.len()];
+        Self {
+            names,
+            index,
+            deps,
+            rdeps,
+        }
+    }
+
+    /// Records that `command` depends on `dependency`
+    ///
+    /// Both commands must already be part of the graph
+    pub(crate) fn add_edge(&mut self, command: &str, dependency: &str) {
+        let (Some(&command), Some(&dependency)) =
+            (self.index.get(command), self.index.get(dependency))
+        else {
+            tracing::warn!("Skipping edge {command} -> {dependency}, a command is missing");
+            return;
+        };
+
+        if !self.deps[command].contains(&dependency) {
+            self.deps[command].push(dependency);
+            self.rdeps[dependency].push(command);
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<usize, SmeltErr> {
+        self.index
+            .get(name)
+            .copied()
+            .ok_or_else(|| SmeltErr::MissingCommandDependency {
+                missing_dep_name: name.to_string(),
+                suggestions: closest_matches(name, &self.names),
+            })
+    }
+
+    fn lookup_all(&self, names: &[String]) -> Result<Vec<usize>, SmeltErr> {
+        names.iter().map(|name| self.lookup(name)).collect()
+    }
+
+    fn to_names(&self, nodes: impl IntoIterator<Item = usize>) -> Vec<String> {
+        nodes
+            .into_iter()
+            .map(|node| self.names[node].clone())
+            .collect()
+    }
+
+    /// Every node reachable from `roots` by following `edges`, roots excluded
+    fn reachable(&self, roots: &[usize], edges: &[Vec<usize>]) -> BTreeSet<usize> {
+        let mut seen = BTreeSet::new();
+        let mut queue: VecDeque<usize> = roots.iter().copied().collect();
+        while let Some(node) = queue.pop_front() {
+            for &next in edges[node].iter() {
+                if seen.insert(next) {
+                    queue.push_back(next);
+                }
+            }
+        }
+        for root in roots {
+            seen.remove(root);
+        }
+        seen
+    }
+
+    /// All of the commands that `roots` transitively depend on, not including `roots` themselves
+    pub fn deps(&self, roots: &[String]) -> Result<Vec<String>, SmeltErr> {
+        let roots = self.lookup_all(roots)?;
+        Ok(self.to_names(self.reachable(&roots, &self.deps)))
+    }
+
+    /// All of the commands that transitively depend on `roots`, not including `roots` themselves
+    pub fn rdeps(&self, roots: &[String]) -> Result<Vec<String>, SmeltErr> {
+        let roots = self.lookup_all(roots)?;
+        Ok(self.to_names(self.reachable(&roots, &self.rdeps)))
+    }
+
+    /// The shortest dependency path from `from` to `to`, both ends included
+    ///
+    /// Returns an empty vec if `from` does not depend on `to`
+    pub fn path(&self, from: &str, to: &str) -> Result<Vec<String>, SmeltErr> {
+        let from = self.lookup(from)?;
+        let to = self.lookup(to)?;
+
+        let mut parents: HashMap<usize, usize> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+        while let Some(node) = queue.pop_front() {
+            if node == to {
+                let mut path = vec![to];
+                let mut curr = to;
+                while curr != from {
+                    curr = parents[&curr];
+                    path.push(curr);
+                }
+                path.reverse();
+                return Ok(self.to_names(path));
+            }
+            for &next in self.deps[node].iter() {
+                if next != from && !parents.contains_key(&next) {
+                    parents.insert(next, node);
+                    queue.push_back(next);
+                }
+            }
+        }
+        Ok(vec![])
+    }
+
+    /// Kahn's algorithm over `nodes`
+    ///
+    /// Returns the sorted nodes, followed by the nodes that could not be sorted because they are
+    /// on, or depend on, a cycle
+    fn sort(&self, nodes: &BTreeSet<usize>) -> (Vec<usize>, BTreeSet<usize>) {
+        let mut remaining: HashMap<usize, usize> = nodes
+            .iter()
+            .map(|&node| (node, self.deps[node].len()))
+            .collect();
+        // ties are broken by declaration order, so the output is stable
+        let mut ready: BTreeSet<usize> = remaining
+            .iter()
+            .filter(|(_, &cnt)| cnt == 0)
+            .map(|(&node, _)| node)
+            .collect();
+
+        let mut order = Vec::with_capacity(nodes.len());
+        while let Some(node) = ready.pop_first() {
+            order.push(node);
+            remaining.remove(&node);
+            for rdep in self.rdeps[node].iter() {
+                if let Some(cnt) = remaining.get_mut(rdep) {
+                    *cnt -= 1;
+                    if *cnt == 0 {
+                        ready.insert(*rdep);
+                    }
+                }
+            }
+        }
+
+        (order, remaining.into_keys().collect())
+    }
+
+    /// Extracts one cycle per strongly connected component of the nodes left over by
+    /// [`Self::sort`]
+    ///
+    /// Leftover nodes that are only stuck behind a cycle, without being on one, are skipped
+    fn find_cycles(&self, leftover: &BTreeSet<usize>) -> Vec<Vec<usize>> {
+        let mut assigned = HashSet::new();
+        let mut cycles = vec![];
+        for &start in leftover {
+            if assigned.contains(&start) {
+                continue;
+            }
+            let upstream = self.reachable(&[start], &self.deps);
+            let mut component: BTreeSet<usize> = self
+                .reachable(&[start], &self.rdeps)
+                .intersection(&upstream)
+                .copied()
+                .collect();
+            component.insert(start);
+            assigned.extend(component.iter().copied());
+            if component.len() == 1 && !self.deps[start].contains(&start) {
+                continue;
+            }
+
+            // every node in the component has a dependency inside of it, so this walk has to
+            // loop back on itself
+            let mut path: Vec<usize> = vec![];
+            let mut on_path: HashMap<usize, usize> = HashMap::new();
+            let mut node = start;
+            while let Entry::Vacant(entry) = on_path.entry(node) {
+                entry.insert(path.len());
+                path.push(node);
+                node = *self.deps[node]
+                    .iter()
+                    .find(|dep| component.contains(dep))
+                    .expect("Nodes in a cycle always depend on another node in it");
+            }
+            let mut cycle = path.split_off(on_path[&node]);
+            cycle.push(node);
+            cycles.push(cycle);
+        }
+        cycles
+    }
+
+    /// Every dependency cycle in the graph
+    ///
+    /// Each cycle is listed in dependency order and starts and ends with the same command, so
+    /// `[a, b, a]` means that `a` depends on `b`, which depends on `a`
+    pub fn cycles(&self) -> Vec<Vec<String>> {
+        let all = (0..self.names.len()).collect();
+        let (_, leftover) = self.sort(&all);
+        self.find_cycles(&leftover)
+            .into_iter()
+            .map(|cycle| self.to_names(cycle))
+            .collect()
+    }
+
+    /// `roots` and all of their dependencies, ordered so that every command comes after the
+    /// commands it depends on
+    pub fn topo_order(&self, roots: &[String]) -> Result<Vec<String>, SmeltErr> {
+        let roots = self.lookup_all(roots)?;
+        let mut closure = self.reachable(&roots, &self.deps);
+        closure.extend(roots);
+
+        let (order, leftover) = self.sort(&closure);
+        if let Some(cycle) = self.find_cycles(&leftover).into_iter().next() {
+            return Err(SmeltErr::DependencyCycle {
+                cycle: self.to_names(cycle),
+            });
+        }
+
+        Ok(self.to_names(order))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(vals: &[&str]) -> Vec<String> {
+        vals.iter().map(|val| val.to_string()).collect()
+    }
+
+    /// build <- stim <- test_a
+    /// build <------- test_b
+    fn simple_graph() -> DependencyGraph {
+        let mut graph = DependencyGraph::new(names(&["test_a", "test_b", "stim", "build"]));
+        graph.add_edge("test_a", "stim");
+        graph.add_edge("stim", "build");
+        graph.add_edge("test_b", "build");
+        graph
+    }
+
+    #[test]
+    fn deps_and_rdeps() {
+        let graph = simple_graph();
+        assert_eq!(
+            graph.deps(&names(&["test_a"])).unwrap(),
+            names(&["stim", "build"])
+        );
+        assert_eq!(
+            graph.rdeps(&names(&["build"])).unwrap(),
+            names(&["test_a", "test_b", "stim"])
+        );
+        assert!(graph.deps(&names(&["build"])).unwrap().is_empty());
+        assert!(graph.deps(&names(&["missing"])).is_err());
+        assert!(matches!(
+            graph.deps(&names(&["test_c"])),
+            Err(SmeltErr::MissingCommandDependency { suggestions, .. }) if suggestions[0] == "test_a"
+        ));
+    }
+
+    #[test]
+    fn path_between_commands() {
+        let graph = simple_graph();
+        assert_eq!(
+            graph.path("test_a", "build").unwrap(),
+            names(&["test_a", "stim", "build"])
+        );
+        assert!(graph.path("test_b", "stim").unwrap().is_empty());
+    }
+
+    #[test]
+    fn topo_order_puts_deps_first() {
+        let graph = simple_graph();
+        assert_eq!(
+            graph.topo_order(&names(&["test_a", "test_b"])).unwrap(),
+            names(&["build", "test_b", "stim", "test_a"])
+        );
+    }
+
+    #[test]
+    fn topo_order_rejects_cycles() {
+        let mut graph = simple_graph();
+        graph.add_edge("build", "test_a");
+        assert!(matches!(
+            graph.topo_order(&names(&["test_b"])),
+            Err(SmeltErr::DependencyCycle { .. })
+        ));
+    }
+
+    #[test]
+    fn cycles_are_reported_once() {
+        let mut graph = simple_graph();
+        assert!(graph.cycles().is_empty());
+
+        graph.add_edge("build", "test_a");
+        // test_b is stuck behind the cycle, but is not part of it
+        assert_eq!(
+            graph.cycles(),
+            vec![names(&["test_a", "stim", "build", "test_a"])]
+        );
+
+        graph.add_edge("test_b", "test_b");
+        assert_eq!(graph.cycles().len(), 2);
+        assert!(graph.cycles().contains(&names(&["test_b", "test_b"])));
+    }
+}
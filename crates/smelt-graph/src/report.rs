@@ -0,0 +1,295 @@
+use std::{
+    fmt::Write,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use smelt_core::SmeltErr;
+use smelt_data::client_commands::ReportFormat;
+
+/// Number of lines from the end of a failing command's log that are put into a report
+const OUTPUT_TAIL_LINES: usize = 50;
+
+/// Number of bytes from the end of a failing command's log that the tail is taken from
+const OUTPUT_TAIL_BYTES: u64 = 16 * 1024;
+
+/// How a single command ended
+#[derive(Clone, Debug, PartialEq)]
+pub enum CaseOutcome {
+    Passed,
+    Failed {
+        exit_code: i32,
+        /// The last lines of the command's log
+        output_tail: String,
+    },
+    Skipped {
+        reason: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReportCase {
+    pub name: String,
+    /// The target type of the command -- empty if the command was never executed
+    pub command_type: String,
+    pub duration_secs: f64,
+    pub outcome: CaseOutcome,
+}
+
+/// The results of a finished invocation, in a shape that can be handed to CI systems
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InvocationReport {
+    pub suite_name: String,
+    pub hostname: String,
+    pub duration_secs: f64,
+    pub cases: Vec<ReportCase>,
+}
+
+impl InvocationReport {
+    pub const fn junit_file() -> &'static str {
+        "junit.xml"
+    }
+
+    pub const fn tap_file() -> &'static str {
+        "results.tap"
+    }
+
+    fn count(&self, pred: impl Fn(&CaseOutcome) -> bool) -> usize {
+        self.cases.iter().filter(|case| pred(&case.outcome)).count()
+    }
+
+    pub fn to_junit_xml(&self) -> String {
+        let mut xml = String::new();
+        let _ = writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        let _ = writeln!(xml, "<testsuites>");
+        let _ = writeln!(
+            xml,
+            r#"  <testsuite name="{}" tests="{}" failures="{}" skipped="{}" time="{:.3}" hostname="{}">"#,
+            escape_xml(&self.suite_name),
+            self.cases.len(),
+            self.count(|outcome| matches!(outcome, CaseOutcome::Failed { .. })),
+            self.count(|outcome| matches!(outcome, CaseOutcome::Skipped { .. })),
+            self.duration_secs,
+            escape_xml(&self.hostname),
+        );
+        for case in self.cases.iter() {
+            let _ = write!(
+                xml,
+                r#"    <testcase name="{}" classname="{}" time="{:.3}""#,
+                escape_xml(&case.name),
+                escape_xml(&case.command_type),
+                case.duration_secs,
+            );
+            match &case.outcome {
+                CaseOutcome::Passed => {
+                    let _ = writeln!(xml, "/>");
+                }
+                CaseOutcome::Failed {
+                    exit_code,
+                    output_tail,
+                } => {
+                    let _ = writeln!(xml, ">");
+                    let _ = writeln!(
+                        xml,
+                        r#"      <failure message="failed with exit code {}">{}</failure>"#,
+                        exit_code,
+                        escape_xml(output_tail)
+                    );
+                    let _ = writeln!(xml, "    </testcase>");
+                }
+                CaseOutcome::Skipped { reason } => {
+                    let _ = writeln!(xml, ">");
+                    let _ = writeln!(xml, r#"      <skipped message="{}"/>"#, escape_xml(reason));
+                    let _ = writeln!(xml, "    </testcase>");
+                }
+            }
+        }
+        let _ = writeln!(xml, "  </testsuite>");
+        let _ = writeln!(xml, "</testsuites>");
+        xml
+    }
+
+    /// Renders the report as TAP version 13, with the details of failures in YAML blocks
+    pub fn to_tap(&self) -> String {
+        let mut tap = String::new();
+        let _ = writeln!(tap, "TAP version 13");
+        let _ = writeln!(tap, "1..{}", self.cases.len());
+        for (idx, case) in self.cases.iter().enumerate() {
+            let num = idx + 1;
+            let name = case.name.replace('#', "\\#");
+            match &case.outcome {
+                CaseOutcome::Passed => {
+                    let _ = writeln!(tap, "ok {num} - {name}");
+                    let _ = writeln!(tap, "  ---");
+                    let _ = writeln!(tap, "  duration_ms: {:.0}", case.duration_secs * 1000.0);
+                    let _ = writeln!(tap, "  ...");
+                }
+                CaseOutcome::Failed {
+                    exit_code,
+                    output_tail,
+                } => {
+                    let _ = writeln!(tap, "not ok {num} - {name}");
+                    let _ = writeln!(tap, "  ---");
+                    let _ = writeln!(tap, "  duration_ms: {:.0}", case.duration_secs * 1000.0);
+                    let _ = writeln!(tap, "  exit_code: {exit_code}");
+                    let _ = writeln!(tap, "  output: |");
+                    for line in output_tail.lines() {
+                        let _ = writeln!(tap, "    {line}");
+                    }
+                    let _ = writeln!(tap, "  ...");
+                }
+                CaseOutcome::Skipped { reason } => {
+                    let _ = writeln!(tap, "ok {num} - {name} # SKIP {reason}");
+                }
+            }
+        }
+        tap
+    }
+
+    /// Writes one file per requested format to `output_dir`, returning the paths of the files
+    pub async fn write_to(
+        &self,
+        output_dir: &Path,
+        formats: impl IntoIterator<Item = ReportFormat>,
+    ) -> Result<Vec<PathBuf>, SmeltErr> {
+        let mut paths = vec![];
+        for format in formats {
+            let (path, contents) = match format {
+                ReportFormat::JunitXml => {
+                    (output_dir.join(Self::junit_file()), self.to_junit_xml())
+                }
+                ReportFormat::Tap => (output_dir.join(Self::tap_file()), self.to_tap()),
+            };
+            tokio::fs::write(&path, contents).await?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+/// The last `OUTPUT_TAIL_LINES` lines of the log at `path` -- empty if the log can't be read
+///
+/// Only the last `OUTPUT_TAIL_BYTES` of the log are read, and bytes that aren't valid utf-8 are
+/// replaced
+pub(crate) async fn output_tail(path: &str) -> String {
+    read_tail(path).await.unwrap_or_default()
+}
+
+async fn read_tail(path: &str) -> std::io::Result<String> {
+    let mut log = tokio::fs::File::open(path).await?;
+    let len = log.metadata().await?.len();
+    let start = len.saturating_sub(OUTPUT_TAIL_BYTES);
+    log.seek(SeekFrom::Start(start)).await?;
+    let mut bytes = Vec::with_capacity((len - start) as usize);
+    log.read_to_end(&mut bytes).await?;
+
+    let tail = String::from_utf8_lossy(&bytes);
+    let mut lines: Vec<&str> = tail.lines().collect();
+    // the first line is cut off, unless the log is read from the start
+    if start > 0 && !lines.is_empty() {
+        lines.remove(0);
+    }
+    Ok(lines[lines.len().saturating_sub(OUTPUT_TAIL_LINES)..].join("\n"))
+}
+
+fn escape_xml(val: &str) -> String {
+    let mut escaped = String::with_capacity(val.len());
+    for chr in val.chars() {
+        match chr {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // control characters are not allowed in xml 1.0, even escaped
+            chr if chr.is_control() && !matches!(chr, '\n' | '\r' | '\t') => {}
+            chr => escaped.push(chr),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> InvocationReport {
+        InvocationReport {
+            suite_name: "trace".to_string(),
+            hostname: "host".to_string(),
+            duration_secs: 3.0,
+            cases: vec![
+                ReportCase {
+                    name: "build".to_string(),
+                    command_type: "build".to_string(),
+                    duration_secs: 1.0,
+                    outcome: CaseOutcome::Passed,
+                },
+                ReportCase {
+                    name: "test_a".to_string(),
+                    command_type: "test".to_string(),
+                    duration_secs: 1.5,
+                    outcome: CaseOutcome::Failed {
+                        exit_code: 2,
+                        output_tail: "expected <1> got <2>".to_string(),
+                    },
+                },
+                ReportCase {
+                    name: "test_b".to_string(),
+                    command_type: String::new(),
+                    duration_secs: 0.0,
+                    outcome: CaseOutcome::Skipped {
+                        reason: "dependency failed".to_string(),
+                    },
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn junit_xml() {
+        let xml = report().to_junit_xml();
+        assert!(xml.contains(
+            r#"<testsuite name="trace" tests="3" failures="1" skipped="1" time="3.000" hostname="host">"#
+        ));
+        assert!(xml.contains(r#"<testcase name="build" classname="build" time="1.000"/>"#));
+        assert!(xml.contains(
+            r#"<failure message="failed with exit code 2">expected &lt;1&gt; got &lt;2&gt;</failure>"#
+        ));
+        assert!(xml.contains(r#"<skipped message="dependency failed"/>"#));
+    }
+
+    #[tokio::test]
+    async fn tail_of_a_long_log() {
+        let path = std::env::temp_dir().join(format!("smelt-tail-{}.log", std::process::id()));
+        let mut log = "x".repeat(OUTPUT_TAIL_BYTES as usize * 2).into_bytes();
+        for line in 0..100 {
+            log.extend(format!("\nline {line}").as_bytes());
+        }
+        log.extend(b" \xff");
+        tokio::fs::write(&path, log).await.unwrap();
+
+        let tail = output_tail(path.to_str().unwrap()).await;
+        let lines: Vec<&str> = tail.lines().collect();
+        assert_eq!(lines.len(), OUTPUT_TAIL_LINES);
+        assert_eq!(lines[0], "line 50");
+        assert_eq!(lines[OUTPUT_TAIL_LINES - 1], "line 99 \u{fffd}");
+        assert!(output_tail("/does/not/exist").await.is_empty());
+        let _ = tokio::fs::remove_file(path).await;
+    }
+
+    #[test]
+    fn tap() {
+        let tap = report().to_tap();
+        let lines: Vec<&str> = tap.lines().collect();
+        assert_eq!(lines[..3], ["TAP version 13", "1..3", "ok 1 - build"]);
+        assert!(tap.contains("not ok 2 - test_a\n  ---\n  duration_ms: 1500\n  exit_code: 2\n"));
+        assert!(tap.contains("    expected <1> got <2>\n"));
+        assert_eq!(
+            lines.last(),
+            Some(&"ok 3 - test_b # SKIP dependency failed")
+        );
+    }
+}
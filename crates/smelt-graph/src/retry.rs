@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use dice::{DiceData, UserComputationData};
+use smelt_data::executed_tests::{ExecutedTestResult, ResourceUsage, TestStatus};
+
+use crate::executor::{keep_attempt_log, Executor};
+use crate::Command;
+
+/// Classifies a test whose first attempt failed, from the outcomes of its reruns
+///
+/// Only `PassedOnRetry` counts as a pass. A `Flaky` test still fails: its result keeps the exit
+/// code of a failing attempt, so its dependents are skipped and the run fails, the same as if it
+/// had not been rerun
+pub(crate) fn classify_reruns(reruns_passed: &[bool]) -> TestStatus {
+    if reruns_passed.is_empty() || reruns_passed.iter().all(|passed| !passed) {
+        TestStatus::Failed
+    } else if reruns_passed.iter().all(|passed| *passed) {
+        TestStatus::PassedOnRetry
+    } else {
+        TestStatus::Flaky
+    }
+}
+
+fn attempt_usage(attempt: &ExecutedTestResult) -> ResourceUsage {
+    attempt
+        .clone()
+        .to_test_result()
+        .outputs
+        .and_then(|outputs| outputs.usage)
+        .unwrap_or_default()
+}
+
+/// Reruns a failed command up to `retries` times, reusing the results of its dependencies
+///
+/// The reruns stop early once they disagree with each other, as the test is known to be flaky at
+/// that point. The log of every attempt is kept as a `smelt_log_attempt_<n>` artifact
+///
+/// If every rerun passed, the last result is returned, otherwise the last failing one is -- see
+/// `classify_reruns`. Its `smelt_log` points at the log of the attempt it came from, and its usage
+/// covers every attempt
+pub(crate) async fn rerun_failed(
+    executor: Arc<dyn Executor>,
+    command: Arc<Command>,
+    first_attempt: ExecutedTestResult,
+    retries: u32,
+    dice_data: &UserComputationData,
+    global_data: &DiceData,
+) -> anyhow::Result<ExecutedTestResult> {
+    let mut attempt_logs = vec![keep_attempt_log(&command, global_data, 1).await?];
+    let mut usage = attempt_usage(&first_attempt);
+    let mut reruns_passed = vec![];
+    let mut last_failed = (1, first_attempt);
+    let mut last_passed = None;
+
+    for attempt in 2..=retries + 1 {
+        tracing::debug!("Rerunning {}, attempt {attempt}", command.name);
+        let output = executor
+            .execute_commands(command.clone(), dice_data, global_data)
+            .await?;
+        attempt_logs.push(keep_attempt_log(&command, global_data, attempt).await?);
+        usage.merge(&attempt_usage(&output));
+        reruns_passed.push(!output.failed());
+        if output.failed() {
+            last_failed = (attempt, output);
+        } else {
+            last_passed = Some((attempt, output));
+        }
+
+        if classify_reruns(&reruns_passed) == TestStatus::Flaky {
+            break;
+        }
+    }
+
+    let status = classify_reruns(&reruns_passed);
+    let (attempt, mut result) = match (status, last_passed) {
+        (TestStatus::PassedOnRetry, Some(last_passed)) => last_passed,
+        _ => last_failed,
+    };
+    if let Some(outputs) = result.outputs_mut() {
+        let attempt_log = attempt_logs[attempt as usize - 1].pointer.clone();
+        for artifact in outputs.artifacts.iter_mut() {
+            if artifact.artifact_name == "smelt_log" {
+                artifact.pointer = attempt_log.clone();
+            }
+        }
+        outputs.status = status.into();
+        outputs.artifacts.extend(attempt_logs);
+        outputs.usage = Some(usage);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        assert_eq!(classify_reruns(&[]), TestStatus::Failed);
+        assert_eq!(classify_reruns(&[false, false]), TestStatus::Failed);
+        assert_eq!(classify_reruns(&[true, true]), TestStatus::PassedOnRetry);
+        assert_eq!(classify_reruns(&[false, true]), TestStatus::Flaky);
+        assert_eq!(classify_reruns(&[true, false]), TestStatus::Flaky);
+    }
+}
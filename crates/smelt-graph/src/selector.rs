@@ -0,0 +1,299 @@
+use std::str::FromStr;
+
+use regex::Regex;
+
+use smelt_core::SmeltErr;
+
+use crate::commands::{Command, TargetType};
+
+/// An expression that picks commands out of the graph
+///
+/// The grammar is
+///
+/// ```text
+/// expr    := term ("or" term)*
+/// term    := factor ("and" factor)*
+/// factor  := "not" factor | "(" expr ")" | atom
+/// atom    := "name:" GLOB | "regex:" REGEX | "type:" TARGET_TYPE | "tag:" TAG
+///          | "meta:" KEY ["=" GLOB] | GLOB
+/// ```
+///
+/// A bare glob matches against command names, so `uart_* and not *_long` selects every command
+/// starting with `uart_` that does not end with `_long`. Values containing whitespace or
+/// parentheses can be wrapped in double quotes, e.g. `regex:"uart_(tx|rx)"`
+#[derive(Debug, Clone)]
+pub enum Selector {
+    /// Name matches a glob, where `*` matches any run of characters and `?` a single character
+    Name(Regex),
+    /// Name contains a match for a regex
+    Regex(Regex),
+    Type(TargetType),
+    Tag(String),
+    /// Metadata has the key, and if a glob is given, its value matches the glob
+    Meta(String, Option<Regex>),
+    And(Box<Selector>, Box<Selector>),
+    Or(Box<Selector>, Box<Selector>),
+    Not(Box<Selector>),
+}
+
+impl Selector {
+    pub fn matches(&self, command: &Command) -> bool {
+        match self {
+            Selector::Name(glob) => glob.is_match(&command.name),
+            Selector::Regex(regex) => regex.is_match(&command.name),
+            Selector::Type(target_type) => &command.target_type == target_type,
+            Selector::Tag(tag) => command.tags.iter().any(|val| val == tag),
+            Selector::Meta(key, glob) => match (command.metadata.get(key), glob) {
+                (Some(val), Some(glob)) => glob.is_match(val),
+                (Some(_), None) => true,
+                (None, _) => false,
+            },
+            Selector::And(lhs, rhs) => lhs.matches(command) && rhs.matches(command),
+            Selector::Or(lhs, rhs) => lhs.matches(command) || rhs.matches(command),
+            Selector::Not(inner) => !inner.matches(command),
+        }
+    }
+}
+
+impl FromStr for Selector {
+    type Err = SmeltErr;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| SmeltErr::InvalidSelector {
+            selector: selector.to_string(),
+            reason,
+        };
+        let tokens = tokenize(selector).map_err(invalid)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.expr().map_err(invalid)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(token) => Err(invalid(format!("unexpected {token}"))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Word(String),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::Open => write!(f, "'('"),
+            Token::Close => write!(f, "')'"),
+            Token::And => write!(f, "'and'"),
+            Token::Or => write!(f, "'or'"),
+            Token::Not => write!(f, "'not'"),
+            Token::Word(word) => write!(f, "'{word}'"),
+        }
+    }
+}
+
+fn tokenize(selector: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = selector.chars().peekable();
+    while let Some(&next) = chars.peek() {
+        match next {
+            _ if next.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            _ => {
+                let mut word = String::new();
+                let mut quoted = false;
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || next == '(' || next == ')' {
+                        break;
+                    }
+                    chars.next();
+                    if next != '"' {
+                        word.push(next);
+                        continue;
+                    }
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some(val) => word.push(val),
+                            None => return Err("unterminated quote".to_string()),
+                        }
+                    }
+                }
+                tokens.push(match word.as_str() {
+                    "and" if !quoted => Token::And,
+                    "or" if !quoted => Token::Or,
+                    "not" if !quoted => Token::Not,
+                    _ => Token::Word(word),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next_if(&mut self, token: Token) -> bool {
+        let found = self.tokens.get(self.pos) == Some(&token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expr(&mut self) -> Result<Selector, String> {
+        let mut lhs = self.term()?;
+        while self.next_if(Token::Or) {
+            lhs = Selector::Or(Box::new(lhs), Box::new(self.term()?));
+        }
+        Ok(lhs)
+    }
+
+    fn term(&mut self) -> Result<Selector, String> {
+        let mut lhs = self.factor()?;
+        while self.next_if(Token::And) {
+            lhs = Selector::And(Box::new(lhs), Box::new(self.factor()?));
+        }
+        Ok(lhs)
+    }
+
+    fn factor(&mut self) -> Result<Selector, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| "unexpected end of selector".to_string())?;
+        self.pos += 1;
+        match token {
+            Token::Not => Ok(Selector::Not(Box::new(self.factor()?))),
+            Token::Open => {
+                let expr = self.expr()?;
+                if !self.next_if(Token::Close) {
+                    return Err("missing ')'".to_string());
+                }
+                Ok(expr)
+            }
+            Token::Word(word) => atom(&word),
+            token => Err(format!("unexpected {token}")),
+        }
+    }
+}
+
+fn atom(word: &str) -> Result<Selector, String> {
+    match word.split_once(':') {
+        Some(("name", glob)) => glob_to_regex(glob).map(Selector::Name),
+        Some(("regex", regex)) => Regex::new(regex)
+            .map(Selector::Regex)
+            .map_err(|err| err.to_string()),
+        Some(("type", target_type)) => TargetType::from_str(target_type)
+            .map(Selector::Type)
+            .map_err(|err| err.to_string()),
+        Some(("tag", tag)) => Ok(Selector::Tag(tag.to_string())),
+        Some(("meta", meta)) => match meta.split_once('=') {
+            Some((key, glob)) => Ok(Selector::Meta(key.to_string(), Some(glob_to_regex(glob)?))),
+            None => Ok(Selector::Meta(meta.to_string(), None)),
+        },
+        _ => glob_to_regex(word).map(Selector::Name),
+    }
+}
+
+fn glob_to_regex(glob: &str) -> Result<Regex, String> {
+    let mut regex = String::from("^");
+    for val in glob.chars() {
+        match val {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            _ => regex.push_str(&regex::escape(&val.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::test_command;
+
+    fn command(name: &str, target_type: TargetType, tags: &[&str]) -> Command {
+        test_command(name, target_type)
+            .with_tags(tags)
+            .with_metadata("dut", &format!("{name}_core"))
+    }
+
+    fn select<'a>(selector: &str, commands: &'a [Command]) -> Vec<&'a str> {
+        let selector = Selector::from_str(selector).unwrap();
+        commands
+            .iter()
+            .filter(|command| selector.matches(command))
+            .map(|command| command.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn select_commands() {
+        let commands = vec![
+            command("uart_tx", TargetType::Test, &["smoke"]),
+            command("uart_rx_long", TargetType::Test, &[]),
+            command("uart_build", TargetType::Build, &[]),
+            command("spi_tx", TargetType::Test, &["smoke"]),
+        ];
+
+        assert_eq!(
+            select("uart_* and not *_long", &commands),
+            vec!["uart_tx", "uart_build"]
+        );
+        assert_eq!(
+            select(
+                "type:test and (tag:smoke or regex:\"_(rx|ry)_\")",
+                &commands
+            ),
+            vec!["uart_tx", "uart_rx_long", "spi_tx"]
+        );
+        assert_eq!(select("not not name:spi_??", &commands), vec!["spi_tx"]);
+        assert_eq!(
+            select("meta:dut=uart_r* or meta:owner", &commands),
+            vec!["uart_rx_long"]
+        );
+        assert!(select("\"and\"", &commands).is_empty());
+    }
+
+    #[test]
+    fn reject_malformed_selectors() {
+        for selector in [
+            "",
+            "uart_* and",
+            "(uart_*",
+            "uart_*)",
+            "type:nope",
+            "regex:(",
+            "\"open",
+        ] {
+            assert!(
+                matches!(
+                    Selector::from_str(selector),
+                    Err(SmeltErr::InvalidSelector { .. })
+                ),
+                "{selector} should not parse"
+            );
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use sha1::{Digest, Sha1};
+
+use smelt_core::SmeltErr;
+use smelt_data::client_commands::{Shard, ShardStrategy};
+
+/// Checks that a shard request makes sense -- a `shard_count` of 0 means the run is not sharded
+pub fn validate_shard(shard: &Shard) -> Result<(), SmeltErr> {
+    if shard.shard_count != 0 && shard.shard_index >= shard.shard_count {
+        return Err(SmeltErr::InvalidShard {
+            shard_index: shard.shard_index,
+            shard_count: shard.shard_count,
+        });
+    }
+    Ok(())
+}
+
+/// Picks the items that belong to `shard`, keeping their original order
+///
+/// The split only depends on the names of the items (and the past durations, if the shard is
+/// balanced), so every machine that is handed the same selection computes the same split
+pub fn select_shard<T>(
+    items: Vec<T>,
+    name: impl Fn(&T) -> &str,
+    shard: &Shard,
+) -> Result<Vec<T>, SmeltErr> {
+    validate_shard(shard)?;
+    if shard.shard_count == 0 {
+        return Ok(items);
+    }
+
+    let names: Vec<&str> = items.iter().map(&name).collect();
+    let assignment = match shard.strategy() {
+        ShardStrategy::HashNames => names
+            .iter()
+            .map(|name| hash_shard(name, shard.shard_count))
+            .collect(),
+        ShardStrategy::BalanceDurations => {
+            balance_shards(&names, &shard.past_durations, shard.shard_count)
+        }
+    };
+
+    Ok(items
+        .into_iter()
+        .zip(assignment)
+        .filter(|(_, assigned)| *assigned == shard.shard_index)
+        .map(|(item, _)| item)
+        .collect())
+}
+
+fn hash_shard(name: &str, shard_count: u32) -> u32 {
+    let digest: [u8; 20] = Sha1::digest(name.as_bytes()).into();
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(prefix) % u64::from(shard_count)) as u32
+}
+
+/// Longest processing time first -- the slowest remaining item always goes to the least loaded
+/// shard
+///
+/// Items without a past duration are assumed to take the mean of the known durations
+fn balance_shards(names: &[&str], durations: &HashMap<String, f64>, shard_count: u32) -> Vec<u32> {
+    let known: Vec<f64> = names
+        .iter()
+        .filter_map(|name| durations.get(*name).copied())
+        .collect();
+    let fallback = if known.is_empty() {
+        1.0
+    } else {
+        known.iter().sum::<f64>() / known.len() as f64
+    };
+
+    let mut order: Vec<usize> = (0..names.len()).collect();
+    let duration = |idx: usize| durations.get(names[idx]).copied().unwrap_or(fallback);
+    order.sort_by(|&a, &b| {
+        duration(b)
+            .total_cmp(&duration(a))
+            .then_with(|| names[a].cmp(names[b]))
+    });
+
+    let mut loads = vec![0.0f64; shard_count as usize];
+    let mut assignment = vec![0; names.len()];
+    for idx in order {
+        let (lightest, _) = loads
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .expect("There is always at least one shard");
+        loads[lightest] += duration(idx);
+        assignment[idx] = lightest as u32;
+    }
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shard(shard_index: u32, shard_count: u32, strategy: ShardStrategy) -> Shard {
+        Shard {
+            shard_index,
+            shard_count,
+            strategy: strategy.into(),
+            past_durations: HashMap::new(),
+        }
+    }
+
+    fn names(count: usize) -> Vec<String> {
+        (0..count).map(|idx| format!("test_{idx}")).collect()
+    }
+
+    #[test]
+    fn hashed_shards_cover_everything_once() {
+        let all = names(50);
+        let mut seen = vec![];
+        for idx in 0..4 {
+            let selected = select_shard(
+                all.clone(),
+                |val| val.as_str(),
+                &shard(idx, 4, ShardStrategy::HashNames),
+            )
+            .unwrap();
+            // stable across calls
+            assert_eq!(
+                selected,
+                select_shard(
+                    all.clone(),
+                    |val| val.as_str(),
+                    &shard(idx, 4, ShardStrategy::HashNames)
+                )
+                .unwrap()
+            );
+            seen.extend(selected);
+        }
+        seen.sort();
+        let mut expected = all.clone();
+        expected.sort();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn balanced_shards_split_by_duration() {
+        let all = vec!["long", "short_a", "short_b", "short_c"];
+        let mut request = shard(0, 2, ShardStrategy::BalanceDurations);
+        request.past_durations = HashMap::from([
+            ("long".to_string(), 30.0),
+            ("short_a".to_string(), 10.0),
+            ("short_b".to_string(), 10.0),
+        ]);
+        assert_eq!(
+            select_shard(all.clone(), |val| val, &request).unwrap(),
+            vec!["long"]
+        );
+        request.shard_index = 1;
+        assert_eq!(
+            select_shard(all, |val| val, &request).unwrap(),
+            vec!["short_a", "short_b", "short_c"]
+        );
+    }
+
+    #[test]
+    fn unsharded_and_invalid_requests() {
+        let all = names(3);
+        assert_eq!(
+            select_shard(all.clone(), |val| val.as_str(), &Shard::default()).unwrap(),
+            all
+        );
+        assert!(matches!(
+            select_shard(
+                all,
+                |val| val.as_str(),
+                &shard(2, 2, ShardStrategy::HashNames)
+            ),
+            Err(SmeltErr::InvalidShard { .. })
+        ));
+    }
+}
@@ -1,8 +1,8 @@
 use dice::{DiceData, UserComputationData};
-use smelt_data::{invoke_event::InvokeVariant, Event, ExecutionStart};
+use smelt_data::{client_commands::Shard, invoke_event::InvokeVariant, Event, ExecutionStart};
 use smelt_events::{
     new_invoke_event,
-    runtime_support::{GetSmeltRoot, GetTraceId},
+    runtime_support::{GetDryRun, GetSmeltRoot, GetTraceId},
 };
 use tokio::process::Command;
 use whoami::fallible;
@@ -43,8 +43,13 @@ async fn get_git_info() -> (String, String, String) {
     (hash, branch, repo)
 }
 
-async fn exec_info(global_data: &DiceData) -> ExecutionStart {
-    let hostname = fallible::hostname().unwrap_or("unknown_host".to_string());
+/// Name of the host smelt is running on
+pub(crate) fn local_hostname() -> String {
+    fallible::hostname().unwrap_or("unknown_host".to_string())
+}
+
+async fn exec_info(global_data: &DiceData, shard: &Shard, dry_run: bool) -> ExecutionStart {
+    let hostname = local_hostname();
     let username = fallible::username().unwrap_or("unkown_user".to_string());
 
     let smelt_root = global_data.get_smelt_root().to_string_lossy().to_string();
@@ -57,14 +62,19 @@ async fn exec_info(global_data: &DiceData) -> ExecutionStart {
         git_hash,
         git_branch,
         git_repo,
+        shard_index: shard.shard_index,
+        shard_count: shard.shard_count,
+        dry_run,
     }
 }
 
 pub async fn invoke_start_message(
     user_data: &UserComputationData,
     global_data: &DiceData,
+    shard: &Shard,
 ) -> Event {
-    let invoke_variant = InvokeVariant::Start(exec_info(global_data).await);
+    let invoke_variant =
+        InvokeVariant::Start(exec_info(global_data, shard, user_data.is_dry_run()).await);
     let trace_id = user_data.get_trace_id();
     new_invoke_event(trace_id, invoke_variant)
 }
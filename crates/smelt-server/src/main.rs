@@ -0,0 +1,128 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use anyhow::Context;
+use clap::Parser;
+use smelt_data::{
+    client_commands::{configure_smelt::InitExecutor, CfgLocal, ConfigureSmelt, OtlpCfg},
+    smelt_server::smelt_controller_server::SmeltControllerServer,
+};
+use smelt_graph::spawn_graph_server;
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::Server;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+mod service;
+
+use service::ControllerService;
+
+/// Serves a long-lived smelt command graph over gRPC
+///
+/// Every client that connects shares the same graph, cached results and job slots
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// TCP address to listen on
+    #[arg(long, default_value = "127.0.0.1:50051", conflicts_with = "uds")]
+    addr: SocketAddr,
+    /// Unix socket to listen on, instead of a TCP address
+    #[arg(long)]
+    uds: Option<PathBuf>,
+    /// JSON encoded `ConfigureSmelt` -- when provided, the flags below are ignored
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Root of the project, smelt-out/ is created under it
+    #[arg(long)]
+    smelt_root: Option<PathBuf>,
+    /// Number of job slots the executor has -- defaults to the number of cpus
+    #[arg(long)]
+    jobs: Option<u64>,
+    /// Ignores every command that is not a test
+    #[arg(long)]
+    test_only: bool,
+    /// Number of extra times a failed test is rerun
+    #[arg(long, default_value_t = 0)]
+    retry_failed: u32,
+    /// Records every event of every run to smelt-out/events/, so it can be replayed
+    #[arg(long)]
+    record_events: bool,
+    /// Exports every run as a trace to the OTLP collector at this endpoint
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+}
+
+impl Args {
+    fn smelt_cfg(&self) -> anyhow::Result<ConfigureSmelt> {
+        if let Some(ref config) = self.config {
+            let contents = std::fs::read_to_string(config)
+                .with_context(|| format!("Could not read the config at {}", config.display()))?;
+            return serde_json::from_str(&contents)
+                .with_context(|| format!("Malformed config at {}", config.display()));
+        }
+
+        let smelt_root = match self.smelt_root {
+            Some(ref root) => root.canonicalize()?,
+            None => std::env::current_dir()?,
+        };
+        let job_slots = match self.jobs {
+            Some(jobs) => jobs,
+            None => std::thread::available_parallelism()?.get() as u64,
+        };
+        Ok(ConfigureSmelt {
+            smelt_root: smelt_root.to_string_lossy().to_string(),
+            job_slots,
+            test_only: self.test_only,
+            silent: true,
+            retry_failed: self.retry_failed,
+            record_events: self.record_events,
+            otlp: self.otlp_endpoint.as_ref().map(|endpoint| OtlpCfg {
+                endpoint: endpoint.clone(),
+                ..Default::default()
+            }),
+            init_executor: Some(InitExecutor::Local(CfgLocal {})),
+            ..Default::default()
+        })
+    }
+}
+
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+    tracing::info!("Shutting down smelt-server");
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let args = Args::parse();
+    let cfg = args.smelt_cfg()?;
+    tracing::info!("Serving the command graph rooted at {}", cfg.smelt_root);
+
+    let service = SmeltControllerServer::new(ControllerService::new(spawn_graph_server(cfg)));
+    let server = Server::builder().add_service(service);
+
+    match args.uds {
+        Some(path) => {
+            // a socket left over from a previous server would fail the bind
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            let incoming = UnixListenerStream::new(UnixListener::bind(&path)?);
+            tracing::info!("Listening on {}", path.display());
+            server
+                .serve_with_incoming_shutdown(incoming, shutdown_signal())
+                .await?;
+            std::fs::remove_file(&path)?;
+        }
+        None => {
+            tracing::info!("Listening on {}", args.addr);
+            server
+                .serve_with_shutdown(args.addr, shutdown_signal())
+                .await?;
+        }
+    }
+    Ok(())
+}
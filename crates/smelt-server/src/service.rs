@@ -0,0 +1,211 @@
+use std::pin::Pin;
+
+use futures::{Stream, StreamExt};
+use smelt_data::{
+    client_commands::{ClientCommand, ClientResp},
+    smelt_server::smelt_controller_server::SmeltController,
+    Event,
+};
+use smelt_events::{ClientCommandBundle, ClientCommandResp, EventStreams};
+use smelt_graph::SmeltServerHandle;
+use tokio::sync::oneshot;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+/// Serves the `SmeltController` rpcs from a single command graph
+pub struct ControllerService {
+    handle: SmeltServerHandle,
+}
+
+impl ControllerService {
+    pub fn new(handle: SmeltServerHandle) -> Self {
+        Self { handle }
+    }
+
+    // `Status` is what every rpc returns anyways
+    #[allow(clippy::result_large_err)]
+    fn submit(&self, message: ClientCommand) -> Result<EventStreams, Status> {
+        let (bundle, streams) = ClientCommandBundle::from_message(message);
+        self.handle
+            .tx_client
+            .send(bundle)
+            .map_err(|_| Status::unavailable("The command graph is no longer running"))?;
+        Ok(streams)
+    }
+}
+
+async fn command_resp(
+    sync_chan: oneshot::Receiver<ClientCommandResp>,
+) -> Result<ClientResp, Status> {
+    match sync_chan.await {
+        Ok(Ok(resp)) => Ok(resp),
+        Ok(Err(err)) => Err(Status::failed_precondition(err.error_payload)),
+        Err(_) => Err(Status::internal(
+            "The command graph dropped the command before responding",
+        )),
+    }
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<Event, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl SmeltController for ControllerService {
+    async fn execute(
+        &self,
+        request: Request<ClientCommand>,
+    ) -> Result<Response<ClientResp>, Status> {
+        let EventStreams {
+            sync_chan,
+            mut event_stream,
+        } = self.submit(request.into_inner())?;
+
+        // nobody is listening to these events, but the graph blocks if they are not drained
+        tokio::spawn(async move { while event_stream.recv().await.is_some() {} });
+
+        command_resp(sync_chan).await.map(Response::new)
+    }
+
+    type RunStream = EventStream;
+
+    async fn run(
+        &self,
+        request: Request<ClientCommand>,
+    ) -> Result<Response<Self::RunStream>, Status> {
+        let EventStreams {
+            sync_chan,
+            event_stream,
+        } = self.submit(request.into_inner())?;
+
+        // the events of a command stop once the command is done, so its response is already in
+        let outcome = futures::stream::once(command_resp(sync_chan))
+            .filter_map(|resp| async move { resp.err().map(Err) });
+        let events = ReceiverStream::new(event_stream).map(Ok).chain(outcome);
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smelt_data::{
+        client_commands::{
+            client_command::ClientCommands, client_resp::ClientResponses,
+            configure_smelt::InitExecutor, CfgLocal, ConfigureSmelt, RunOne, RunType, SetCommands,
+        },
+        command_event::CommandVariant,
+        event::Et,
+        smelt_server::{
+            smelt_controller_client::SmeltControllerClient,
+            smelt_controller_server::SmeltControllerServer,
+        },
+        CommandEvent,
+    };
+    use smelt_graph::spawn_graph_server;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{transport::Channel, Code};
+
+    use super::*;
+
+    const COMMANDS: &str = r#"
+- name: passes
+  target_type: test
+  script: ["echo passes"]
+  runtime: {num_cpus: 1, max_memory_mb: 1, timeout: 10}
+- name: fails
+  target_type: test
+  script: ["exit 3"]
+  runtime: {num_cpus: 1, max_memory_mb: 1, timeout: 10}
+"#;
+
+    fn command(command: ClientCommands) -> ClientCommand {
+        ClientCommand {
+            client_commands: Some(command),
+        }
+    }
+
+    /// Serves a command graph rooted in a fresh directory on a free port
+    async fn serve() -> SmeltControllerClient<Channel> {
+        let smelt_root = std::env::temp_dir().join(format!("smelt-server-{}", std::process::id()));
+        std::fs::create_dir_all(&smelt_root).unwrap();
+        let cfg = ConfigureSmelt {
+            smelt_root: smelt_root.to_string_lossy().to_string(),
+            job_slots: 1,
+            silent: true,
+            init_executor: Some(InitExecutor::Local(CfgLocal {})),
+            ..Default::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let service = ControllerService::new(spawn_graph_server(cfg));
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(SmeltControllerServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        SmeltControllerClient::connect(endpoint).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn execute_and_run() {
+        let mut client = serve().await;
+
+        let resp = client
+            .execute(command(ClientCommands::Setter(SetCommands {
+                command_content: COMMANDS.to_string(),
+            })))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(matches!(
+            resp.client_responses,
+            Some(ClientResponses::Validation(ref validation)) if validation.errors.is_empty()
+        ));
+
+        let mut events = client
+            .run(command(ClientCommands::Runtype(RunType {
+                typeinfo: "test".to_string(),
+                ..Default::default()
+            })))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut passed = vec![];
+        let mut done = false;
+        while let Some(event) = events.message().await.unwrap() {
+            assert!(!done, "no event is sent after AllCommandsDone");
+            done = event.finished_event();
+            if let Some(Et::Command(CommandEvent {
+                command_ref,
+                command_variant: Some(CommandVariant::Finished(finished)),
+            })) = event.et
+            {
+                passed.push((command_ref, finished.passed()));
+            }
+        }
+        assert!(done);
+        passed.sort();
+        assert_eq!(
+            passed,
+            vec![("fails".to_string(), false), ("passes".to_string(), true)]
+        );
+
+        // a client command that fails ends the stream with an error
+        let mut events = client
+            .run(command(ClientCommands::Runone(RunOne {
+                command_name: "missing".to_string(),
+                ..Default::default()
+            })))
+            .await
+            .unwrap()
+            .into_inner();
+        let status = loop {
+            match events.message().await {
+                Ok(Some(_)) => {}
+                Ok(None) => panic!("the stream ended without an error"),
+                Err(status) => break status,
+            }
+        };
+        assert_eq!(status.code(), Code::FailedPrecondition);
+    }
+}
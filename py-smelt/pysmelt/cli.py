@@ -5,13 +5,31 @@ import yaml
 from pysmelt.interfaces.paths import SmeltPath
 from pysmelt.output import smelt_console
 from pysmelt.interfaces import SmeltTargetType
-from pysmelt.proto.smelt_client.commands import CfgDocker, ConfigureSmelt
+from pysmelt.interfaces.analysis import past_durations_from_junit
+from pysmelt.proto.smelt_client.commands import (
+    CfgDocker,
+    ConfigureSmelt,
+    OtlpCfg,
+    ReportFormat,
+    Shard,
+    ShardStrategy,
+)
 from pysmelt.rc import SmeltRcHolder
+from rich.table import Table
 from pysmelt.smelt_muncher import parse_smelt
 from pysmelt.output_utils import pretty_print_tests
-from pysmelt.pygraph import create_graph
+from pysmelt.proto.smelt_telemetry import CommandPlanned
+from pysmelt.pygraph import (
+    PyGraph,
+    create_graph,
+    maybe_get_message,
+    replay_events,
+    write_timeline,
+)
+from pysmelt.subscribers.error_handler import GraphValidationError, SmeltErrEx
+from pysmelt.subscribers.output_collector import OutputConsole
 from pysmelt.serde import SafeDataclassDumper
-from typing import Optional, Dict
+from typing import Optional, Dict, Tuple
 from typer import Exit
 from pysmelt.templates.template_rule import create_rule_target_from_template
 
@@ -80,6 +98,57 @@ def lower(
         yaml.dump(commands, f, Dumper=SafeDataclassDumper, sort_keys=False)
 
 
+REPORT_FORMATS = {"junit": ReportFormat.JUNIT_XML, "tap": ReportFormat.TAP}
+
+
+def validate_reports(values: List[str]):
+    for value in values:
+        if value not in REPORT_FORMATS:
+            raise Exit(
+                f'Invalid value for "--report". Possible values are {", ".join(REPORT_FORMATS)}'
+            )
+    return values
+
+
+def _create_shard(
+    shard_index: int, shard_count: int, balance_from: Optional[str]
+) -> Optional[Shard]:
+    if shard_count == 0:
+        return None
+    if balance_from:
+        return Shard(
+            shard_index=shard_index,
+            shard_count=shard_count,
+            strategy=ShardStrategy.BALANCE_DURATIONS,
+            past_durations=past_durations_from_junit(balance_from),
+        )
+    return Shard(shard_index=shard_index, shard_count=shard_count)
+
+
+def _run_target(graph: PyGraph, target_name: str):
+    """
+    Runs a single target, reporting an unknown target name (and the closest matching names) without a traceback
+    """
+    try:
+        graph.run_one_test_interactive(target_name)
+    except SmeltErrEx as err:
+        smelt_console.print(f"[red] {err}")
+        raise Exit(code=1)
+
+
+def _print_plan(plan: Optional[List[Tuple[str, CommandPlanned]]]):
+    """
+    Prints every command of a dry run, in the order it would execute
+    """
+    plan = plan or []
+    for name, planned in plan:
+        smelt_console.rule(
+            f"{name} -- cpus: {planned.num_cpus}, memory: {planned.max_memory_mb}MB, timeout: {planned.timeout}s"
+        )
+        smelt_console.print(planned.script, markup=False, highlight=False)
+    smelt_console.print(f"[green] {len(plan)} commands would execute")
+
+
 @app.command(
     help="Executes an smelt file",
 )
@@ -89,6 +158,18 @@ def execute(
     target_name: Optional[str] = typer.Option(
         None, help="Target name -- if not provided, runs all the tests"
     ),
+    select: Optional[str] = typer.Option(
+        None,
+        help='Selector expression, e.g. "uart_* and not *_long" -- takes precedence over --tt',
+    ),
+    shard_index: int = typer.Option(0, help="Index of the shard to run"),
+    shard_count: int = typer.Option(
+        0, help="Number of shards the selected commands are split into -- 0 disables sharding"
+    ),
+    balance_from: Optional[str] = typer.Option(
+        None,
+        help="JUnit xml from a previous run -- if set, shards are balanced by past test durations instead of name hashes",
+    ),
     test_only: bool = typer.Option(
         False,
         help="If set, assumes non-test commands have passed successfully and will not run them",
@@ -97,6 +178,30 @@ def execute(
     jobs: Optional[int] = typer.Option(
         None, "--jobs", help="max number of jobslots allowed"
     ),
+    dry_run: bool = typer.Option(
+        False,
+        help="If set, prints the script and resources of every command that would execute, without running anything",
+        is_flag=True,
+    ),
+    report: List[str] = typer.Option(
+        [],
+        help="Report written next to the invocation record in smelt-out/invocations/ -- junit or tap, can be repeated",
+        callback=validate_reports,
+    ),
+    retry_failed: int = typer.Option(
+        0,
+        help="Reruns each failed test up to this many times, to tell flaky failures apart",
+        min=0,
+    ),
+    record_events: bool = typer.Option(
+        False,
+        help="If set, every event of the run is recorded to smelt-out/events/, so it can be replayed",
+        is_flag=True,
+    ),
+    otlp_endpoint: Optional[str] = typer.Option(
+        None,
+        help="OTLP collector that the run is exported to as a trace, e.g. http://localhost:4317",
+    ),
 ):
 
     if jobs:
@@ -104,13 +209,27 @@ def execute(
 
     def configure_cb(cfg: ConfigureSmelt) -> ConfigureSmelt:
         cfg.test_only = test_only
+        cfg.reports = [REPORT_FORMATS[val] for val in report]
+        cfg.retry_failed = retry_failed
+        cfg.record_events = record_events
+        if otlp_endpoint:
+            cfg.otlp = OtlpCfg(endpoint=otlp_endpoint)
         return cfg
 
     graph = create_graph(str(smelt_file), cfg_init=configure_cb)
+    shard = _create_shard(shard_index, shard_count, balance_from)
+    if target_name and not dry_run:
+        _run_target(graph, target_name)
+        return
+
     if target_name:
-        graph.run_one_test_interactive(target_name)
+        plan = graph.plan_one_test(target_name)
+    elif select:
+        plan = graph.run_selected_commands(select, shard, dry_run)
     else:
-        graph.run_all_typed_commands(tt)
+        plan = graph.run_all_typed_commands(tt, shard, dry_run)
+    if dry_run:
+        _print_plan(plan)
 
 
 @app.command(
@@ -135,6 +254,25 @@ def execute_docker(
     jobs: Optional[int] = typer.Option(
         None, "--jobs", help="max number of jobslots allowed"
     ),
+    report: List[str] = typer.Option(
+        [],
+        help="Report written next to the invocation record in smelt-out/invocations/ -- junit or tap, can be repeated",
+        callback=validate_reports,
+    ),
+    retry_failed: int = typer.Option(
+        0,
+        help="Reruns each failed test up to this many times, to tell flaky failures apart",
+        min=0,
+    ),
+    record_events: bool = typer.Option(
+        False,
+        help="If set, every event of the run is recorded to smelt-out/events/, so it can be replayed",
+        is_flag=True,
+    ),
+    otlp_endpoint: Optional[str] = typer.Option(
+        None,
+        help="OTLP collector that the run is exported to as a trace, e.g. http://localhost:4317",
+    ),
 ):
 
     if jobs:
@@ -142,6 +280,11 @@ def execute_docker(
 
     def configure_cb(cfg: ConfigureSmelt) -> ConfigureSmelt:
         cfg.test_only = test_only
+        cfg.reports = [REPORT_FORMATS[val] for val in report]
+        cfg.retry_failed = retry_failed
+        cfg.record_events = record_events
+        if otlp_endpoint:
+            cfg.otlp = OtlpCfg(endpoint=otlp_endpoint)
         cfg.docker = CfgDocker()
         cfg.docker.image_name = img
         cfg.docker.additional_mounts = {}
@@ -149,7 +292,7 @@ def execute_docker(
 
     graph = create_graph(str(smelt_file), cfg_init=configure_cb)
     if target_name:
-        graph.run_one_test_interactive(target_name)
+        _run_target(graph, target_name)
     elif tt:
         graph.run_all_typed_commands(tt)
     else:
@@ -163,11 +306,86 @@ def validate(
     smelt_file: TlPath,
 ):
 
-    graph = create_graph(str(smelt_file))
+    try:
+        graph = create_graph(str(smelt_file))
+    except GraphValidationError as err:
+        smelt_console.print(f"[red] {smelt_file.name} is invalid\n{err}")
+        raise Exit(code=1)
     smelt_console.print(f"[green] {smelt_file.name} is valid")
     pretty_print_tests(graph)
 
 
+@app.command(
+    help="Exports the command graph of an smelt file as Graphviz DOT and JSON",
+)
+def export_graph(
+    smelt_file: TlPath,
+    output_dir: Optional[str] = typer.Option(
+        None, help="Directory to write graph.dot and graph.json to -- defaults to smelt-out/"
+    ),
+):
+    graph = create_graph(str(smelt_file))
+    resp = graph.export_graph(output_dir, include_status=False)
+    smelt_console.print(f"[green] Wrote {resp.dot_path} and {resp.json_path}")
+
+
+@app.command(
+    help="Shows the pass rate, last passing commit and flakiness of commands across previous runs",
+)
+def history(
+    smelt_file: TlPath,
+    target_name: Optional[List[str]] = typer.Option(
+        None, help="Target name -- if not provided, shows every command"
+    ),
+):
+    graph = create_graph(str(smelt_file))
+    table = Table(show_header=True, header_style="bold magenta")
+    for column in [
+        "Command Name",
+        "Runs",
+        "Pass rate",
+        "Last passing commit",
+        "Flips",
+        "Flaky score",
+    ]:
+        table.add_column(column)
+    for entry in graph.command_history(target_name):
+        table.add_row(
+            entry.command_name,
+            str(entry.runs),
+            f"{entry.pass_rate:.0%}",
+            entry.last_passing_commit[:12] or "never",
+            str(entry.flip_count),
+            f"{entry.flaky_score:.2f}",
+        )
+    smelt_console.print(table)
+
+
+@app.command(
+    help="Replays a run that was recorded with --record-events, as if it was running",
+)
+def replay(event_log: Path):
+    listener = replay_events(str(event_log))
+    with OutputConsole() as console:
+        while not listener.is_done():
+            message = maybe_get_message(listener)
+            if message:
+                console.process_message(message)
+
+
+@app.command(
+    help="Writes a run that was recorded with --record-events as a timeline, which opens in Perfetto",
+)
+def timeline(
+    event_log: Path,
+    output: Path = typer.Option(
+        Path("timeline.json"), help="Where the Chrome trace-event JSON is written"
+    ),
+):
+    write_timeline(str(event_log), str(output))
+    smelt_console.log(f"Wrote the timeline to {output}")
+
+
 @app.command(help="Create a new target def file at the provided path")
 def init_rule(output: CommandPath):
     create_rule_target_from_template(str(output))
@@ -1,5 +1,6 @@
 import pathlib
-from typing import List, Optional
+from collections import defaultdict
+from typing import Dict, List, Optional
 from pysmelt.proto.executed_tests import Invocation, TestResult
 from pysmelt.interfaces.paths import SmeltPath
 from dataclasses import dataclass
@@ -21,6 +22,22 @@ def most_recent_junit_path() -> SmeltPath:
     return SmeltPath("smelt-out/tests.xml")
 
 
+def past_durations_from_junit(junit_path: str) -> Dict[str, float]:
+    """
+    Reads the duration of every test case in a JUnit xml file, in seconds
+
+    Useful for balancing shards by the durations of a previous run
+    """
+    from junitparser.junitparser import JUnitXml
+
+    return {
+        case.name: case.time
+        for suite in JUnitXml.fromfile(junit_path)
+        for case in suite
+        if case.name
+    }
+
+
 def get_previous_invocation() -> Invocation:
     with open(most_recent_invoke_path().to_abs_path(), "rb") as f:
         invbytes = f.read()
@@ -97,3 +114,32 @@ class IQL:
             ]
         else:
             return None
+
+    def group_by_metadata(self, key: str) -> Dict[str, List[TestResult]]:
+        """
+        Groups the executed tests by the value of a metadata key
+
+        `owner` and `dut` group by the owner and design under test, tests without the key are grouped under ""
+        """
+
+        def get_value(test: TestResult) -> str:
+            if key == "owner":
+                return test.metadata.test_owner
+            if key == "dut":
+                return test.metadata.design_under_test
+            return test.metadata.extras.get(key, "")
+
+        groups: Dict[str, List[TestResult]] = defaultdict(list)
+        for test in self.inv.executed_tests:
+            groups[get_value(test)].append(test)
+        return dict(groups)
+
+    def group_by_tag(self) -> Dict[str, List[TestResult]]:
+        """
+        Groups the executed tests by tag -- a test with several tags shows up in several groups
+        """
+        groups: Dict[str, List[TestResult]] = defaultdict(list)
+        for test in self.inv.executed_tests:
+            for tag in test.metadata.tags:
+                groups[tag].append(test)
+        return dict(groups)
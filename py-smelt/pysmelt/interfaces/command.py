@@ -1,7 +1,7 @@
 from typing import List, Literal, Dict, Any, Optional, Tuple
 from enum import Enum
 from pysmelt.interfaces.runtime import RuntimeRequirements
-from dataclasses import dataclass, asdict
+from dataclasses import dataclass, asdict, field
 
 
 from pysmelt.rc import SmeltRcHolder
@@ -38,6 +38,16 @@ class Command:
     runtime: RuntimeRequirements
     working_dir: str
     on_failure: Optional[CommandRef] = None
+    tags: List[str] = field(default_factory=list)
+    """
+    Free-form labels that can be used to select commands
+    """
+    metadata: Dict[str, str] = field(default_factory=dict)
+    """
+    Free-form key/value pairs that are carried into the results of the command
+
+    The `owner` and `dut` keys are reported as the owner and design under test of the command
+    """
 
     @classmethod
     def from_dict(cls, data: Dict[str, Any]):
@@ -47,6 +57,8 @@ class Command:
         dependencies = data["dependencies"] if "dependencies" in data else []
         dependent_files = data["dependent_files"] if "dependent_files" in data else []
         outputs = data["outputs"] if "outputs" in data else []
+        tags = data["tags"] if "tags" in data else []
+        metadata = data["metadata"] if "metadata" in data else {}
         working_dir = (
             data["working_dir"]
             if "working_dir" in data
@@ -64,6 +76,8 @@ class Command:
             outputs=outputs,
             runtime=runtime,
             working_dir=working_dir,
+            tags=tags,
+            metadata=metadata,
         )
 
     def to_dict(self) -> Dict[str, Any]:
@@ -88,6 +88,31 @@ class Target(ABC):
         """
         return []
 
+    def get_tags(self) -> List[str]:
+        """
+        Free-form labels attached to every command generated by this target
+
+        By default, these are the `tags` given to the target in the smelt file
+        """
+        return list(self.__dict__.get("_smelt_tags", []))
+
+    def get_metadata(self) -> Dict[str, str]:
+        """
+        Free-form key/value pairs attached to every command generated by this target
+
+        By default, this is the `metadata` given to the target in the smelt file
+        """
+        return dict(self.__dict__.get("_smelt_metadata", {}))
+
+    def annotate(self, tags: List[str], metadata: Dict[str, str]):
+        """
+        Sets the tags and metadata returned by the default `get_tags` and `get_metadata`
+
+        Targets are usually frozen dataclasses, so the attributes are set directly
+        """
+        object.__setattr__(self, "_smelt_tags", list(tags))
+        object.__setattr__(self, "_smelt_metadata", dict(metadata))
+
     @property
     def as_ref(self) -> TargetRef:
         """
@@ -114,6 +139,8 @@ class Target(ABC):
             outputs=outputs,
             working_dir=working_dir,
             on_failure=f"{rerun_command.name}" if rerun_command else None,
+            tags=self.get_tags(),
+            metadata=self.get_metadata(),
         )
 
     def to_command(self, working_dir: str) -> Command:
@@ -152,6 +179,8 @@ class Target(ABC):
                 dependent_files=dependent_files,
                 outputs=outputs,
                 working_dir=working_dir,
+                tags=self.get_tags(),
+                metadata=self.get_metadata(),
             )
 
     def default_rebuild_command(self, working_dir: str) -> Optional[Command]:
@@ -181,6 +210,8 @@ class Target(ABC):
                 dependent_files=dependent_files,
                 outputs=outputs,
                 working_dir=working_dir,
+                tags=self.get_tags(),
+                metadata=self.get_metadata(),
             )
 
     def __post_init__(self):
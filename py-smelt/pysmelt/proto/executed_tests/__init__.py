@@ -5,11 +5,31 @@
 
 from dataclasses import dataclass
 from datetime import datetime
-from typing import List
+from typing import (
+    Dict,
+    List,
+)
 
 import betterproto
 
 
+class TestStatus(betterproto.Enum):
+    PASSED = 0
+    """passed on the first attempt"""
+
+    FAILED = 1
+    """every attempt failed"""
+
+    FLAKY = 2
+    """
+    the reruns of a failed attempt disagreed with each other -- the test
+    still fails, with the exit code of a failing attempt
+    """
+
+    PASSED_ON_RETRY = 3
+    """the first attempt failed, and every rerun passed"""
+
+
 @dataclass(eq=False, repr=False)
 class Digest(betterproto.Message):
     """Taken directly from the bazel reapi, for compat"""
@@ -31,6 +51,34 @@ class ArtifactPointer(betterproto.Message):
     artifact_name: str = betterproto.string_field(3)
 
 
+@dataclass(eq=False, repr=False)
+class TestMetaData(betterproto.Message):
+    """
+    Populated from the `tags` and `metadata` of the command that was executed
+    The `owner` and `dut` metadata keys are lifted into their own fields, every
+    other key ends up in `extras`
+    """
+
+    test_owner: str = betterproto.string_field(1)
+    """User name of who owns the test"""
+
+    design_under_test: str = betterproto.string_field(2)
+    """
+    name of the design under test -- useful for tracking across different
+    testbenches
+    """
+
+    extras: Dict[str, str] = betterproto.map_field(
+        3, betterproto.TYPE_STRING, betterproto.TYPE_STRING
+    )
+    """
+    Any extra data end-users might want to tag onto a test, for test
+    organization
+    """
+
+    tags: List[str] = betterproto.string_field(4)
+
+
 @dataclass(eq=False, repr=False)
 class TestResult(betterproto.Message):
     """
@@ -42,6 +90,7 @@ class TestResult(betterproto.Message):
     """name of the test"""
 
     outputs: "TestOutputs" = betterproto.message_field(2)
+    metadata: "TestMetaData" = betterproto.message_field(3)
 
 
 @dataclass(eq=False, repr=False)
@@ -52,6 +101,39 @@ class TestOutputs(betterproto.Message):
     exit_code: int = betterproto.int32_field(2)
     """exit code of the test"""
 
+    status: "TestStatus" = betterproto.enum_field(3)
+    """how the test ended up once failed attempts were rerun"""
+
+    usage: "ResourceUsage" = betterproto.message_field(4)
+    """what running the test cost, over every attempt"""
+
+
+@dataclass(eq=False, repr=False)
+class ResourceUsage(betterproto.Message):
+    """Resources a command used while it executed"""
+
+    started_at: datetime = betterproto.message_field(1)
+    """
+    wall clock time the first attempt started, and the last attempt finished
+    """
+
+    finished_at: datetime = betterproto.message_field(2)
+    user_cpu_ms: int = betterproto.uint64_field(3)
+    """
+    cpu time used by the command and every descendant it waited for, in
+     milliseconds -- the cpu time of every attempt adds up
+    """
+
+    system_cpu_ms: int = betterproto.uint64_field(4)
+    peak_rss_bytes: int = betterproto.uint64_field(5)
+    """resident memory of the largest process of the command, in bytes"""
+
+    hostname: str = betterproto.string_field(6)
+    """host the command was executed from"""
+
+    container: str = betterproto.string_field(7)
+    """container the command ran in -- empty unless it ran in docker"""
+
 
 @dataclass(eq=False, repr=False)
 class Invocation(betterproto.Message):
@@ -68,3 +150,10 @@ class Invocation(betterproto.Message):
     hostname: str = betterproto.string_field(6)
     smelt_root: str = betterproto.string_field(7)
     executed_tests: List["TestResult"] = betterproto.message_field(8)
+    shard_index: int = betterproto.uint32_field(9)
+    """
+    Set if the invocation only ran one shard of the requested tests, so the
+    invocations of every shard can be merged
+    """
+
+    shard_count: int = betterproto.uint32_field(10)
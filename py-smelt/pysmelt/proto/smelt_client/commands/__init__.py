@@ -13,12 +13,55 @@ from typing import (
 import betterproto
 
 
+class ReportFormat(betterproto.Enum):
+    JUNIT_XML = 0
+    """written to junit.xml"""
+
+    TAP = 1
+    """written to results.tap"""
+
+
 class ProfilingSelection(betterproto.Enum):
     DISABLED = 0
     SIMPLE_PROF = 1
     """only memory and cpu"""
 
 
+class QueryType(betterproto.Enum):
+    DEPS = 0
+    """transitive dependencies of the queried commands"""
+
+    RDEPS = 1
+    """transitive reverse dependencies of the queried commands"""
+
+    PATH = 2
+    """a dependency path from the queried command to the destination command"""
+
+    TOPO_ORDER = 3
+    """
+    the queried commands and all of their dependencies, in the order they can
+    be executed
+    """
+
+
+class ValidationErrorKind(betterproto.Enum):
+    MISSING_COMMAND_DEPENDENCY = 0
+    MISSING_FILE_DEPENDENCY = 1
+    DUPLICATE_COMMAND_NAME = 2
+    DUPLICATE_OUTPUT = 3
+    DEPENDENCY_CYCLE = 4
+
+
+class ShardStrategy(betterproto.Enum):
+    HASH_NAMES = 0
+    """each command lands in the shard picked by a hash of its name"""
+
+    BALANCE_DURATIONS = 1
+    """
+    commands are spread so every shard has about the same total past duration
+    """
+
+
 class RunMode(betterproto.Enum):
     Local = 0
     Remote = 1
@@ -31,6 +74,19 @@ class ClientCommand(betterproto.Message):
     runtype: "RunType" = betterproto.message_field(3, group="ClientCommands")
     runmany: "RunMany" = betterproto.message_field(4, group="ClientCommands")
     getcfg: "GetConfig" = betterproto.message_field(5, group="ClientCommands")
+    query: "Query" = betterproto.message_field(6, group="ClientCommands")
+    export: "ExportGraph" = betterproto.message_field(7, group="ClientCommands")
+    runselected: "RunSelected" = betterproto.message_field(8, group="ClientCommands")
+    history: "HistoryQuery" = betterproto.message_field(9, group="ClientCommands")
+    add_commands: "AddCommands" = betterproto.message_field(
+        10, group="ClientCommands"
+    )
+    remove_commands: "RemoveCommands" = betterproto.message_field(
+        11, group="ClientCommands"
+    )
+    replace_command: "ReplaceCommand" = betterproto.message_field(
+        12, group="ClientCommands"
+    )
 
 
 @dataclass(eq=False, repr=False)
@@ -38,14 +94,55 @@ class SetCommands(betterproto.Message):
     command_content: str = betterproto.string_field(1)
 
 
+@dataclass(eq=False, repr=False)
+class AddCommands(betterproto.Message):
+    """
+    Incremental edits of a graph that is already set -- only the commands that
+    are touched by an edit are validated, and every other command keeps its
+    cached results. Each of them is answered with a SetCommandsResp
+
+    command_content is a YAML list of commands
+    """
+
+    command_content: str = betterproto.string_field(1)
+
+
+@dataclass(eq=False, repr=False)
+class RemoveCommands(betterproto.Message):
+    """
+    Refused if a command that is kept depends on one of the removed commands
+    """
+
+    command_names: List[str] = betterproto.string_field(1)
+
+
+@dataclass(eq=False, repr=False)
+class ReplaceCommand(betterproto.Message):
+    """
+    command_content is a single YAML command, which takes the place of the
+    command with the same name
+    """
+
+    command_content: str = betterproto.string_field(1)
+
+
 @dataclass(eq=False, repr=False)
 class RunOne(betterproto.Message):
+    """
+    When dry_run is set on a run request, commands are planned in dependency
+    order but never handed to an executor -- a CommandPlanned event is sent for
+    each of them instead
+    """
+
     command_name: str = betterproto.string_field(1)
+    dry_run: bool = betterproto.bool_field(2)
 
 
 @dataclass(eq=False, repr=False)
 class RunMany(betterproto.Message):
     command_names: List[str] = betterproto.string_field(1)
+    shard: "Shard" = betterproto.message_field(2)
+    dry_run: bool = betterproto.bool_field(3)
 
 
 @dataclass(eq=False, repr=False)
@@ -56,12 +153,88 @@ class RunType(betterproto.Message):
     babey
     """
 
+    shard: "Shard" = betterproto.message_field(2)
+    dry_run: bool = betterproto.bool_field(3)
+
 
 @dataclass(eq=False, repr=False)
 class GetConfig(betterproto.Message):
     pass
 
 
+@dataclass(eq=False, repr=False)
+class RunSelected(betterproto.Message):
+    """
+    Runs every command matched by a selector expression, e.g. `uart_* and not
+    *_long` or `type:test and (tag:smoke or regex:"^spi_")`
+    """
+
+    selector: str = betterproto.string_field(1)
+    shard: "Shard" = betterproto.message_field(2)
+    dry_run: bool = betterproto.bool_field(3)
+
+
+@dataclass(eq=False, repr=False)
+class Shard(betterproto.Message):
+    """
+    Restricts a run to a stable subset of the requested commands -- the
+    dependencies of the subset are still executed. A shard_count of 0 means the
+    run is not sharded
+    """
+
+    shard_index: int = betterproto.uint32_field(1)
+    shard_count: int = betterproto.uint32_field(2)
+    strategy: "ShardStrategy" = betterproto.enum_field(3)
+    past_durations: Dict[str, float] = betterproto.map_field(
+        4, betterproto.TYPE_STRING, betterproto.TYPE_DOUBLE
+    )
+    """
+    Past durations of commands, in seconds -- only used by BALANCE_DURATIONS.
+    Commands without a duration are assumed to take the mean duration
+    """
+
+
+@dataclass(eq=False, repr=False)
+class Query(betterproto.Message):
+    """
+    Asks a structural question about the current graph -- nothing is executed
+    """
+
+    query_type: "QueryType" = betterproto.enum_field(1)
+    command_names: List[str] = betterproto.string_field(2)
+    destination: str = betterproto.string_field(3)
+    """Only used for PATH queries"""
+
+
+@dataclass(eq=False, repr=False)
+class ExportGraph(betterproto.Message):
+    """
+    Writes the current graph as Graphviz DOT and as a JSON node/edge list
+    """
+
+    output_dir: str = betterproto.string_field(1)
+    """
+    Directory the exported files are written to -- defaults to
+    $SMELT_ROOT/smelt-out
+    """
+
+    include_status: bool = betterproto.bool_field(2)
+    """
+    If true, every node carries its pass/fail/skip state from the latest run
+    of the command
+    """
+
+
+@dataclass(eq=False, repr=False)
+class HistoryQuery(betterproto.Message):
+    """
+    Looks up the results history of commands -- every command in the graph if
+    no names are given
+    """
+
+    command_names: List[str] = betterproto.string_field(1)
+
+
 @dataclass(eq=False, repr=False)
 class ClientResp(betterproto.Message):
     """Responses to the client command"""
@@ -69,6 +242,85 @@ class ClientResp(betterproto.Message):
     current_cfg: "ConfigureSmelt" = betterproto.message_field(
         1, group="ClientResponses"
     )
+    query_resp: "QueryResp" = betterproto.message_field(2, group="ClientResponses")
+    exported: "ExportGraphResp" = betterproto.message_field(
+        3, group="ClientResponses"
+    )
+    validation: "SetCommandsResp" = betterproto.message_field(
+        4, group="ClientResponses"
+    )
+    history: "HistoryResp" = betterproto.message_field(5, group="ClientResponses")
+
+
+@dataclass(eq=False, repr=False)
+class ValidationError(betterproto.Message):
+    """A single problem found while validating a graph"""
+
+    kind: "ValidationErrorKind" = betterproto.enum_field(1)
+    command_name: str = betterproto.string_field(2)
+    """The command the problem was found in"""
+
+    field: str = betterproto.string_field(3)
+    """The field of the command holding the problem, e.g. `dependencies`"""
+
+    reference: str = betterproto.string_field(4)
+    """The missing command or file, or the duplicated name or output"""
+
+    cycle: List[str] = betterproto.string_field(5)
+    """
+    Only set for DEPENDENCY_CYCLE -- the commands on the cycle in dependency
+    order, starting and ending with the same command
+    """
+
+    message: str = betterproto.string_field(6)
+    suggestions: List[str] = betterproto.string_field(7)
+    """
+    The closest matching command names or output paths, if a command or file
+    could not be found
+    """
+
+
+@dataclass(eq=False, repr=False)
+class SetCommandsResp(betterproto.Message):
+    """
+    Every problem found in the graph that was set -- if this is empty, the
+    graph is valid
+    """
+
+    errors: List["ValidationError"] = betterproto.message_field(1)
+
+
+@dataclass(eq=False, repr=False)
+class QueryResp(betterproto.Message):
+    command_names: List[str] = betterproto.string_field(1)
+
+
+@dataclass(eq=False, repr=False)
+class ExportGraphResp(betterproto.Message):
+    dot_path: str = betterproto.string_field(1)
+    json_path: str = betterproto.string_field(2)
+
+
+@dataclass(eq=False, repr=False)
+class CommandHistory(betterproto.Message):
+    """Skipped runs do not count towards any of these statistics"""
+
+    command_name: str = betterproto.string_field(1)
+    runs: int = betterproto.uint64_field(2)
+    passes: int = betterproto.uint64_field(3)
+    pass_rate: float = betterproto.double_field(4)
+    last_passing_commit: str = betterproto.string_field(5)
+    """git hash of the most recent passing run -- empty if it never passed"""
+
+    flip_count: int = betterproto.uint64_field(6)
+    """number of times the command went from passing to failing, or back"""
+
+    flaky_score: float = betterproto.double_field(7)
+
+
+@dataclass(eq=False, repr=False)
+class HistoryResp(betterproto.Message):
+    history: List["CommandHistory"] = betterproto.message_field(1)
 
 
 @dataclass(eq=False, repr=False)
@@ -95,10 +347,36 @@ class ConfigureSmelt(betterproto.Message):
     silent: bool = betterproto.bool_field(5)
     """If true, we do not transmit stdout from the server"""
 
+    reports: List["ReportFormat"] = betterproto.enum_field(6)
+    """Reports written next to the Invocation record of every run"""
+
+    retry_failed: int = betterproto.uint32_field(7)
+    """
+    Number of extra times a failed test is rerun, to tell flaky failures apart
+    """
+
+    record_events: bool = betterproto.bool_field(8)
+    """
+    If true, every event of a run is appended to smelt-out/events/<trace_id>.pb,
+    as length delimited protobufs -- they can be replayed later on
+    """
+
+    otlp: "OtlpCfg" = betterproto.message_field(9)
+    """If set, every run is exported as a trace to an OTLP collector"""
+
     local: "CfgLocal" = betterproto.message_field(10, group="InitExecutor")
     docker: "CfgDocker" = betterproto.message_field(11, group="InitExecutor")
 
 
+@dataclass(eq=False, repr=False)
+class OtlpCfg(betterproto.Message):
+    endpoint: str = betterproto.string_field(1)
+    """gRPC endpoint of the collector, e.g. http://localhost:4317"""
+
+    service_name: str = betterproto.string_field(2)
+    """Reported as the service.name of every trace -- "smelt" if empty"""
+
+
 @dataclass(eq=False, repr=False)
 class ProfilerCfg(betterproto.Message):
     prof_type: "ProfilingSelection" = betterproto.enum_field(1)
@@ -5,6 +5,7 @@
 
 from dataclasses import dataclass
 from datetime import datetime
+from typing import List
 
 import betterproto
 
@@ -24,6 +25,37 @@ class SmeltErrorType(betterproto.Enum):
     """
 
 
+class SmeltErrorCode(betterproto.Enum):
+    """
+    Which error of the smelt runtime was raised -- mirrors the variants of
+     `SmeltErr`
+    """
+
+    UNKNOWN = 0
+    DICE_FAIL = 1
+    IO_ERROR = 2
+    SERDE_YAML_ERROR = 3
+    SERDE_JSON_ERROR = 4
+    COMMAND_CACHE_MISS = 5
+    BAD_TARGET_TYPE = 6
+    EXECUTOR_FAILED = 7
+    MISSING_COMMAND_DEPENDENCY = 8
+    MISSING_FILE_DEPENDENCY = 9
+    COMMAND_SETTING_FAILED = 10
+    DUPLICATE_COMMAND_NAME = 11
+    DUPLICATE_OUTPUT = 12
+    MISSING_OUTPUTS = 13
+    BAD_ARTIFACT_NAME = 14
+    INVALID_CONFIG = 15
+    INVALID_QUERY = 16
+    DEPENDENCY_CYCLE = 17
+    INVALID_SELECTOR = 18
+    EMPTY_SELECTION = 19
+    INVALID_SHARD = 20
+    HISTORY_FAILED = 21
+    TRACE_EXPORT_FAILED = 22
+
+
 @dataclass(eq=False, repr=False)
 class Event(betterproto.Message):
     """Event flows from server -> client only"""
@@ -53,6 +85,7 @@ class CommandEvent(betterproto.Message):
     stdout: "CommandStdout" = betterproto.message_field(8, group="CommandVariant")
     profile: "CommandProfile" = betterproto.message_field(9, group="CommandVariant")
     skipped: "CommandSkipped" = betterproto.message_field(10, group="CommandVariant")
+    planned: "CommandPlanned" = betterproto.message_field(11, group="CommandVariant")
 
 
 @dataclass(eq=False, repr=False)
@@ -62,7 +95,11 @@ class CommandScheduled(betterproto.Message):
 
 @dataclass(eq=False, repr=False)
 class CommandStarted(betterproto.Message):
-    pass
+    job_slots: List[int] = betterproto.uint32_field(1)
+    """
+    indices of the job slots the command holds while it runs -- empty if it
+    runs without taking job slots, e.g. in a docker container
+    """
 
 
 @dataclass(eq=False, repr=False)
@@ -75,21 +112,58 @@ class CommandSkipped(betterproto.Message):
     pass
 
 
+@dataclass(eq=False, repr=False)
+class CommandPlanned(betterproto.Message):
+    """Sent instead of running a command during a dry run"""
+
+    script: str = betterproto.string_field(1)
+    """contents of the command.sh that would be executed"""
+
+    num_cpus: int = betterproto.uint32_field(2)
+    max_memory_mb: int = betterproto.uint32_field(3)
+    timeout: int = betterproto.uint32_field(4)
+
+
 @dataclass(eq=False, repr=False)
 class CommandStdout(betterproto.Message):
+    """
+    Stdout of a command, batched -- output holds one or more lines, separated by
+    newlines
+
+    Stdout never holds up a command: if the consumer of the events
+    falls behind, lines are only written to the log of the command.
+    dropped_lines counts the lines that were left out of the event stream since
+    the previous CommandStdout of the command
+    """
+
     output: str = betterproto.string_field(1)
+    dropped_lines: int = betterproto.uint64_field(2)
 
 
 @dataclass(eq=False, repr=False)
 class CommandFinished(betterproto.Message):
     outputs: "_executed_tests__.TestOutputs" = betterproto.message_field(1)
     command_type: str = betterproto.string_field(2)
+    metadata: "_executed_tests__.TestMetaData" = betterproto.message_field(3)
+    flaky_score: float = betterproto.double_field(4)
+    """
+    How often this command has flipped between passing and failing in its
+    recent history, including this run -- 0 is stable, 1 flips every run
+    """
+
+    peaks: "ProfilePeaks" = betterproto.message_field(5)
+    """Peak resource usage of the command over every attempt, if it was profiled"""
 
 
 @dataclass(eq=False, repr=False)
 class CommandProfile(betterproto.Message):
+    """
+    Every value covers the whole process tree of the command -- on linux, that
+    is every descendant of the command, not just its direct children
+    """
+
     memory_used: int = betterproto.uint64_field(1)
-    """memory used by the command, in bytes"""
+    """resident memory used by the command, in bytes"""
 
     cpu_load: float = betterproto.float_field(2)
     """cpu load as a fraction -- 1.0 is one cpu, 2.0 is 2 cpus, etc"""
@@ -97,6 +171,31 @@ class CommandProfile(betterproto.Message):
     time_since_start_ms: int = betterproto.uint64_field(3)
     """Number of milliseconds since profiling began"""
 
+    cpu_time_ms: int = betterproto.uint64_field(4)
+    """user and system cpu time used so far, in milliseconds"""
+
+    io_read_bytes: int = betterproto.uint64_field(5)
+    """bytes read from and written to storage so far"""
+
+    io_write_bytes: int = betterproto.uint64_field(6)
+    num_threads: int = betterproto.uint32_field(7)
+    """number of threads across every process of the command"""
+
+
+@dataclass(eq=False, repr=False)
+class ProfilePeaks(betterproto.Message):
+    """
+    Highest values seen in the CommandProfile samples of a command -- the cpu
+    time and io of reruns add up, as each attempt starts from zero
+    """
+
+    memory_used: int = betterproto.uint64_field(1)
+    cpu_load: float = betterproto.float_field(2)
+    cpu_time_ms: int = betterproto.uint64_field(3)
+    io_read_bytes: int = betterproto.uint64_field(4)
+    io_write_bytes: int = betterproto.uint64_field(5)
+    num_threads: int = betterproto.uint32_field(6)
+
 
 @dataclass(eq=False, repr=False)
 class InvokeEvent(betterproto.Message):
@@ -115,11 +214,24 @@ class ExecutionStart(betterproto.Message):
     git_hash: str = betterproto.string_field(4)
     git_repo: str = betterproto.string_field(5)
     git_branch: str = betterproto.string_field(6)
+    shard_index: int = betterproto.uint32_field(7)
+    """
+    Which shard of the requested commands this invocation runs -- a shard_count
+    of 0 means the invocation is not sharded
+    """
+
+    shard_count: int = betterproto.uint32_field(8)
+    dry_run: bool = betterproto.bool_field(9)
+    """Commands are only planned, never executed, in a dry run"""
 
 
 @dataclass(eq=False, repr=False)
 class AllCommandsDone(betterproto.Message):
-    pass
+    invocation_dir: str = betterproto.string_field(1)
+    """
+    Directory the Invocation record of the run was written to -- empty if
+    nothing was recorded, e.g. for a dry run
+    """
 
 
 @dataclass(eq=False, repr=False)
@@ -131,3 +243,45 @@ class SetGraph(betterproto.Message):
 class SmeltError(betterproto.Message):
     sig: "SmeltErrorType" = betterproto.enum_field(1)
     error_payload: str = betterproto.string_field(2)
+    """The error, formatted for humans"""
+
+    code: "SmeltErrorCode" = betterproto.enum_field(3)
+    command_name: str = betterproto.string_field(4)
+    """
+    The missing or duplicated command, if the error is about a command
+    """
+
+    path: str = betterproto.string_field(5)
+    """The missing or duplicated file, if the error is about a file"""
+
+    reason: str = betterproto.string_field(6)
+    """
+    Why the config, query or selector was rejected, or what the underlying
+     error was for errors that wrap another one
+    """
+
+    selector: str = betterproto.string_field(7)
+    """The selector that was rejected, or did not match any commands"""
+
+    suggestions: List[str] = betterproto.string_field(8)
+    """
+    The closest matching command names or output paths, if a command or file
+     could not be found
+    """
+
+    cycle: List[str] = betterproto.string_field(9)
+    """
+    Only set for DEPENDENCY_CYCLE -- the commands on the cycle in dependency
+     order, starting and ending with the same command
+    """
+
+    missing_outputs: List[str] = betterproto.string_field(10)
+    """Only set for MISSING_OUTPUTS"""
+
+    shard_index: int = betterproto.uint32_field(11)
+    """
+    Only set for INVALID_SHARD -- the shard that was requested, and how many
+     shards there are
+    """
+
+    shard_count: int = betterproto.uint32_field(12)
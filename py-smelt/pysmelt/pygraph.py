@@ -1,24 +1,38 @@
+import os
 import time
+from contextlib import contextmanager
 from dataclasses import dataclass
-from typing import Callable, Generator, List, Optional
+from typing import AsyncGenerator, Callable, Generator, List, Optional, Tuple
 
 import yaml
 from pysmelt.pysmelt import PyController, PyEventStream
+from pysmelt.pysmelt import write_timeline as pysmelt_write_timeline
 
 from pysmelt.interfaces import Command
 from pysmelt.interfaces.paths import SmeltPath, SmeltPathFetcher
 from pysmelt.proto.smelt_client.commands import (
     CfgDocker,
     CfgLocal,
+    CommandHistory,
     ConfigureSmelt,
+    ExportGraph,
+    ExportGraphResp,
+    HistoryQuery,
+    HistoryResp,
     ProfilerCfg,
     ProfilingSelection,
+    Query,
+    QueryResp,
+    QueryType,
+    SetCommandsResp,
+    Shard,
 )
-from pysmelt.proto.smelt_telemetry import Event
+from pysmelt.proto.smelt_telemetry import CommandPlanned, Event
 from pysmelt.rc import SmeltRcHolder
 from pysmelt.smelt_muncher import SmeltUniverse, create_universe
 from pysmelt.subscribers import SmeltSub
-from pysmelt.subscribers.error_handler import SmeltErrorHandler
+from pysmelt.subscribers.dry_run import DryRunPlanner
+from pysmelt.subscribers.error_handler import GraphValidationError, SmeltErrorHandler
 from pysmelt.subscribers.invocation_builder import InvocationBuilder
 from pysmelt.subscribers.output_collector import OutputConsole
 from pysmelt.subscribers.retcode import RetcodeTracker
@@ -81,6 +95,34 @@ def spin_for_message(listener: PyEventStream, backoff: float = 0.2, time_out: in
     raise RuntimeError(f"Before timeout of {time_out} expired!")
 
 
+async def events(listener: PyEventStream) -> AsyncGenerator[Event, None]:
+    """
+    Yields every `Event` of the stream as it arrives, until the stream is closed
+
+    Nothing is polled -- the event loop is free to run other tasks while no event is pending
+    """
+    async for message in listener:
+        yield Event.FromString(message)
+
+
+def replay_events(event_log: str) -> PyEventStream:
+    """
+    Event stream of a run that was recorded with `record_events` set
+
+    Subscribers can't tell it apart from the stream of a live run, so historical runs can be fed to any of them
+    """
+    return PyEventStream.replay(event_log)
+
+
+def write_timeline(event_log: str, output: str) -> None:
+    """
+    Writes a run that was recorded with `record_events` set as Chrome trace-event JSON, which opens in Perfetto
+
+    Every job slot gets a track with the commands that ran in it, and the profile of every command becomes its memory and cpu counter tracks
+    """
+    pysmelt_write_timeline(event_log, output)
+
+
 @dataclass
 class PyGraph:
     universe: SmeltUniverse
@@ -96,25 +138,107 @@ class PyGraph:
 
     additional_listeners: List[SmeltSub]
 
-    def runloop(self, listener: PyEventStream):
+    last_invocation_dir: Optional[str] = None
+    """
+    Directory holding the Invocation record (invocation.pb and invocation.json) of the most recent run
+    """
+
+    last_trace_id: Optional[str] = None
+    """
+    Trace id of the most recent run -- its events are recorded under this id, if `record_events` is set
+    """
+
+    def _fan_out(self, message: Event, subscribers: List[SmeltSub]):
+        """
+        Hands a message to each of the subscribers of a run, and then to every additional listener
+        """
+        for subscriber in subscribers:
+            subscriber.process_message(message)
+        for other_listener in self.additional_listeners:
+            other_listener.process_message(message)
+
+    @contextmanager
+    def _run_subscribers(self) -> Generator[Callable[[Event], None], None, None]:
+        """
+        Yields the handler of each message of a run -- the invocation is written once the run is over
+        """
         errhandler = SmeltErrorHandler()
         invbuilder = InvocationBuilder()
         with OutputConsole() as console:
+            subscribers = [self.retcode_tracker, console, errhandler, invbuilder]
+
+            def process_message(message: Event):
+                self.last_trace_id = message.trace_id
+                self._fan_out(message, subscribers)
+
+            yield process_message
+        invbuilder.write_invocation_and_junit()
+        self.last_invocation_dir = invbuilder.invocation_dir
+
+    def runloop(self, listener: PyEventStream):
+        """
+        Consumes every `Event` of a run by polling for it -- `runloop_async` awaits them instead
+        """
+        with self._run_subscribers() as process_message:
             while not listener.is_done():
-                # tbh, this could be async
-                # but async interopt with rust is kind of experimental and i dont want to do it yet
                 message = maybe_get_message(listener, blocking=False)
                 if message:
-                    self.retcode_tracker.process_message(message)
-                    console.process_message(message)
-                    errhandler.process_message(message)
-                    invbuilder.process_message(message)
-                    for other_listener in self.additional_listeners:
-                        other_listener.process_message(message)
+                    process_message(message)
                 if not message:
                     # add a little bit of backoff
                     time.sleep(0.01)
-        invbuilder.write_invocation_and_junit()
+
+    def planloop(self, listener: PyEventStream) -> List[Tuple[str, CommandPlanned]]:
+        """
+        Consumes every `Event` from a dry run, and returns the planned commands in the order they would execute
+
+        Nothing was executed, so no invocation or junit results are written
+        """
+        errhandler = SmeltErrorHandler()
+        planner = DryRunPlanner()
+        while not listener.is_done():
+            message = maybe_get_message(listener, blocking=False)
+            if message:
+                self._fan_out(message, [errhandler, planner])
+            if not message:
+                time.sleep(0.01)
+        return planner.planned
+
+    def _run_or_plan(
+        self, listener: PyEventStream, dry_run: bool
+    ) -> Optional[List[Tuple[str, CommandPlanned]]]:
+        if dry_run:
+            return self.planloop(listener)
+        self.runloop(listener)
+        return None
+
+    async def runloop_async(self, listener: PyEventStream):
+        """
+        Same as `runloop`, but awaits each event instead of polling for it
+        """
+        with self._run_subscribers() as process_message:
+            async for message in events(listener):
+                process_message(message)
+
+    async def planloop_async(
+        self, listener: PyEventStream
+    ) -> List[Tuple[str, CommandPlanned]]:
+        """
+        Same as `planloop`, but awaits each event instead of polling for it
+        """
+        errhandler = SmeltErrorHandler()
+        planner = DryRunPlanner()
+        async for message in events(listener):
+            self._fan_out(message, [errhandler, planner])
+        return planner.planned
+
+    async def _run_or_plan_async(
+        self, listener: PyEventStream, dry_run: bool
+    ) -> Optional[List[Tuple[str, CommandPlanned]]]:
+        if dry_run:
+            return await self.planloop_async(listener)
+        await self.runloop_async(listener)
+        return None
 
     def console_runloop(
         self, test_name: str, listener: PyEventStream, sink: StdoutSink
@@ -144,10 +268,71 @@ class PyGraph:
                 # add a little bit of backoff
                 yield listener.is_done()
 
+    def event_log(self, trace_id: Optional[str] = None) -> str:
+        """
+        Path to the event log of the run `trace_id` -- the most recent run if no trace id is given
+        """
+        trace_id = trace_id or self.last_trace_id
+        if trace_id is None:
+            raise RuntimeError("No run has happened yet")
+        smelt_root = self.get_current_cfg().smelt_root
+        return os.path.join(smelt_root, "smelt-out", "events", f"{trace_id}.pb")
+
     def get_current_cfg(self) -> ConfigureSmelt:
         raw_cfg = self.controller.get_current_cfg()
         return ConfigureSmelt.FromString(raw_cfg)
 
+    def query(
+        self, query_type: QueryType, command_names: List[str], destination: str = ""
+    ) -> List[str]:
+        """
+        Runs a structural query against the graph -- no commands are executed
+        """
+        query = Query(
+            query_type=query_type, command_names=command_names, destination=destination
+        )
+        raw_resp = self.controller.query(bytes(query))
+        return QueryResp.FromString(raw_resp).command_names
+
+    def query_deps(self, command_names: List[str]) -> List[str]:
+        return self.query(QueryType.DEPS, command_names)
+
+    def query_rdeps(self, command_names: List[str]) -> List[str]:
+        return self.query(QueryType.RDEPS, command_names)
+
+    def query_path(self, source: str, destination: str) -> List[str]:
+        """
+        Returns a dependency path from source to destination, or an empty list if source does not depend on destination
+        """
+        return self.query(QueryType.PATH, [source], destination)
+
+    def query_topo_order(self, command_names: List[str]) -> List[str]:
+        return self.query(QueryType.TOPO_ORDER, command_names)
+
+    def export_graph(
+        self, output_dir: Optional[str] = None, include_status: bool = True
+    ) -> ExportGraphResp:
+        """
+        Writes the graph as Graphviz DOT and as a JSON node/edge list
+
+        By default, the files are written to smelt-out/, and every command is annotated with the status of its latest run
+        """
+        export = ExportGraph(output_dir=output_dir or "", include_status=include_status)
+        raw_resp = self.controller.export_graph(bytes(export))
+        return ExportGraphResp.FromString(raw_resp)
+
+    def command_history(
+        self, command_names: Optional[List[str]] = None
+    ) -> List[CommandHistory]:
+        """
+        Pass rate, last passing commit, flip count and flaky score of each command, from the results history in smelt-out/
+
+        If no names are given, the history of every command in the graph is returned
+        """
+        query = HistoryQuery(command_names=command_names or [])
+        raw_resp = self.controller.query_history(bytes(query))
+        return HistoryResp.FromString(raw_resp).history
+
     def reset(self):
         pass
 
@@ -166,27 +351,112 @@ class PyGraph:
                 return
             time.sleep(0.1)
 
-    def run_specific_commands(self, commands: List[Command]):
+    def run_specific_commands(
+        self,
+        commands: List[Command],
+        shard: Optional[Shard] = None,
+        dry_run: bool = False,
+    ) -> Optional[List[Tuple[str, CommandPlanned]]]:
+        """
+        Runs the given commands
+
+        For every run_* method, setting dry_run walks the graph without executing anything, and returns the name and
+        plan of each command in the order it would execute
+        """
         self.reset()
         test_names = [command.name for command in commands]
-        listener = self.controller.run_many_tests(test_names)
-        self.runloop(listener)
+        listener = self.controller.run_many_tests(
+            test_names, _shard_bytes(shard), dry_run
+        )
+        return self._run_or_plan(listener, dry_run)
 
-    def run_all_typed_commands(self, maybe_type: str):
+    def run_all_typed_commands(
+        self, maybe_type: str, shard: Optional[Shard] = None, dry_run: bool = False
+    ) -> Optional[List[Tuple[str, CommandPlanned]]]:
         self.reset()
-        listener = self.controller.run_all_tests(maybe_type)
-        self.runloop(listener)
+        listener = self.controller.run_all_tests(
+            maybe_type, _shard_bytes(shard), dry_run
+        )
+        return self._run_or_plan(listener, dry_run)
+
+    async def run_all_typed_commands_async(
+        self, maybe_type: str, shard: Optional[Shard] = None, dry_run: bool = False
+    ) -> Optional[List[Tuple[str, CommandPlanned]]]:
+        """
+        Awaitable version of `run_all_typed_commands`
+        """
+        self.reset()
+        listener = await self.controller.run_all_tests_async(
+            maybe_type, _shard_bytes(shard), dry_run
+        )
+        return await self._run_or_plan_async(listener, dry_run)
+
+    def run_selected_commands(
+        self, selector: str, shard: Optional[Shard] = None, dry_run: bool = False
+    ) -> Optional[List[Tuple[str, CommandPlanned]]]:
+        """
+        Runs every command matched by the selector expression
+
+        Selectors combine name globs, `regex:`, `type:` and `tag:` matches with `and`, `or`, `not` and parentheses, e.g.
+        `uart_* and not *_long`. An error is thrown if nothing matches
+        """
+        self.reset()
+        listener = self.controller.run_selected(selector, _shard_bytes(shard), dry_run)
+        return self._run_or_plan(listener, dry_run)
+
+    async def run_selected_commands_async(
+        self, selector: str, shard: Optional[Shard] = None, dry_run: bool = False
+    ) -> Optional[List[Tuple[str, CommandPlanned]]]:
+        """
+        Awaitable version of `run_selected_commands`
+        """
+        self.reset()
+        listener = await self.controller.run_selected_async(
+            selector, _shard_bytes(shard), dry_run
+        )
+        return await self._run_or_plan_async(listener, dry_run)
+
+    def run_all_commands(
+        self, shard: Optional[Shard] = None, dry_run: bool = False
+    ) -> Optional[List[Tuple[str, CommandPlanned]]]:
+        """
+        Runs every top level command
+
+        If a shard is given, only that shard of the commands (and their dependencies) is executed
+        """
+        self.reset()
+        listener = self.controller.run_many_tests(
+            self._top_level_command_names(), _shard_bytes(shard), dry_run
+        )
+        return self._run_or_plan(listener, dry_run)
 
-    def run_all_commands(self):
+    async def run_all_commands_async(
+        self, shard: Optional[Shard] = None, dry_run: bool = False
+    ) -> Optional[List[Tuple[str, CommandPlanned]]]:
+        """
+        Awaitable version of `run_all_commands`
+        """
         self.reset()
+        listener = await self.controller.run_many_tests_async(
+            self._top_level_command_names(), _shard_bytes(shard), dry_run
+        )
+        return await self._run_or_plan_async(listener, dry_run)
+
+    def _top_level_command_names(self) -> List[str]:
         toptests = self.universe.top_level_commands
-        valid_commands = [
+        return [
             command.name
             for command in toptests
             if command.target_type != "rebuild" and command.target_type != "rerun"
         ]
-        listener = self.controller.run_many_tests(valid_commands)
-        self.runloop(listener)
+
+    def plan_one_test(self, name: str) -> List[Tuple[str, CommandPlanned]]:
+        """
+        Dry run of a single test -- returns the plan of the test and every dependency, in the order they would execute
+        """
+        self.reset()
+        listener = self.controller.run_one_test(name, True)
+        return self.planloop(listener)
 
     def set_commands(self):
         """
@@ -194,10 +464,59 @@ class PyGraph:
 
 
         If the list of commands are malformed -- e.g. syntax error in the yaml, then an error will be thrown
+
+        If the graph is invalid, a GraphValidationError holding every problem in the graph is thrown
         """
         commands = self.universe.all_commands
         commands_as_str = yaml.safe_dump([command.to_dict() for command in commands])
-        self.controller.set_graph(commands_as_str)
+        self._raise_validation_errors(self.controller.set_graph(commands_as_str))
+
+    async def set_commands_async(self):
+        """
+        Awaitable version of `set_commands`
+        """
+        commands = self.universe.all_commands
+        commands_as_str = yaml.safe_dump([command.to_dict() for command in commands])
+        self._raise_validation_errors(
+            await self.controller.set_graph_async(commands_as_str)
+        )
+
+    def add_commands(self, commands: List[Command]):
+        """
+        Adds commands to the graph that is already set, without validating the rest of the graph
+
+        Commands added this way are not part of the universe -- run them by name.
+        If a name or output is already taken, nothing is added and a GraphValidationError is thrown
+        """
+        commands_as_str = yaml.safe_dump([command.to_dict() for command in commands])
+        self._raise_validation_errors(self.controller.add_commands(commands_as_str))
+
+    def remove_commands(self, command_names: List[str]):
+        """
+        Removes commands from the graph
+
+        If a command that is kept depends on a removed one, nothing is removed, and the dependents are reported in a GraphValidationError
+        """
+        self._raise_validation_errors(self.controller.remove_commands(command_names))
+
+    def replace_command(self, command: Command):
+        """
+        Replaces the command of the graph that has the same name as `command`
+
+        Every command that is not affected by the new definition keeps its cached results
+        """
+        command_as_str = yaml.safe_dump(command.to_dict())
+        self._raise_validation_errors(self.controller.replace_command(command_as_str))
+
+    def _raise_validation_errors(self, raw_resp: bytes):
+        resp = SetCommandsResp.FromString(raw_resp)
+        if resp.errors:
+            locations = {
+                command.name: str(path)
+                for path, command_list in self.universe.commands.items()
+                for command in command_list
+            }
+            raise GraphValidationError(resp.errors, locations)
 
     @classmethod
     def init(cls, cfg: ConfigureSmelt, universe: SmeltUniverse):
@@ -220,6 +539,10 @@ class PyGraph:
         return cls.init(cfg, universe=universe)
 
 
+def _shard_bytes(shard: Optional[Shard]) -> Optional[bytes]:
+    return bytes(shard) if shard is not None else None
+
+
 def _create_cfg() -> ConfigureSmelt:
     cfg = default_cfg()
     return cfg
@@ -26,12 +26,16 @@ class SerYamlTarget(BaseModel):
     name: str
     rule: str
     rule_args: Dict[str, Any] = {}
+    tags: List[str] = []
+    metadata: Dict[str, str] = {}
 
 
 @dataclass
 class PreTarget:
     target_typ: Type[Target]
     rule_args: Dict[str, Any]
+    tags: List[str]
+    metadata: Dict[str, str]
 
 
 def populate_rule_args(
@@ -49,11 +53,18 @@ def populate_rule_args(
         # Point to the location where end users can create new rules
         raise RuntimeError(f"Rule named {rule_payload.rule} has not been created!")
     target_type = all_rules[rule_payload.rule]["target"]
-    return PreTarget(target_typ=target_type, rule_args=rule_payload.rule_args)
+    return PreTarget(
+        target_typ=target_type,
+        rule_args=rule_payload.rule_args,
+        tags=rule_payload.tags,
+        metadata=rule_payload.metadata,
+    )
 
 
 def to_target(pre_target: PreTarget) -> Target:
-    return pre_target.target_typ(**pre_target.rule_args)
+    target = pre_target.target_typ(**pre_target.rule_args)
+    target.annotate(pre_target.tags, pre_target.metadata)
+    return target
 
 
 def get_targets(
@@ -0,0 +1,31 @@
+from dataclasses import dataclass, field
+from typing import List, Tuple, cast
+import betterproto
+from pysmelt.proto.smelt_telemetry import CommandEvent, CommandPlanned, Event
+
+
+@dataclass
+class DryRunPlanner:
+    """
+    Simple subscriber that collects every command planned by a dry run, in the order they would execute
+    """
+
+    planned: List[Tuple[str, CommandPlanned]] = field(default_factory=list)
+
+    def process_message(self, message: Event):
+        (variant, event_payload) = betterproto.which_one_of(message, "et")
+        if variant == "command":
+            event_payload = cast(CommandEvent, event_payload)
+            (command_name, command_payload) = betterproto.which_one_of(
+                event_payload, "CommandVariant"
+            )
+            if command_name == "planned":
+                command_payload = cast(CommandPlanned, command_payload)
+                self.planned.append((event_payload.command_ref, command_payload))
+            else:
+                pass
+        else:
+            pass
+
+    def reset(self):
+        self.planned = []
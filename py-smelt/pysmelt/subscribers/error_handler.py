@@ -1,7 +1,13 @@
 from dataclasses import dataclass
-from typing import cast
+from typing import Dict, List, Optional, Type, cast
 import betterproto
-from pysmelt.proto.smelt_telemetry import Event, SmeltError, SmeltErrorType
+from pysmelt.proto.smelt_client.commands import ValidationError
+from pysmelt.proto.smelt_telemetry import (
+    Event,
+    SmeltError,
+    SmeltErrorCode,
+    SmeltErrorType,
+)
 
 
 class ClientErr(RuntimeError):
@@ -15,9 +21,250 @@ class ClientErr(RuntimeError):
 class SmeltErrEx(RuntimeError):
     """
     Error that gets thrown when we receive an internal SMELT error
+
+    Every error code of the runtime is thrown as its own subclass, e.g. `MissingCommandDependency`, so that tools can
+    catch the errors they know how to handle -- the fields of the error say which command, file or selector it is about
     """
 
-    pass
+    error: SmeltError
+
+    def __init__(self, message: str, error: Optional[SmeltError] = None):
+        super().__init__(message)
+        self.error = error if error is not None else SmeltError(error_payload=message)
+
+    @property
+    def code(self) -> SmeltErrorCode:
+        return self.error.code
+
+    @property
+    def command_name(self) -> str:
+        return self.error.command_name
+
+    @property
+    def path(self) -> str:
+        return self.error.path
+
+    @property
+    def reason(self) -> str:
+        return self.error.reason
+
+    @property
+    def selector(self) -> str:
+        return self.error.selector
+
+    @property
+    def suggestions(self) -> List[str]:
+        return self.error.suggestions
+
+    @property
+    def cycle(self) -> List[str]:
+        return self.error.cycle
+
+    @property
+    def missing_outputs(self) -> List[str]:
+        return self.error.missing_outputs
+
+    @property
+    def shard_index(self) -> int:
+        return self.error.shard_index
+
+    @property
+    def shard_count(self) -> int:
+        return self.error.shard_count
+
+
+class DiceFail(SmeltErrEx):
+    """
+    The incremental computation engine of the runtime failed
+    """
+
+
+class IoError(SmeltErrEx):
+    """
+    The runtime could not read or write a file
+    """
+
+
+class SerdeYamlError(SmeltErrEx):
+    """
+    A yaml file or message could not be parsed
+    """
+
+
+class SerdeJsonError(SmeltErrEx):
+    """
+    A json file or message could not be parsed
+    """
+
+
+class CommandCacheMiss(SmeltErrEx):
+    """
+    A command was looked up before it was computed
+    """
+
+
+class BadTargetType(SmeltErrEx):
+    """
+    A command was declared with a target type that does not exist
+    """
+
+
+class ExecutorFailed(SmeltErrEx):
+    """
+    The executor could not execute a command
+    """
+
+
+class MissingCommandDependency(SmeltErrEx):
+    """
+    A command was referenced, but never declared -- `command_name` is the missing command
+    """
+
+
+class MissingFileDependency(SmeltErrEx):
+    """
+    A file was depended on, but no command declares it as an output -- `path` is the missing file
+    """
+
+
+class CommandSettingFailed(SmeltErrEx):
+    """
+    The commands of the graph could not be set
+    """
+
+
+class DuplicateCommandName(SmeltErrEx):
+    """
+    Two commands were declared with the same `command_name`
+    """
+
+
+class DuplicateOutput(SmeltErrEx):
+    """
+    Two commands declared the same output -- `path` is the output
+    """
+
+
+class MissingOutputs(SmeltErrEx):
+    """
+    A command did not create every output it declared, which are in `missing_outputs`
+    """
+
+
+class BadArtifactName(SmeltErrEx):
+    """
+    The name of an artifact could not be parsed
+    """
+
+
+class InvalidConfig(SmeltErrEx):
+    """
+    The smelt config was rejected
+    """
+
+
+class InvalidQuery(SmeltErrEx):
+    """
+    A query of the graph was rejected
+    """
+
+
+class DependencyCycle(SmeltErrEx):
+    """
+    The dependencies of the graph form a `cycle`
+    """
+
+
+class InvalidSelector(SmeltErrEx):
+    """
+    A selector could not be parsed
+    """
+
+
+class EmptySelection(SmeltErrEx):
+    """
+    A selector did not match any commands
+    """
+
+
+class InvalidShard(SmeltErrEx):
+    """
+    A shard index was out of range -- `shard_index` is the one that was requested, and
+    `shard_count` the number of shards
+    """
+
+
+class HistoryFailed(SmeltErrEx):
+    """
+    The results history could not be read or written
+    """
+
+
+class TraceExportFailed(SmeltErrEx):
+    """
+    The trace of a run could not be exported
+    """
+
+
+_EXCEPTIONS: Dict[SmeltErrorCode, Type[SmeltErrEx]] = {
+    SmeltErrorCode.DICE_FAIL: DiceFail,
+    SmeltErrorCode.IO_ERROR: IoError,
+    SmeltErrorCode.SERDE_YAML_ERROR: SerdeYamlError,
+    SmeltErrorCode.SERDE_JSON_ERROR: SerdeJsonError,
+    SmeltErrorCode.COMMAND_CACHE_MISS: CommandCacheMiss,
+    SmeltErrorCode.BAD_TARGET_TYPE: BadTargetType,
+    SmeltErrorCode.EXECUTOR_FAILED: ExecutorFailed,
+    SmeltErrorCode.MISSING_COMMAND_DEPENDENCY: MissingCommandDependency,
+    SmeltErrorCode.MISSING_FILE_DEPENDENCY: MissingFileDependency,
+    SmeltErrorCode.COMMAND_SETTING_FAILED: CommandSettingFailed,
+    SmeltErrorCode.DUPLICATE_COMMAND_NAME: DuplicateCommandName,
+    SmeltErrorCode.DUPLICATE_OUTPUT: DuplicateOutput,
+    SmeltErrorCode.MISSING_OUTPUTS: MissingOutputs,
+    SmeltErrorCode.BAD_ARTIFACT_NAME: BadArtifactName,
+    SmeltErrorCode.INVALID_CONFIG: InvalidConfig,
+    SmeltErrorCode.INVALID_QUERY: InvalidQuery,
+    SmeltErrorCode.DEPENDENCY_CYCLE: DependencyCycle,
+    SmeltErrorCode.INVALID_SELECTOR: InvalidSelector,
+    SmeltErrorCode.EMPTY_SELECTION: EmptySelection,
+    SmeltErrorCode.INVALID_SHARD: InvalidShard,
+    SmeltErrorCode.HISTORY_FAILED: HistoryFailed,
+    SmeltErrorCode.TRACE_EXPORT_FAILED: TraceExportFailed,
+}
+
+
+def exception_from_error(error: SmeltError) -> SmeltErrEx:
+    """
+    The exception that is raised for `error`, picked by its code
+    """
+    return _EXCEPTIONS.get(error.code, SmeltErrEx)(error.error_payload, error)
+
+
+def exception_from_bytes(serialized: bytes) -> SmeltErrEx:
+    """
+    The exception that is raised for a serialized `SmeltError` -- this is how errors of the runtime cross into python
+    """
+    return exception_from_error(SmeltError().parse(serialized))
+
+
+class GraphValidationError(RuntimeError):
+    """
+    Error that gets thrown when the graph handed to the smelt runtime is malformed
+
+    Holds every problem that was found, along with the smelt file that each offending command was defined in
+    """
+
+    errors: List[ValidationError]
+    locations: Dict[str, str]
+
+    def __init__(self, errors: List[ValidationError], locations: Dict[str, str]):
+        self.errors = errors
+        self.locations = locations
+        lines = [f"{len(errors)} error(s) found while validating the graph:"]
+        for error in errors:
+            location = locations.get(error.command_name)
+            prefix = f"{location}: " if location else ""
+            lines.append(f"  {prefix}{error.message}")
+        super().__init__("\n".join(lines))
 
 
 @dataclass
@@ -33,6 +280,6 @@ class SmeltErrorHandler:
             if error.sig == SmeltErrorType.CLIENT_ERROR:
                 raise ClientErr(error.error_payload)
             if error.sig == SmeltErrorType.INTERNAL_ERROR:
-                raise SmeltErrEx(error.error_payload)
+                raise exception_from_error(error)
         else:
             pass
@@ -10,6 +10,7 @@ from pysmelt.interfaces.analysis import (
 )
 from pysmelt.interfaces.target import SmeltTargetType
 from pysmelt.proto.smelt_telemetry import (
+    AllCommandsDone,
     CommandEvent,
     CommandFinished,
     CommandStarted,
@@ -46,6 +47,12 @@ class InvocationBuilder:
     branch: Optional[str] = None
     hostname: Optional[str] = None
     smelt_root: Optional[str] = None
+    shard_index: int = 0
+    shard_count: int = 0
+    invocation_dir: Optional[str] = None
+    """
+    Where the smelt runtime wrote its own Invocation record, as protobuf and json
+    """
     tests: List[Tuple[TestResult, str, datetime]] = field(default_factory=list)
     test_start: Dict[str, datetime] = field(default_factory=dict)
 
@@ -68,7 +75,9 @@ class InvocationBuilder:
                 self.tests.append(
                     (
                         TestResult(
-                            test_name=command_name, outputs=command_payload.outputs
+                            test_name=command_name,
+                            outputs=command_payload.outputs,
+                            metadata=command_payload.metadata,
                         ),
                         command_payload.command_type,
                         message.time,
@@ -92,8 +101,12 @@ class InvocationBuilder:
                 self.smelt_root = invoke_payload.smelt_root
                 self.invoke_id = message.trace_id
                 self.user = invoke_payload.username
+                self.shard_index = invoke_payload.shard_index
+                self.shard_count = invoke_payload.shard_count
             if invoke_variant == "done":
                 self.rundate = message.time
+                invoke_payload = cast(AllCommandsDone, invoke_payload)
+                self.invocation_dir = invoke_payload.invocation_dir or None
 
         else:
             pass
@@ -118,6 +131,8 @@ class InvocationBuilder:
             hostname=self.hostname,
             smelt_root=self.smelt_root,
             executed_tests=results,
+            shard_index=self.shard_index,
+            shard_count=self.shard_count,
         )
 
     def create_junit(self) -> JUnitXml:
@@ -126,7 +141,10 @@ class InvocationBuilder:
         assert self.start, "start is required"
         assert self.smelt_root, "smelt_root is required"
 
-        suite = TestSuite(name=self.invoke_id)
+        suite_name = self.invoke_id
+        if self.shard_count:
+            suite_name = f"{suite_name}-shard-{self.shard_index}-of-{self.shard_count}"
+        suite = TestSuite(name=suite_name)
         if self.hostname:
             suite.hostname = self.hostname
         suite_duration = (self.rundate - self.start).total_seconds()
@@ -24,6 +24,7 @@ from pysmelt.proto.smelt_telemetry import (
     CommandStdout,
     Event,
 )
+from pysmelt.proto.executed_tests import TestOutputs, TestStatus
 import betterproto
 from rich.progress import (
     Progress,
@@ -41,6 +42,7 @@ class Status(enum.Enum):
     finished = "finished"
     cancelled = "cancelled"
     skipped = "skipped"
+    planned = "planned"
 
 
 @dataclass
@@ -51,6 +53,16 @@ class StatusObj:
     status: Status
 
 
+def status_label(outputs: TestOutputs) -> str:
+    if outputs.status == TestStatus.FLAKY:
+        return "FLAKY"
+    if outputs.status == TestStatus.PASSED_ON_RETRY:
+        return "PASSED ON RETRY"
+    if outputs.exit_code == 0:
+        return "PASSED"
+    return f"FAILED, code: {outputs.exit_code}"
+
+
 def format_time(total_seconds: float, rich_conformant: bool = False) -> str:
     hours, minutes = divmod(total_seconds, 3600)
     minutes, seconds = divmod(total_seconds, 60)
@@ -262,15 +274,7 @@ class OutputConsole:
             total_seconds = execution_time.total_seconds()
             time_str = format_time(total_seconds)
 
-            table.add_row(
-                command_name,
-                (
-                    "PASSED"
-                    if obj.outputs.exit_code == 0
-                    else f"FAILED, code: {obj.outputs.exit_code}"
-                ),
-                time_str,
-            )
+            table.add_row(command_name, status_label(obj.outputs), time_str)
 
         if len(new_finished_list) > topn:
             unseen = len(new_finished_list) - topn
@@ -346,9 +350,16 @@ class OutputConsole:
             else:
                 if self.progress and self.print_stdout:
                     payload = cast(CommandStdout, payload)
+                    if payload.dropped_lines:
+                        self.progress.print(
+                            f"{name}: {payload.dropped_lines} lines not shown, see the log of the command"
+                        )
                     self.progress.print(payload.output)
 
     def processed_started(self, name: str, time: datetime):
+        if name in self.start_time:
+            # a failed test being rerun -- it still counts as a single command
+            return
         self.total_executing += 1
         self.total_run += 1
         self.start_time[name] = time
@@ -1,11 +1,13 @@
 from collections import defaultdict
 from dataclasses import dataclass, field
-from typing import DefaultDict, List, cast
+from typing import DefaultDict, Dict, List, cast
 import betterproto
 from pysmelt.proto.smelt_telemetry import (
+    CommandFinished,
     CommandProfile,
     CommandEvent,
     Event,
+    ProfilePeaks,
 )
 
 
@@ -18,6 +20,8 @@ class ProfileWatcher:
     profile_events: DefaultDict[str, List[CommandProfile]] = field(
         default_factory=lambda: defaultdict(list)
     )
+    # peak usage of every profiled command, from its CommandFinished event
+    peaks: Dict[str, ProfilePeaks] = field(default_factory=dict)
 
     def process_message(self, message: Event):
         (variant, event_payload) = betterproto.which_one_of(message, "et")
@@ -30,6 +34,10 @@ class ProfileWatcher:
                 profile = cast(CommandProfile, command_payload)
 
                 self.profile_events[event_payload.command_ref].append(profile)
+            elif command_name == "finished":
+                finished = cast(CommandFinished, command_payload)
+                if betterproto.serialized_on_wire(finished.peaks):
+                    self.peaks[event_payload.command_ref] = finished.peaks
             else:
                 pass
         else:
@@ -31,7 +31,12 @@ class StdoutPrinter:
             )
             if command_name == "stdout":
                 command_payload = cast(CommandStdout, command_payload)
-                self.sink(command_payload.output)
+                if command_payload.dropped_lines:
+                    self.sink(
+                        f"({command_payload.dropped_lines} lines not shown, see the log of the command)"
+                    )
+                for line in command_payload.output.splitlines():
+                    self.sink(line)
 
             else:
                 pass
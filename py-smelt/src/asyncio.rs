@@ -0,0 +1,107 @@
+//! Bridges rust futures to asyncio
+//!
+//! Futures are driven by a tokio runtime of their own, and only take the GIL once they are done, to
+//! hand their output to the event loop that awaits them -- python never polls for anything
+
+use std::{future::Future, sync::OnceLock};
+
+use pyo3::prelude::*;
+use tokio::runtime::{Builder, Runtime};
+
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("pysmelt-asyncio")
+            .enable_all()
+            .build()
+            .expect("Could not start the runtime that drives asyncio futures")
+    })
+}
+
+/// What to do with the output of a future if the asyncio future awaiting it was cancelled before it
+/// could take it -- dropped once the output is taken
+pub(crate) type Unclaimed = Box<dyn FnOnce() + Send>;
+
+/// Resolves an asyncio future with the output of a rust future
+///
+/// Asyncio futures are not thread safe, so this is scheduled on their event loop
+#[pyclass]
+struct Resolve {
+    future: PyObject,
+    result: Option<PyObject>,
+    failed: bool,
+    unclaimed: Option<Unclaimed>,
+}
+
+#[pymethods]
+impl Resolve {
+    fn __call__(&mut self, py: Python<'_>) -> PyResult<()> {
+        let Some(result) = self.result.take() else {
+            return Ok(());
+        };
+        let unclaimed = self.unclaimed.take();
+        let future = self.future.bind(py);
+        if future.call_method0("done")?.is_truthy()? {
+            if let Some(unclaimed) = unclaimed {
+                unclaimed();
+            }
+            return Ok(());
+        }
+        let method = if self.failed {
+            "set_exception"
+        } else {
+            "set_result"
+        };
+        future.call_method1(method, (result,))?;
+        Ok(())
+    }
+}
+
+/// Returns an asyncio future of the running event loop, which resolves to the output of `fut`
+///
+/// Must be called from a coroutine, or anything else that runs on an event loop
+pub(crate) fn future_into_py<F>(py: Python<'_>, fut: F) -> PyResult<Bound<'_, PyAny>>
+where
+    F: Future<Output = PyResult<PyObject>> + Send + 'static,
+{
+    claimable_into_py(py, async move { fut.await.map(|val| (val, None)) })
+}
+
+/// Same as `future_into_py`, but a successful output comes along with what to do with it if the
+/// asyncio future was cancelled before it could take it
+pub(crate) fn claimable_into_py<F>(py: Python<'_>, fut: F) -> PyResult<Bound<'_, PyAny>>
+where
+    F: Future<Output = PyResult<(PyObject, Option<Unclaimed>)>> + Send + 'static,
+{
+    let event_loop = py
+        .import_bound("asyncio")?
+        .call_method0("get_running_loop")?;
+    let py_future = event_loop.call_method0("create_future")?;
+
+    let event_loop = event_loop.unbind();
+    let future = py_future.clone().unbind();
+    runtime().spawn(async move {
+        let output = fut.await;
+        // if the event loop was closed, nothing is waiting for the output anymore
+        let _ = Python::with_gil(|py| {
+            let resolve = match output {
+                Ok((val, unclaimed)) => Resolve {
+                    future,
+                    result: Some(val),
+                    failed: false,
+                    unclaimed,
+                },
+                Err(err) => Resolve {
+                    future,
+                    result: Some(err.into_value(py).into_py(py)),
+                    failed: true,
+                    unclaimed: None,
+                },
+            };
+            event_loop.call_method1(py, "call_soon_threadsafe", (resolve,))
+        });
+    });
+    Ok(py_future)
+}
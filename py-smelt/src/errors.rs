@@ -0,0 +1,33 @@
+use prost::Message;
+use pyo3::{exceptions::PyRuntimeError, prelude::*, sync::GILOnceCell, types::PyBytes};
+use smelt_data::SmeltError;
+
+/// `pysmelt.subscribers.error_handler.exception_from_bytes`, imported on the first error
+static EXCEPTION_FROM_BYTES: GILOnceCell<PyObject> = GILOnceCell::new();
+
+/// Turns `error` into the exception that `pysmelt` raises for its code, e.g.
+/// `MissingCommandDependency`
+///
+/// Falls back to a plain RuntimeError if the exceptions of `pysmelt` cannot be imported, with the
+/// reason they could not be as its cause
+pub(crate) fn smelt_error_to_py(error: &SmeltError) -> PyErr {
+    Python::with_gil(|py| {
+        let exception = EXCEPTION_FROM_BYTES
+            .get_or_try_init(py, || {
+                py.import_bound("pysmelt.subscribers.error_handler")?
+                    .getattr("exception_from_bytes")
+                    .map(Bound::unbind)
+            })
+            .and_then(|from_bytes| {
+                from_bytes.call1(py, (PyBytes::new_bound(py, &error.encode_to_vec()),))
+            });
+        match exception {
+            Ok(exception) => PyErr::from_value_bound(exception.into_bound(py)),
+            Err(import_err) => {
+                let fallback = PyRuntimeError::new_err(error.error_payload.clone());
+                fallback.set_cause(py, Some(import_err));
+                fallback
+            }
+        }
+    })
+}
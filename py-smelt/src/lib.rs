@@ -1,7 +1,14 @@
 use smelt_core::SmeltErr;
-use smelt_data::client_commands::{client_resp::ClientResponses, ClientCommand, ClientResp};
-use smelt_data::{client_commands::ConfigureSmelt, Event};
+use smelt_data::client_commands::{
+    client_resp::ClientResponses, ClientCommand, ClientResp, ExportGraph, HistoryQuery, Query,
+    Shard,
+};
+use smelt_data::{client_commands::ConfigureSmelt, Event, SmeltErrorType};
+mod asyncio;
+mod errors;
 mod telemetry;
+use asyncio::{claimable_into_py, future_into_py, Unclaimed};
+use errors::smelt_error_to_py;
 use telemetry::{get_subscriber, init_subscriber};
 
 use std::sync::Once;
@@ -12,19 +19,23 @@ static START: Once = Once::new();
 
 use prost::Message;
 use pyo3::{
-    exceptions::PyRuntimeError,
+    exceptions::{PyRuntimeError, PyStopAsyncIteration},
     prelude::*,
     types::{PyBytes, PyType},
 };
-use smelt_events::{ClientCommandBundle, ClientCommandResp, EventStreams};
+use smelt_events::{
+    event_log::replay_event_log, timeline, ClientCommandBundle, ClientCommandResp, EventStreams,
+};
 use smelt_graph::{spawn_graph_server, SmeltServerHandle};
 
-use std::sync::Arc;
-use tokio::sync::mpsc::{error::TryRecvError, Receiver, UnboundedSender};
+use std::{path::PathBuf, sync::Arc};
+use tokio::sync::{
+    mpsc::{error::TryRecvError, Receiver, UnboundedSender},
+    Mutex,
+};
 
 pub fn arc_err_to_py(smelt_err: Arc<SmeltErr>) -> PyErr {
-    let smelt_string = smelt_err.to_string();
-    PyRuntimeError::new_err(smelt_string)
+    smelt_error_to_py(&smelt_err.to_proto(SmeltErrorType::InternalError))
 }
 
 /// A Python module implemented in Rust.
@@ -32,9 +43,21 @@ pub fn arc_err_to_py(smelt_err: Arc<SmeltErr>) -> PyErr {
 fn pysmelt(_py: Python, m: Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<PyController>()?;
     m.add_class::<PyEventStream>()?;
+    m.add_function(wrap_pyfunction!(write_timeline, &m)?)?;
     Ok(())
 }
 
+/// Writes the run recorded at `event_log` as a Chrome trace-event timeline to `output`
+#[pyfunction]
+fn write_timeline(event_log: PathBuf, output: PathBuf) -> PyResult<()> {
+    timeline::write_timeline(&event_log, &output).map_err(|err| {
+        PyRuntimeError::new_err(format!(
+            "Could not write the timeline of {}: {err}",
+            event_log.display()
+        ))
+    })
+}
+
 #[pyclass]
 pub struct PyController {
     handle: SmeltServerHandle,
@@ -42,15 +65,33 @@ pub struct PyController {
 
 #[pyclass]
 pub struct PyEventStream {
+    /// Shared with the futures returned by `__anext__`, which wait for events on another thread
+    stream: Arc<Mutex<EventStream>>,
+}
+
+struct EventStream {
     recv_chan: Receiver<Event>,
+    /// Event that a cancelled `__anext__` received, which goes to whatever pops the next event
+    pending: Option<Event>,
     done: bool,
+    /// A replayed run is over once every recorded event is popped, even if the run was cut
+    /// short before it was done
+    replayed: bool,
 }
 
 impl PyEventStream {
     pub(crate) fn create_subscriber(recv_chan: Receiver<Event>) -> Self {
-        Self {
+        Self::from_stream(EventStream {
             recv_chan,
+            pending: None,
             done: false,
+            replayed: false,
+        })
+    }
+
+    fn from_stream(stream: EventStream) -> Self {
+        Self {
+            stream: Arc::new(Mutex::new(stream)),
         }
     }
 }
@@ -59,14 +100,16 @@ fn client_channel_err(_in_err: impl std::error::Error) -> PyErr {
     PyRuntimeError::new_err("Channel error trying to send a command to the client")
 }
 
+fn unexpected_resp_err() -> PyErr {
+    PyRuntimeError::new_err("Unexpected response type from the smelt server")
+}
+
 fn handle_client_resp(
     resp: Result<ClientCommandResp, impl std::error::Error>,
 ) -> PyResult<ClientResp> {
     match resp {
         Ok(Ok(client_resp)) => Ok(client_resp),
-        Ok(Err(str)) => Err(PyRuntimeError::new_err(format!(
-            "Client command failed with error {str}"
-        ))),
+        Ok(Err(err)) => Err(smelt_error_to_py(&err)),
         Err(err) => Err(PyRuntimeError::new_err(err.to_string())),
     }
 }
@@ -97,24 +140,157 @@ impl PyController {
         Ok(PyController { handle })
     }
 
-    pub fn set_graph(&self, graph: String) -> PyResult<()> {
-        let EventStreams { sync_chan, .. } =
-            submit_message(&self.handle.tx_client, ClientCommand::send_graph(graph))?;
+    /// Sets the graph, and returns a serialized `SetCommandsResp` holding every validation error
+    pub fn set_graph<'py>(&self, py: Python<'py>, graph: String) -> PyResult<Bound<'py, PyBytes>> {
+        self.edit_graph(py, ClientCommand::send_graph(graph))
+    }
 
-        let resp = sync_chan.blocking_recv();
-        handle_client_resp(resp).map(|_| ())
+    /// Awaitable version of `set_graph`, which does not hold the GIL while the graph is set
+    pub fn set_graph_async<'py>(
+        &self,
+        py: Python<'py>,
+        graph: String,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let command = ClientCommand::send_graph(graph);
+        let EventStreams { sync_chan, .. } = submit_message(&self.handle.tx_client, command)?;
+        future_into_py(py, async move {
+            let resp = handle_client_resp(sync_chan.await)?;
+            Python::with_gil(|py| match resp.client_responses {
+                Some(ClientResponses::Validation(a)) => Ok(to_bytes(a, py).into_py(py)),
+                _ => Err(unexpected_resp_err()),
+            })
+        })
+    }
+
+    /// Adds a YAML list of commands to the graph, and returns a serialized `SetCommandsResp`
+    pub fn add_commands<'py>(
+        &self,
+        py: Python<'py>,
+        commands: String,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        self.edit_graph(py, ClientCommand::add_commands(commands))
+    }
+
+    /// Removes the named commands from the graph, and returns a serialized `SetCommandsResp`
+    pub fn remove_commands<'py>(
+        &self,
+        py: Python<'py>,
+        command_names: Vec<String>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        self.edit_graph(py, ClientCommand::remove_commands(command_names))
+    }
+
+    /// Replaces the command with the same name as the YAML command, and returns a serialized
+    /// `SetCommandsResp`
+    pub fn replace_command<'py>(
+        &self,
+        py: Python<'py>,
+        command: String,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        self.edit_graph(py, ClientCommand::replace_command(command))
+    }
+
+    #[pyo3(signature = (tt, serialized_shard=None, dry_run=false))]
+    pub fn run_all_tests(
+        &self,
+        tt: String,
+        serialized_shard: Option<Vec<u8>>,
+        dry_run: bool,
+    ) -> PyResult<PyEventStream> {
+        self.run_tests(
+            with_shard(ClientCommand::execute_type(tt), serialized_shard)?.with_dry_run(dry_run),
+        )
+    }
+
+    #[pyo3(signature = (test, dry_run=false))]
+    pub fn run_one_test(&self, test: String, dry_run: bool) -> PyResult<PyEventStream> {
+        self.run_tests(ClientCommand::execute_command(test).with_dry_run(dry_run))
     }
 
-    pub fn run_all_tests(&self, tt: String) -> PyResult<PyEventStream> {
-        self.run_tests(ClientCommand::execute_type(tt))
+    #[pyo3(signature = (tests, serialized_shard=None, dry_run=false))]
+    pub fn run_many_tests(
+        &self,
+        tests: Vec<String>,
+        serialized_shard: Option<Vec<u8>>,
+        dry_run: bool,
+    ) -> PyResult<PyEventStream> {
+        self.run_tests(
+            with_shard(ClientCommand::execute_many(tests), serialized_shard)?.with_dry_run(dry_run),
+        )
     }
 
-    pub fn run_one_test(&self, test: String) -> PyResult<PyEventStream> {
-        self.run_tests(ClientCommand::execute_command(test))
+    #[pyo3(signature = (selector, serialized_shard=None, dry_run=false))]
+    pub fn run_selected(
+        &self,
+        selector: String,
+        serialized_shard: Option<Vec<u8>>,
+        dry_run: bool,
+    ) -> PyResult<PyEventStream> {
+        self.run_tests(
+            with_shard(ClientCommand::execute_selected(selector), serialized_shard)?
+                .with_dry_run(dry_run),
+        )
     }
 
-    pub fn run_many_tests(&self, tests: Vec<String>) -> PyResult<PyEventStream> {
-        self.run_tests(ClientCommand::execute_many(tests))
+    /// Awaitable version of `run_all_tests`, which resolves to the event stream of the run once
+    /// the runtime has started it -- if the run can't be started, its error is raised instead
+    #[pyo3(signature = (tt, serialized_shard=None, dry_run=false))]
+    pub fn run_all_tests_async<'py>(
+        &self,
+        py: Python<'py>,
+        tt: String,
+        serialized_shard: Option<Vec<u8>>,
+        dry_run: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        self.run_tests_async(
+            py,
+            with_shard(ClientCommand::execute_type(tt), serialized_shard)?.with_dry_run(dry_run),
+        )
+    }
+
+    /// Awaitable version of `run_one_test`, see `run_all_tests_async`
+    #[pyo3(signature = (test, dry_run=false))]
+    pub fn run_one_test_async<'py>(
+        &self,
+        py: Python<'py>,
+        test: String,
+        dry_run: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        self.run_tests_async(
+            py,
+            ClientCommand::execute_command(test).with_dry_run(dry_run),
+        )
+    }
+
+    /// Awaitable version of `run_many_tests`, see `run_all_tests_async`
+    #[pyo3(signature = (tests, serialized_shard=None, dry_run=false))]
+    pub fn run_many_tests_async<'py>(
+        &self,
+        py: Python<'py>,
+        tests: Vec<String>,
+        serialized_shard: Option<Vec<u8>>,
+        dry_run: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        self.run_tests_async(
+            py,
+            with_shard(ClientCommand::execute_many(tests), serialized_shard)?.with_dry_run(dry_run),
+        )
+    }
+
+    /// Awaitable version of `run_selected`, see `run_all_tests_async`
+    #[pyo3(signature = (selector, serialized_shard=None, dry_run=false))]
+    pub fn run_selected_async<'py>(
+        &self,
+        py: Python<'py>,
+        selector: String,
+        serialized_shard: Option<Vec<u8>>,
+        dry_run: bool,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        self.run_tests_async(
+            py,
+            with_shard(ClientCommand::execute_selected(selector), serialized_shard)?
+                .with_dry_run(dry_run),
+        )
     }
 
     pub fn get_current_cfg<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
@@ -122,18 +298,121 @@ impl PyController {
         let EventStreams { sync_chan, .. } =
             submit_message(&self.handle.tx_client, command).map_err(client_channel_err)?;
         let resp = sync_chan.blocking_recv();
-        handle_client_resp(resp).map(|val| match val.client_responses.unwrap() {
-            ClientResponses::CurrentCfg(a) => to_bytes(a, py),
+        handle_client_resp(resp).and_then(|val| match val.client_responses {
+            Some(ClientResponses::CurrentCfg(a)) => Ok(to_bytes(a, py)),
+            _ => Err(unexpected_resp_err()),
+        })
+    }
+
+    /// Exports the current graph as DOT and JSON, based on a serialized `ExportGraph` message
+    ///
+    /// Returns a serialized `ExportGraphResp`, which holds the paths of the written files
+    pub fn export_graph<'py>(
+        &self,
+        py: Python<'py>,
+        serialized_export: Vec<u8>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let export = ExportGraph::decode(serialized_export.as_slice())
+            .map_err(|err| PyRuntimeError::new_err(format!("Malformed export message: {err}")))?;
+        let command = ClientCommand::export_graph(export.output_dir, export.include_status);
+        let EventStreams { sync_chan, .. } = submit_message(&self.handle.tx_client, command)?;
+        let resp = sync_chan.blocking_recv();
+        handle_client_resp(resp).and_then(|val| match val.client_responses {
+            Some(ClientResponses::Exported(a)) => Ok(to_bytes(a, py)),
+            _ => Err(unexpected_resp_err()),
+        })
+    }
+
+    /// Runs a serialized `Query` against the current graph, and returns a serialized `QueryResp`
+    pub fn query<'py>(
+        &self,
+        py: Python<'py>,
+        serialized_query: Vec<u8>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let query = Query::decode(serialized_query.as_slice())
+            .map_err(|err| PyRuntimeError::new_err(format!("Malformed query message: {err}")))?;
+        let command =
+            ClientCommand::query(query.query_type(), query.command_names, query.destination);
+        let EventStreams { sync_chan, .. } = submit_message(&self.handle.tx_client, command)?;
+        let resp = sync_chan.blocking_recv();
+        handle_client_resp(resp).and_then(|val| match val.client_responses {
+            Some(ClientResponses::QueryResp(a)) => Ok(to_bytes(a, py)),
+            _ => Err(unexpected_resp_err()),
+        })
+    }
+
+    /// Looks up the results history of a serialized `HistoryQuery`, and returns a serialized
+    /// `HistoryResp`
+    pub fn query_history<'py>(
+        &self,
+        py: Python<'py>,
+        serialized_query: Vec<u8>,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let query = HistoryQuery::decode(serialized_query.as_slice()).map_err(|err| {
+            PyRuntimeError::new_err(format!("Malformed history query message: {err}"))
+        })?;
+        let command = ClientCommand::history(query.command_names);
+        let EventStreams { sync_chan, .. } = submit_message(&self.handle.tx_client, command)?;
+        let resp = sync_chan.blocking_recv();
+        handle_client_resp(resp).and_then(|val| match val.client_responses {
+            Some(ClientResponses::History(a)) => Ok(to_bytes(a, py)),
+            _ => Err(unexpected_resp_err()),
         })
     }
 }
 
 impl PyController {
+    fn edit_graph<'py>(
+        &self,
+        py: Python<'py>,
+        command: ClientCommand,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let EventStreams { sync_chan, .. } = submit_message(&self.handle.tx_client, command)?;
+
+        let resp = sync_chan.blocking_recv();
+        handle_client_resp(resp).and_then(|val| match val.client_responses {
+            Some(ClientResponses::Validation(a)) => Ok(to_bytes(a, py)),
+            _ => Err(unexpected_resp_err()),
+        })
+    }
+
     fn run_tests(&self, command: ClientCommand) -> PyResult<PyEventStream> {
         let EventStreams { event_stream, .. } =
             submit_message(&self.handle.tx_client, command).map_err(client_channel_err)?;
         Ok(PyEventStream::create_subscriber(event_stream))
     }
+
+    fn run_tests_async<'py>(
+        &self,
+        py: Python<'py>,
+        command: ClientCommand,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let EventStreams {
+            sync_chan,
+            event_stream,
+        } = submit_message(&self.handle.tx_client, command)?;
+        future_into_py(py, async move {
+            handle_client_resp(sync_chan.await)?;
+            let listener = PyEventStream::create_subscriber(event_stream);
+            Ok(Python::with_gil(|py| listener.into_py(py)))
+        })
+    }
+}
+
+/// Restricts a run request to the serialized `Shard`, if one was given
+fn with_shard(
+    command: ClientCommand,
+    serialized_shard: Option<Vec<u8>>,
+) -> PyResult<ClientCommand> {
+    match serialized_shard {
+        Some(bytes) => {
+            let shard = Shard::decode(bytes.as_slice()).map_err(|err| {
+                PyRuntimeError::new_err(format!("Malformed shard message: {err}"))
+            })?;
+            Ok(command.with_shard(shard))
+        }
+        None => Ok(command),
+    }
 }
 
 #[inline]
@@ -145,14 +424,37 @@ fn to_bytes<M: Message>(message: M, py: Python<'_>) -> Bound<'_, PyBytes> {
 
 #[pymethods]
 impl PyEventStream {
-    pub fn pop_message_blocking<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
-        let val = self
-            .recv_chan
-            .blocking_recv()
-            .ok_or_else(|| PyRuntimeError::new_err("Event channel closed"))?;
-        self.set_done(&val);
+    /// Replays the events of a run that was recorded to an event log
+    #[staticmethod]
+    pub fn replay(event_log: PathBuf) -> PyResult<Self> {
+        let recv_chan = replay_event_log(&event_log).map_err(|err| {
+            PyRuntimeError::new_err(format!(
+                "Could not replay the event log at {}: {err}",
+                event_log.display()
+            ))
+        })?;
+        Ok(Self::from_stream(EventStream {
+            recv_chan,
+            pending: None,
+            done: false,
+            replayed: true,
+        }))
+    }
 
-        let val = val.encode_to_vec();
+    pub fn pop_message_blocking<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        // a pending `__anext__` holds the stream until it gets the GIL back
+        let val = py.allow_threads(|| {
+            let mut stream = self.stream.blocking_lock();
+            let val = match stream.pending.take() {
+                Some(val) => val,
+                None => stream
+                    .recv_chan
+                    .blocking_recv()
+                    .ok_or_else(|| PyRuntimeError::new_err("Event channel closed"))?,
+            };
+            stream.set_done(&val);
+            PyResult::Ok(val.encode_to_vec())
+        })?;
 
         Ok(PyBytes::new_bound(py, &val))
     }
@@ -160,11 +462,18 @@ impl PyEventStream {
         &mut self,
         py: Python<'py>,
     ) -> PyResult<Option<Bound<'py, PyBytes>>> {
-        let val = self.recv_chan.try_recv();
+        // an `__anext__` is already waiting for the next event
+        let Ok(mut stream) = self.stream.try_lock() else {
+            return Ok(None);
+        };
+        let val = match stream.pending.take() {
+            Some(val) => Ok(val),
+            None => stream.recv_chan.try_recv(),
+        };
 
         match val {
             Ok(val) => {
-                self.set_done(&val);
+                stream.set_done(&val);
                 let val = val.encode_to_vec();
 
                 Ok(Some(PyBytes::new_bound(py, &val)))
@@ -177,11 +486,50 @@ impl PyEventStream {
     /// Returns true if we've seen a entire Invocation complete end to end AND the channel has
     /// been closed
     pub fn is_done(&mut self, _py: Python<'_>) -> bool {
-        self.done && self.recv_chan.is_closed()
+        let Ok(stream) = self.stream.try_lock() else {
+            return false;
+        };
+        if stream.pending.is_some() {
+            return false;
+        }
+        if stream.replayed {
+            return stream.recv_chan.is_empty();
+        }
+        stream.done && stream.recv_chan.is_closed()
+    }
+
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    /// Awaits the next serialized `Event`, without holding the GIL while it waits
+    ///
+    /// Iteration stops once the channel is closed, i.e. after the last event of the run. If an
+    /// `__anext__` is cancelled, the event it received is kept for the next one -- the stream stays
+    /// locked until the event is either taken or kept, so events are never reordered
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let stream = self.stream.clone();
+        claimable_into_py(py, async move {
+            let mut stream = stream.lock_owned().await;
+            let val = match stream.pending.take() {
+                Some(val) => val,
+                None => stream
+                    .recv_chan
+                    .recv()
+                    .await
+                    .ok_or_else(|| PyStopAsyncIteration::new_err(()))?,
+            };
+            stream.set_done(&val);
+
+            let bytes = val.encode_to_vec();
+            let bytes = Python::with_gil(|py| PyBytes::new_bound(py, &bytes).into_py(py));
+            let unclaimed: Unclaimed = Box::new(move || stream.pending = Some(val));
+            Ok((bytes, Some(unclaimed)))
+        })
     }
 }
 
-impl PyEventStream {
+impl EventStream {
     fn set_done(&mut self, event: &Event) {
         if event.finished_event() {
             self.done = true;
@@ -1,7 +1,9 @@
+import json
 import math
 import subprocess
+import sys
 from tempfile import NamedTemporaryFile, TemporaryDirectory
-from typing import Generator
+from typing import Generator, List
 
 import betterproto
 import pytest
@@ -20,7 +22,7 @@ from pysmelt.proto.smelt_client.commands import (
 from pysmelt.proto.smelt_telemetry import Event
 from pysmelt.pygraph import PyGraph, create_graph, create_graph_with_docker
 from pysmelt.subscribers import SmeltSub
-from pytests.common import MockRemoteSmeltFileStorage
+from pytests.common import MockRemoteSmeltFileStorage, create_command_list_graph
 
 
 @pytest.fixture(scope="session")
@@ -30,6 +32,29 @@ def simple_docker_image() -> Generator[str, None, None]:
     yield img
 
 
+def command(name: str, deps: List[str], script: List[str]) -> Command:
+    return Command.from_dict(
+        {
+            "name": name,
+            "target_type": "test",
+            "script": script,
+            "dependencies": deps,
+            "outputs": [],
+            "runtime": {
+                "num_cpus": 1,
+                "max_memory_mb": 1024,
+                "timeout": 600,
+                "env": {},
+            },
+        }
+    )
+
+
+def with_recording(cfg: ConfigureSmelt) -> ConfigureSmelt:
+    cfg.record_events = True
+    return cfg
+
+
 def test_sanity_pygraph():
     test_list = f"{get_git_root()}/test_data/command_lists/cl1.yaml"
     with open(test_list) as f:
@@ -298,6 +323,35 @@ def test_profiler():
     # ), "We expect that the more memory test takes about ~4x more memory than the baseline -- we set a lower bound of 2.5x mem to be safe"
 
 
+@pytest.mark.skipif(sys.platform != "linux", reason="process trees are walked on linux")
+def test_profiler_sees_process_tree():
+    """
+    The memory of a process that is started two wrappers down counts towards the command
+    """
+    from pysmelt.subscribers.simple_profiler import ProfileWatcher
+
+    test_list = f"{get_git_root()}/test_data/smelt_files/nested_profile.smelt.yaml"
+
+    def init_sampler(cfg: ConfigureSmelt) -> ConfigureSmelt:
+        cfg.prof_cfg = ProfilerCfg(
+            prof_type=ProfilingSelection.SIMPLE_PROF, sampling_period=100
+        )
+        return cfg
+
+    profiler = ProfileWatcher()
+    graph = create_graph(test_list, init_sampler)
+    graph.additional_listeners.append(profiler)
+    graph.run_all_typed_commands("test")
+
+    samples = profiler.profile_events["nested_high_mem_usage"]
+    assert samples
+    assert max(sample.num_threads for sample in samples) >= 3
+    peaks = profiler.peaks["nested_high_mem_usage"]
+    # the list alone takes 80MB
+    assert peaks.memory_used > 80_000_000
+    assert peaks.memory_used == max(sample.memory_used for sample in samples)
+
+
 def test_split_build():
     """
     Tests the case where no re-run is needed
@@ -328,4 +382,519 @@ def test_sanity_procedural():
     ), f"Expected to see {expected_tests} tasks executed, saw {observed_reexec} tests"
 
 
+def test_query_graph():
+    graph = create_command_list_graph("cl3.yaml")
+
+    assert graph.query_deps(["test"]) == ["sim", "sim2", "sim3", "stim"]
+    assert graph.query_rdeps(["sim"]) == ["sim3", "test"]
+    assert graph.query_path("test", "sim") == ["test", "sim3", "sim"]
+    assert graph.query_path("stim", "sim") == []
+    assert graph.query_topo_order(["test"]) == ["sim", "sim2", "sim3", "stim", "test"]
+
+    with pytest.raises(RuntimeError):
+        graph.query_deps(["not_a_command"])
+
+    with pytest.raises(RuntimeError, match="did you mean test"):
+        graph.query_deps(["tset"])
+
+
+def test_run_selected():
+    graph = create_command_list_graph("cl3.yaml")
+    graph.run_selected_commands("sim* and not (sim3 or regex:2$)")
+    assert graph.retcode_tracker.total_executed() == 1
+
+    with pytest.raises(RuntimeError, match="did not match any commands"):
+        graph.run_selected_commands("type:test and stim")
+
+
+def test_typed_errors():
+    from pysmelt.subscribers.error_handler import (
+        EmptySelection,
+        MissingCommandDependency,
+        SmeltErrEx,
+    )
+    from pysmelt.proto.smelt_telemetry import SmeltErrorCode
+
+    graph = create_command_list_graph("cl3.yaml")
+    with pytest.raises(MissingCommandDependency) as e_info:
+        graph.query_deps(["tset"])
+    assert e_info.value.code == SmeltErrorCode.MISSING_COMMAND_DEPENDENCY
+    assert e_info.value.command_name == "tset"
+    assert e_info.value.suggestions == ["test"]
+
+    with pytest.raises(EmptySelection) as e_info:
+        graph.run_selected_commands("type:test and stim")
+    assert e_info.value.selector == "type:test and stim"
+    assert isinstance(e_info.value, SmeltErrEx)
+
+
+def test_every_error_code_has_an_exception():
+    from pysmelt.subscribers.error_handler import _EXCEPTIONS, SmeltErrEx
+    from pysmelt.proto.smelt_telemetry import SmeltErrorCode
+
+    for code in SmeltErrorCode:
+        if code == SmeltErrorCode.UNKNOWN:
+            continue
+        assert code in _EXCEPTIONS, f"{code.name} has no exception"
+        assert issubclass(_EXCEPTIONS[code], SmeltErrEx)
+        assert _EXCEPTIONS[code] is not SmeltErrEx
+
+
+def test_tags_and_metadata():
+    from pysmelt.interfaces.analysis import IQL
+
+    test_list = f"{get_git_root()}/test_data/smelt_files/tagged.smelt.yaml"
+    graph = create_graph(test_list)
+    graph.run_selected_commands("tag:smoke or meta:seed=4*")
+    assert graph.retcode_tracker.total_executed() == 3
+
+    iql = IQL.from_previous()
+    by_dut = iql.group_by_metadata("dut")
+    assert sorted(test.test_name for test in by_dut["uart"]) == [
+        "uart_long",
+        "uart_smoke",
+    ]
+    assert [test.test_name for test in iql.group_by_metadata("owner")["asmith"]] == [
+        "spi_smoke"
+    ]
+    assert [test.test_name for test in iql.group_by_metadata("seed")["42"]] == [
+        "uart_long"
+    ]
+    assert len(iql.group_by_tag()["smoke"]) == 2
+
+
+def test_sharded_runs_cover_every_test():
+    from pysmelt.interfaces.analysis import IQL
+    from pysmelt.proto.smelt_client.commands import Shard
+    from pysmelt.subscribers.error_handler import InvalidShard
+
+    test_list = f"{get_git_root()}/test_data/smelt_files/tagged.smelt.yaml"
+    executed = []
+    for shard_index in range(2):
+        graph = create_graph(test_list)
+        graph.run_all_typed_commands("test", Shard(shard_index=shard_index, shard_count=2))
+        inv = IQL.from_previous().inv
+        assert (inv.shard_index, inv.shard_count) == (shard_index, 2)
+        executed.extend(test.test_name for test in inv.executed_tests)
+
+    assert sorted(executed) == ["spi_smoke", "uart_long", "uart_smoke"]
+
+    graph = create_graph(test_list)
+    with pytest.raises(InvalidShard, match="Invalid shard") as e_info:
+        graph.run_all_typed_commands("test", Shard(shard_index=2, shard_count=2))
+    assert (e_info.value.shard_index, e_info.value.shard_count) == (2, 2)
+
+
+def test_export_graph():
+    import json
+
+    graph = create_command_list_graph("cl3.yaml")
+    graph.run_all_commands()
+    with TemporaryDirectory() as dir_name:
+        resp = graph.export_graph(dir_name)
+        with open(resp.json_path) as f:
+            exported = json.load(f)
+        with open(resp.dot_path) as f:
+            dot = f.read()
+
+    statuses = {node["name"]: node.get("status") for node in exported["nodes"]}
+    assert statuses["test"] == "passed"
+    assert {"from": "sim3", "to": "sim", "kind": "command"} in exported["edges"]
+    assert dot.startswith("digraph smelt {")
+
+
+def test_dry_run():
+    graph = create_command_list_graph("cl2.yaml")
+    plan = graph.run_all_typed_commands("test", dry_run=True)
+    assert [name for name, _ in plan] == ["stim", "test"]
+    _, planned_test = plan[1]
+    assert "export TARGET_ROOT=" in planned_test.script
+    assert planned_test.script.endswith("cat myout\n")
+    assert planned_test.num_cpus == 1 and planned_test.max_memory_mb == 1024
+    assert graph.retcode_tracker.total_executed() == 0
+
+    # nothing planned by the dry run is reused by a real run
+    graph.run_all_typed_commands("test")
+    assert graph.retcode_tracker.total_executed() == 2
+
+    # and nothing executed by the real run is reused by a dry run
+    plan = graph.run_all_typed_commands("test", dry_run=True)
+    assert [name for name, _ in plan] == ["stim", "test"]
+    assert graph.retcode_tracker.total_executed() == 0
+
+
+def test_invocation_record():
+    import json
+    import os
+
+    from pysmelt.proto.executed_tests import Invocation
+
+    graph = create_command_list_graph("cl3.yaml")
+    graph.run_all_typed_commands("test")
+    inv_dir = graph.last_invocation_dir
+    assert inv_dir is not None
+
+    with open(os.path.join(inv_dir, "invocation.pb"), "rb") as f:
+        invocation = Invocation.FromString(f.read())
+    assert os.path.basename(inv_dir) == invocation.invoke_id
+    assert len(invocation.executed_tests) == graph.retcode_tracker.total_executed()
+
+    with open(os.path.join(inv_dir, "invocation.json")) as f:
+        as_json = json.load(f)
+    assert as_json["invoke_id"] == invocation.invoke_id
+
+
+def test_resource_usage_recorded():
+    import os
+
+    from pysmelt.proto.executed_tests import Invocation
+
+    test_list = f"{get_git_root()}/test_data/smelt_files/nested_profile.smelt.yaml"
+    graph = create_graph(test_list)
+    graph.run_all_typed_commands("test")
+
+    with open(os.path.join(graph.last_invocation_dir, "invocation.pb"), "rb") as f:
+        invocation = Invocation.FromString(f.read())
+    (test,) = invocation.executed_tests
+    usage = test.outputs.usage
+    assert usage.hostname
+    assert usage.container == ""
+    assert usage.finished_at > usage.started_at
+    # the list is allocated two wrappers down, and still counts towards the command
+    assert usage.peak_rss_bytes > 80_000_000
+    assert usage.user_cpu_ms + usage.system_cpu_ms > 0
+
+
+def test_junit_and_tap_reports():
+    import os
+    from xml.etree import ElementTree
+
+    from pysmelt.proto.smelt_client.commands import ReportFormat
+
+    def with_reports(cfg: ConfigureSmelt) -> ConfigureSmelt:
+        cfg.reports = [ReportFormat.JUNIT_XML, ReportFormat.TAP]
+        return cfg
+
+    test_list = f"{get_git_root()}/test_data/smelt_files/failing_tests_only.smelt.yaml"
+    graph = create_graph(test_list, cfg_init=with_reports)
+    graph.run_all_commands()
+    inv_dir = graph.last_invocation_dir
+    assert inv_dir is not None
+
+    suite = ElementTree.parse(os.path.join(inv_dir, "junit.xml")).find("testsuite")
+    cases = {case.get("name"): case for case in suite.iter("testcase")}
+    failure = cases["fail_test_example_1"].find("failure")
+    assert failure is not None and "test1" in failure.text
+    assert int(suite.get("tests")) == graph.retcode_tracker.total_executed()
+
+    with open(os.path.join(inv_dir, "results.tap")) as f:
+        tap = f.read().splitlines()
+    assert tap[0] == "TAP version 13"
+    assert any(line.endswith("- fail_test_example_1") for line in tap)
+
+
+def test_results_history():
+    test_list = f"{get_git_root()}/test_data/smelt_files/failing_tests_only.smelt.yaml"
+    graph = create_graph(test_list)
+    graph.run_all_commands()
+    graph.run_all_commands()
+
+    (entry,) = graph.command_history(["fail_test_example_1"])
+    assert entry.command_name == "fail_test_example_1"
+    assert entry.runs >= 2
+    assert entry.passes == 0 and entry.pass_rate == 0
+    assert entry.last_passing_commit == ""
+    assert entry.flaky_score == 0
+
+    names = [entry.command_name for entry in graph.command_history()]
+    assert "fail_test_example_1" in names
+
+    with pytest.raises(RuntimeError):
+        graph.command_history(["not_a_real_command"])
+
+
+def test_rerun_failed_tests():
+    import os
+
+    from pysmelt.proto.executed_tests import Invocation, TestStatus
+
+    def with_retries(cfg: ConfigureSmelt) -> ConfigureSmelt:
+        cfg.retry_failed = 2
+        return cfg
+
+    with TemporaryDirectory() as state, NamedTemporaryFile("w+") as tmp_file:
+        # every command appends to its own attempt counter -- the alternating command
+        # only passes on even attempts, the next one on every attempt but the first, and
+        # the last two never pass
+        tmp_file.write(
+            f"""
+- name: alternating_test
+  rule: raw_bash
+  rule_args:
+    cmds:
+      - echo attempt >> {state}/alternating
+      - '[[ $(( $(wc -l < {state}/alternating) % 2 )) == 0 ]]'
+- name: fails_once_test
+  rule: raw_bash
+  rule_args:
+    cmds:
+      - echo attempt >> {state}/fails_once
+      - '[[ $(wc -l < {state}/fails_once) != 1 ]]'
+- name: always_fails_test
+  rule: raw_bash
+  rule_args:
+    cmds:
+      - echo attempt >> {state}/always_fails
+      - exit 1
+- name: fails_build
+  rule: raw_bash_build
+  rule_args:
+    cmds:
+      - echo attempt >> {state}/fails_build
+      - exit 1
+"""
+        )
+        tmp_file.flush()
+        graph = create_graph(tmp_file.name, cfg_init=with_retries)
+        graph.run_all_commands()
+        inv_dir = graph.last_invocation_dir
+        assert inv_dir is not None
+        with open(os.path.join(inv_dir, "invocation.pb"), "rb") as f:
+            invocation = Invocation.FromString(f.read())
+        outputs = {test.test_name: test.outputs for test in invocation.executed_tests}
+
+        def attempts(name: str) -> int:
+            with open(os.path.join(state, name)) as f:
+                return len(f.readlines())
+
+        # fail, pass, fail -- the reruns disagree, so the third attempt is the last one
+        assert outputs["alternating_test"].status == TestStatus.FLAKY
+        assert outputs["alternating_test"].exit_code != 0
+        assert attempts("alternating") == 3
+
+        assert outputs["fails_once_test"].status == TestStatus.PASSED_ON_RETRY
+        assert attempts("fails_once") == 3
+
+        assert outputs["always_fails_test"].status == TestStatus.FAILED
+        assert outputs["always_fails_test"].exit_code == 1
+        assert attempts("always_fails") == 3
+
+        attempt_logs = [
+            artifact.artifact_name
+            for artifact in outputs["always_fails_test"].artifacts
+            if artifact.artifact_name.startswith("smelt_log_attempt_")
+        ]
+        assert attempt_logs == [f"smelt_log_attempt_{n}" for n in range(1, 4)]
+
+        # only tests are rerun
+        assert outputs["fails_build"].status == TestStatus.FAILED
+        assert attempts("fails_build") == 1
+
+
+def test_concurrent_invocations():
+    from pysmelt.pygraph import maybe_get_message
+
+    graph = PyGraph.init_commands_only(
+        [
+            command("shared_build", [], ["sleep 1"]),
+            command("test_a", ["shared_build"], ["echo a"]),
+            command("test_b", ["shared_build"], ["echo b"]),
+        ]
+    )
+    listeners = [
+        graph.controller.run_one_test("test_a"),
+        graph.controller.run_one_test("test_b"),
+    ]
+    events: List[List[Event]] = [[], []]
+    while not all(listener.is_done() for listener in listeners):
+        for listener, received in zip(listeners, events):
+            message = maybe_get_message(listener)
+            if message:
+                received.append(message)
+
+    # each invocation gets its own trace, and its own completion
+    traces = [{event.trace_id for event in received} for received in events]
+    assert all(len(trace) == 1 for trace in traces)
+    assert traces[0] != traces[1]
+    for received in events:
+        assert betterproto.which_one_of(received[-1].invoke, "InvokeVariant")[0] == "done"
+
+    # the shared dependency was in flight for both invocations, but only executed once
+    def started(received: List[Event], name: str) -> int:
+        return sum(
+            1
+            for event in received
+            if betterproto.which_one_of(event, "et")[0] == "command"
+            and event.command.command_ref == name
+            and betterproto.which_one_of(event.command, "CommandVariant")[0] == "started"
+        )
+
+    assert started(events[0], "shared_build") + started(events[1], "shared_build") == 1
+    assert started(events[0], "test_a") == 1 and started(events[1], "test_b") == 1
+
+
+def test_incremental_graph_edits():
+    from pysmelt.proto.smelt_client.commands import ValidationErrorKind
+    from pysmelt.subscribers.error_handler import GraphValidationError
+
+    graph = PyGraph.init_commands_only(
+        [
+            command("build", [], ["echo build"]),
+            command("test_a", ["build"], ["echo a"]),
+        ]
+    )
+    test_b = command("test_b", ["build"], ["echo b"])
+    graph.add_commands([test_b])
+    assert graph.query_rdeps(["build"]) == ["test_a", "test_b"]
+    graph.run_specific_commands([test_b])
+    assert graph.retcode_tracker.retcode_dict == {"build": 0, "test_b": 0}
+
+    # a name that is already taken leaves the graph as it was
+    with pytest.raises(GraphValidationError) as e_info:
+        graph.add_commands([command("test_a", [], ["echo again"])])
+    (error,) = e_info.value.errors
+    assert error.kind == ValidationErrorKind.DUPLICATE_COMMAND_NAME
+
+    failing_b = command("test_b", ["build"], ["exit 1"])
+    graph.replace_command(failing_b)
+    graph.run_specific_commands([failing_b])
+    assert graph.retcode_tracker.retcode_dict["test_b"] == 1
+
+    # a removal that would leave dependents behind leaves the graph as it was
+    with pytest.raises(GraphValidationError) as e_info:
+        graph.remove_commands(["build"])
+    errors = e_info.value.errors
+    assert sorted(error.command_name for error in errors) == ["test_a", "test_b"]
+    assert all(
+        error.kind == ValidationErrorKind.MISSING_COMMAND_DEPENDENCY for error in errors
+    )
+    assert graph.query_rdeps(["build"]) == ["test_a", "test_b"]
+
+    # removing the dependents along with the command goes through
+    graph.remove_commands(["build", "test_a", "test_b"])
+    with pytest.raises(RuntimeError):
+        graph.query_deps(["build"])
+
+
+def test_chatty_command_slow_consumer():
+    import os
+    import re
+    import time
+
+    total_lines = 5000
+    chatty = command("chatty", [], [f"seq 1 {total_lines}"])
+    graph = PyGraph.init_commands_only([chatty])
+
+    shown = 0
+    dropped = 0
+
+    def slow_sink(line: str):
+        nonlocal shown, dropped
+        notice = re.match(r"\((\d+) lines not shown", line)
+        if notice:
+            dropped += int(notice.group(1))
+        else:
+            shown += 1
+        time.sleep(0.0001)
+
+    graph.run_one_test_interactive("chatty", sink=slow_sink)
+
+    # every line reaches the log, and every line is either shown or reported as dropped
+    log = os.path.join(graph.get_current_cfg().smelt_root, "smelt-out", "chatty", "command.out")
+    with open(log) as f:
+        assert len(f.readlines()) == total_lines
+    assert shown + dropped == total_lines
+
+
+def test_replay_recorded_events():
+    from pysmelt.pygraph import maybe_get_message, replay_events
+    from pysmelt.subscribers.retcode import RetcodeTracker
+
+    test_list = f"{get_git_root()}/test_data/smelt_files/tests_only.smelt.yaml"
+    graph = create_graph(test_list, cfg_init=with_recording)
+    graph.run_all_commands()
+
+    # subscribers see the same run, without anything being executed again
+    replayed = RetcodeTracker()
+    listener = replay_events(graph.event_log())
+    while not listener.is_done():
+        message = maybe_get_message(listener)
+        if message:
+            assert message.trace_id == graph.last_trace_id
+            replayed.process_message(message)
+    assert replayed.retcode_dict == graph.retcode_tracker.retcode_dict
+    assert replayed.total_executed() == 3
+
+
+def test_async_run():
+    import asyncio
+
+    test_list = f"{get_git_root()}/test_data/smelt_files/tests_only.smelt.yaml"
+    graph = create_graph(test_list)
+
+    async def run() -> int:
+        ticks = 0
+
+        async def tick():
+            nonlocal ticks
+            while True:
+                await asyncio.sleep(0.01)
+                ticks += 1
+
+        # the event loop keeps running other tasks while the events are awaited
+        ticker = asyncio.create_task(tick())
+        await graph.set_commands_async()
+        await graph.run_all_commands_async()
+        ticker.cancel()
+        return ticks
+
+    assert asyncio.run(run()) > 0
+    assert graph.retcode_tracker.total_executed() == 3
+    assert graph.last_invocation_dir is not None
+
+
+def test_cancelled_anext_keeps_its_event():
+    import asyncio
+
+    from pysmelt.pygraph import replay_events
+
+    test_list = f"{get_git_root()}/test_data/smelt_files/tests_only.smelt.yaml"
+    graph = create_graph(test_list, cfg_init=with_recording)
+    graph.run_all_commands()
+
+    async def replay(cancel_first: bool) -> List[bytes]:
+        listener = replay_events(graph.event_log())
+        if cancel_first:
+            listener.__anext__().cancel()
+            await asyncio.sleep(0.1)
+        return [message async for message in listener]
+
+    async def replay_twice():
+        return await replay(cancel_first=False), await replay(cancel_first=True)
+
+    # the event the cancelled __anext__ received goes to the next one
+    expected, after_cancel = asyncio.run(replay_twice())
+    assert after_cancel == expected
+
+
+def test_timeline_of_recorded_run(tmp_path):
+    from pysmelt.pygraph import write_timeline
+
+    test_list = f"{get_git_root()}/test_data/smelt_files/tests_only.smelt.yaml"
+    graph = create_graph(test_list, cfg_init=with_recording)
+    graph.run_all_commands()
+
+    output = tmp_path / "timeline.json"
+    write_timeline(graph.event_log(), str(output))
+    trace_events = json.loads(output.read_text())["traceEvents"]
+    slices = [event for event in trace_events if event["ph"] == "X"]
+    assert len(slices) == 3
+    slots = [
+        event
+        for event in trace_events
+        if event["ph"] == "M" and event["name"] == "thread_name"
+    ]
+    assert {event["tid"] for event in slices} <= {event["tid"] for event in slots}
+
+
 test_sanity_pygraph()
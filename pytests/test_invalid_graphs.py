@@ -1,5 +1,7 @@
 import pytest
 
+from pysmelt.proto.smelt_client.commands import ValidationErrorKind
+from pysmelt.subscribers.error_handler import GraphValidationError
 from pytests.common import create_command_list_graph
 
 
@@ -26,3 +28,26 @@ def test_dup_filename():
 def test_dup_outputs():
     with pytest.raises(RuntimeError) as e_info:
         create_command_list_graph(cl_name="cl_invalid_double_output.yaml")
+
+
+def test_all_errors_reported():
+    with pytest.raises(GraphValidationError) as e_info:
+        create_command_list_graph(cl_name="cl_invalid_cycle.yaml")
+    errors = e_info.value.errors
+    assert [error.kind for error in errors] == [
+        ValidationErrorKind.MISSING_COMMAND_DEPENDENCY,
+        ValidationErrorKind.DEPENDENCY_CYCLE,
+    ]
+    assert errors[0].command_name == "test"
+    assert errors[0].field == "dependencies"
+    assert errors[0].reference == "this_dep_doesnt_exist"
+    assert errors[1].cycle == ["build", "test", "stim", "build"]
+
+
+def test_missing_file_dep_location():
+    with pytest.raises(GraphValidationError) as e_info:
+        create_command_list_graph(cl_name="cl_invalid_missing_file_dep.yaml")
+    (error,) = e_info.value.errors
+    assert error.kind == ValidationErrorKind.MISSING_FILE_DEPENDENCY
+    assert error.reference == "file_that_doesnt_exist.sh"
+    assert "test" in e_info.value.locations